@@ -0,0 +1,255 @@
+//! An actor example.
+//!
+//! This module contains an example of several machines talking to each other
+//! by sending messages through a `Router`.
+
+use super::super::*;
+use std::sync::{Arc, Mutex};
+
+/// Our operand type.  An enum to contain multiple types.
+#[derive(Clone, Debug, PartialEq)]
+enum Operand {
+    I(i64),
+    S(String),
+}
+
+impl Operand {
+    fn to_i(&self) -> Option<i64> {
+        match self {
+            Operand::I(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    fn to_s(&self) -> Option<&str> {
+        match self {
+            Operand::S(ref s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+/// Pushes an piece of data from the data section onto the operand stack.
+fn push(machine: &mut Machine<Operand>, args: &[usize]) {
+    let arg = machine.get_data(args[0]).clone();
+    machine.operand_push(arg);
+}
+
+/// Pops two operands off the top of the stack, adds them together and
+/// pushes the result back onto the stack.
+fn add(machine: &mut Machine<Operand>, _args: &[usize]) {
+    let rhs = machine.operand_pop().to_i().unwrap();
+    let lhs = machine.operand_pop().to_i().unwrap();
+    machine.operand_push(Operand::I(lhs + rhs));
+}
+
+/// Pops two operands off the top of the stack, multiples them together and
+/// pushes the result back onto the stack.
+fn mult(machine: &mut Machine<Operand>, _args: &[usize]) {
+    let rhs = machine.operand_pop().to_i().unwrap();
+    let lhs = machine.operand_pop().to_i().unwrap();
+    machine.operand_push(Operand::I(lhs * rhs));
+}
+
+/// Pops an operand and sends it to the named mailbox.
+fn send(machine: &mut Machine<Operand>, args: &[usize]) {
+    let mailbox = machine.get_data(args[0]).clone();
+    let value = machine.operand_pop();
+    machine.send(mailbox.to_s().unwrap(), value);
+}
+
+/// Receives an operand from the named mailbox and pushes it, blocking until
+/// one arrives.
+fn receive(machine: &mut Machine<Operand>, args: &[usize]) {
+    let mailbox = machine.get_data(args[0]).clone();
+    if let Some(value) = machine.receive(mailbox.to_s().unwrap()) {
+        machine.operand_push(value);
+    }
+}
+
+/// `jump` immediately jumps to the provided label.
+fn jump(machine: &mut Machine<Operand>, args: &[usize]) {
+    let label = machine.get_data(args[0]).clone();
+    machine.jump(label.to_s().unwrap());
+}
+
+/// Spawns a green thread at the provided label and pushes its handle.
+fn spawn(machine: &mut Machine<Operand>, args: &[usize]) {
+    let label = machine.get_data(args[0]).clone();
+    let id = machine.spawn(label.to_s().unwrap());
+    machine.operand_push(Operand::I(id as i64));
+}
+
+fn instruction_table() -> InstructionTable<Operand> {
+    let mut it = InstructionTable::new();
+    it.insert(Instruction::new(0, "push", 1, push));
+    it.insert(Instruction::new(1, "add", 0, add));
+    it.insert(Instruction::new(2, "mult", 0, mult));
+    it.insert(Instruction::new(3, "send", 1, send));
+    it.insert(Instruction::new(4, "receive", 1, receive));
+    it.insert(Instruction::new(5, "jump", 1, jump));
+    it.insert(Instruction::new(6, "spawn", 1, spawn));
+    it
+}
+
+impl From<i64> for Operand {
+    fn from(i: i64) -> Self {
+        Operand::I(i)
+    }
+}
+
+impl<'a> From<&'a str> for Operand {
+    fn from(s: &'a str) -> Self {
+        Operand::S(s.to_string())
+    }
+}
+
+/// A server which doubles every request it receives, forever.
+fn doubler(it: &InstructionTable<Operand>) -> Code<Operand> {
+    let mut builder: Builder<Operand> = Builder::new(it);
+    builder.label("loop");
+    builder.push("receive", vec![Operand::from("requests")]);
+    builder.push("push", vec![Operand::from(2)]);
+    builder.push("mult", vec![]);
+    builder.push("send", vec![Operand::from("responses")]);
+    builder.push("jump", vec![Operand::from("loop")]);
+    Code::from(builder)
+}
+
+/// A client which asks for three numbers to be doubled and adds up the
+/// results.
+fn client(it: &InstructionTable<Operand>) -> Code<Operand> {
+    let mut builder: Builder<Operand> = Builder::new(it);
+    for i in 1..4 {
+        builder.push("push", vec![Operand::from(i)]);
+        builder.push("send", vec![Operand::from("requests")]);
+    }
+    builder.push("receive", vec![Operand::from("responses")]);
+    builder.push("receive", vec![Operand::from("responses")]);
+    builder.push("add", vec![]);
+    builder.push("receive", vec![Operand::from("responses")]);
+    builder.push("add", vec![]);
+    Code::from(builder)
+}
+
+#[test]
+fn request_response() {
+    let it = instruction_table();
+    let constants: WriteManyTable<Operand> = WriteManyTable::new();
+    let router = Router::new();
+    let mut server = Machine::new(doubler(&it), &constants, &it);
+    let mut client = Machine::new(client(&it), &constants, &it);
+    server.connect(&router);
+    client.connect(&router);
+
+    // The server never finishes, so the router stops once it is the only
+    // machine left and it's waiting for a request.
+    assert!(!router.run(&mut [&mut server, &mut client]));
+    assert!(client.is_finished());
+    assert!(server.is_waiting());
+    assert_eq!(client.operand_pop().to_i().unwrap(), 12);
+}
+
+#[test]
+fn host_sends_messages() {
+    let it = instruction_table();
+    let constants: WriteManyTable<Operand> = WriteManyTable::new();
+    let router = Router::new();
+    let mut server = Machine::new(doubler(&it), &constants, &it);
+    server.connect(&router);
+
+    assert!(!router.run(&mut [&mut server]));
+    assert_eq!(router.receive("responses"), None);
+
+    router.send("requests", Operand::from(21));
+    assert!(!router.run(&mut [&mut server]));
+    assert_eq!(router.receive("responses"), Some(Operand::from(42)));
+}
+
+/// Suspends the machine before every `send`, so it never gets past one.
+struct NoSending;
+
+impl Observer<Operand> for NoSending {
+    fn before_instruction(
+        &mut self,
+        machine: &mut Machine<Operand>,
+        _ip: usize,
+        op_code: usize,
+        _args: &[usize],
+    ) {
+        if op_code == 3 {
+            machine.suspend();
+        }
+    }
+}
+
+#[test]
+fn suspended_machines() {
+    let it = instruction_table();
+    let constants: WriteManyTable<Operand> = WriteManyTable::new();
+    let router = Router::new();
+    let mut server = Machine::new(doubler(&it), &constants, &it);
+    let mut client = Machine::new(client(&it), &constants, &it);
+    server.connect(&router);
+    client.connect(&router);
+    client.observe(Arc::new(Mutex::new(NoSending)));
+
+    // The client is stuck before its first send, so nothing can happen.
+    assert!(!router.run(&mut [&mut server, &mut client]));
+    assert!(!client.is_finished());
+    assert!(!client.is_waiting());
+    assert_eq!(client.ip, 3);
+    assert_eq!(router.sent(), 0);
+}
+
+#[test]
+#[should_panic(expected = "not connected to a router")]
+fn unconnected() {
+    let it = instruction_table();
+    let constants: WriteManyTable<Operand> = WriteManyTable::new();
+    let mut server = Machine::new(doubler(&it), &constants, &it);
+    server.run();
+}
+
+#[test]
+fn threads_exchange_messages() {
+    let it = instruction_table();
+    let mut builder: Builder<Operand> = Builder::new(&it);
+    builder.push("spawn", vec![Operand::from("worker")]);
+    builder.push("receive", vec![Operand::from("responses")]);
+    builder.push("jump", vec![Operand::from("end")]);
+    builder.label("worker");
+    builder.push("push", vec![Operand::from(21)]);
+    builder.push("send", vec![Operand::from("responses")]);
+    builder.label("end");
+    let constants: WriteManyTable<Operand> = WriteManyTable::new();
+    let mut machine = Machine::new(Code::from(builder), &constants, &it);
+    machine.connect(&Router::new());
+
+    assert!(machine.run_threads(10));
+    assert_eq!(machine.operand_pop().to_i().unwrap(), 21);
+    assert!(!machine.is_waiting());
+}
+
+#[test]
+fn threads_waiting_for_messages() {
+    let it = instruction_table();
+    let mut builder: Builder<Operand> = Builder::new(&it);
+    builder.push("spawn", vec![Operand::from("worker")]);
+    builder.push("receive", vec![Operand::from("responses")]);
+    builder.push("jump", vec![Operand::from("end")]);
+    builder.label("worker");
+    builder.push("receive", vec![Operand::from("requests")]);
+    builder.push("send", vec![Operand::from("responses")]);
+    builder.label("end");
+    let constants: WriteManyTable<Operand> = WriteManyTable::new();
+    let mut machine = Machine::new(Code::from(builder), &constants, &it);
+    let router = Router::new();
+    machine.connect(&router);
+    assert!(!machine.run_threads(10));
+    assert!(!machine.run_threads(10));
+
+    router.send("requests", Operand::from(13));
+    assert!(machine.run_threads(10));
+    assert_eq!(machine.operand_pop().to_i().unwrap(), 13);
+}
@@ -1,7 +1,7 @@
 //! An Arithmetic Machine.
 //!
 //! This module contains an example of a basic calculator and tests which
-//! verify it's output.
+//! verify its output.
 
 use super::super::*;
 use std::f64;
@@ -44,6 +44,13 @@ fn mult(machine: &mut Machine<f64>, _args: &[usize]) {
     machine.operand_push(lhs * rhs);
 }
 
+/// Adds together every piece of data it's given and pushes the result onto
+/// the stack.
+fn sum(machine: &mut Machine<f64>, args: &[usize]) {
+    let total = args.iter().map(|arg| *machine.get_data(*arg)).sum();
+    machine.operand_push(total);
+}
+
 /// Build an instruction table based on the instructions outlined above.
 fn instruction_table() -> InstructionTable<f64> {
     let mut it = InstructionTable::new();
@@ -52,6 +59,7 @@ fn instruction_table() -> InstructionTable<f64> {
     it.insert(Instruction::new(2, "sub", 0, sub));
     it.insert(Instruction::new(3, "div", 0, div));
     it.insert(Instruction::new(4, "mult", 0, mult));
+    it.insert(Instruction::new(5, "sum", 0, sum).with_variadic_arity(None));
     it
 }
 
@@ -114,3 +122,17 @@ fn multiplication_example() {
     let result = machine.operand_pop();
     assert!((result - 12.0).abs() < f64::EPSILON);
 }
+
+#[test]
+fn sum_example() {
+    let it = instruction_table();
+    let mut builder: Builder<f64> = Builder::new(&it);
+    builder.push("sum", vec![1.0, 2.0, 3.0, 4.0]);
+    builder.push("sum", vec![]);
+    builder.push("add", vec![]);
+    let constants: WriteManyTable<f64> = WriteManyTable::new();
+    let mut machine = Machine::new(Code::from(builder), &constants, &it);
+    machine.run();
+    let result = machine.operand_pop();
+    assert!((result - 10.0).abs() < f64::EPSILON);
+}
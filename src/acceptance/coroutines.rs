@@ -0,0 +1,199 @@
+//! A coroutine example.
+//!
+//! This module contains an example of a machine which uses coroutines to
+//! produce values lazily, both for the host and for the running script.
+
+use super::super::*;
+
+/// Our operand type.  An enum to contain multiple types.
+#[derive(Clone, Debug, PartialEq)]
+enum Operand {
+    I(i64),
+    S(String),
+}
+
+impl Operand {
+    fn to_i(&self) -> Option<i64> {
+        match self {
+            Operand::I(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    fn to_s(&self) -> Option<&str> {
+        match self {
+            Operand::S(ref s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+/// Pushes an piece of data from the data section onto the operand stack.
+fn push(machine: &mut Machine<Operand>, args: &[usize]) {
+    let arg = machine.get_data(args[0]).clone();
+    machine.operand_push(arg);
+}
+
+/// Pops two operands off the top of the stack, adds them together and
+/// pushes the result back onto the stack.
+fn add(machine: &mut Machine<Operand>, _args: &[usize]) {
+    let rhs = machine.operand_pop().to_i().unwrap();
+    let lhs = machine.operand_pop().to_i().unwrap();
+    machine.operand_push(Operand::I(lhs + rhs));
+}
+
+/// Duplicates the top two operands on the stack.
+fn dup2(machine: &mut Machine<Operand>, _args: &[usize]) {
+    let rhs = machine.operand_pop();
+    let lhs = machine.operand_pop();
+    machine.operand_push(lhs.clone());
+    machine.operand_push(rhs.clone());
+    machine.operand_push(lhs);
+    machine.operand_push(rhs);
+}
+
+/// Swaps the top two operands on the stack.
+fn swap(machine: &mut Machine<Operand>, _args: &[usize]) {
+    let rhs = machine.operand_pop();
+    let lhs = machine.operand_pop();
+    machine.operand_push(rhs);
+    machine.operand_push(lhs);
+}
+
+/// Yields a copy of the top of the stack to whoever resumed us.
+fn emit(machine: &mut Machine<Operand>, _args: &[usize]) {
+    let value = machine.operand_stack.peek().clone();
+    machine.yield_value(value);
+}
+
+/// Removes the top operand from the stack.
+fn drop(machine: &mut Machine<Operand>, _args: &[usize]) {
+    machine.operand_pop();
+}
+
+/// `jump` immediately jumps to the provided label.
+fn jump(machine: &mut Machine<Operand>, args: &[usize]) {
+    let label = machine.get_data(args[0]).clone();
+    machine.jump(label.to_s().unwrap());
+}
+
+/// Starts a coroutine at the provided label and pushes its handle.
+fn spawn(machine: &mut Machine<Operand>, args: &[usize]) {
+    let label = machine.get_data(args[0]).clone();
+    let id = machine.coroutine(label.to_s().unwrap());
+    machine.operand_push(Operand::I(id as i64));
+}
+
+/// Resumes the coroutine whose handle is on top of the stack and pushes the
+/// value it yields.
+fn next(machine: &mut Machine<Operand>, _args: &[usize]) {
+    let id = machine.operand_stack.peek().to_i().unwrap() as CoroutineId;
+    let value = machine.resume(id).unwrap();
+    machine.operand_push(value);
+}
+
+fn instruction_table() -> InstructionTable<Operand> {
+    let mut it = InstructionTable::new();
+    it.insert(Instruction::new(0, "push", 1, push));
+    it.insert(Instruction::new(1, "add", 0, add));
+    it.insert(Instruction::new(2, "dup2", 0, dup2));
+    it.insert(Instruction::new(3, "swap", 0, swap));
+    it.insert(Instruction::new(4, "yield", 0, emit));
+    it.insert(Instruction::new(5, "drop", 0, drop));
+    it.insert(Instruction::new(6, "jump", 1, jump));
+    it.insert(Instruction::new(7, "spawn", 1, spawn));
+    it.insert(Instruction::new(8, "next", 0, next));
+    it
+}
+
+impl From<i64> for Operand {
+    fn from(i: i64) -> Self {
+        Operand::I(i)
+    }
+}
+
+impl<'a> From<&'a str> for Operand {
+    fn from(s: &'a str) -> Self {
+        Operand::S(s.to_string())
+    }
+}
+
+/// An infinite fibonacci generator placed after the main program.
+fn fibonacci(builder: &mut Builder<Operand>) {
+    builder.label("fibonacci");
+    builder.push("push", vec![Operand::from(0)]);
+    builder.push("push", vec![Operand::from(1)]);
+    builder.label("fibonacci_loop");
+    builder.push("swap", vec![]);
+    builder.push("yield", vec![]);
+    builder.push("dup2", vec![]);
+    builder.push("add", vec![]);
+    builder.push("swap", vec![]);
+    builder.push("drop", vec![]);
+    builder.push("jump", vec![Operand::from("fibonacci_loop")]);
+}
+
+#[test]
+fn host_generator() {
+    let it = instruction_table();
+    let mut builder: Builder<Operand> = Builder::new(&it);
+    builder.push("jump", vec![Operand::from("end")]);
+    fibonacci(&mut builder);
+    builder.label("end");
+    let constants: WriteManyTable<Operand> = WriteManyTable::new();
+    let mut machine: Machine<Operand> = Machine::new(Code::from(builder), &constants, &it);
+    machine.run();
+
+    let values: Vec<i64> = machine
+        .generator("fibonacci")
+        .take(8)
+        .map(|o| o.to_i().unwrap())
+        .collect();
+    assert_eq!(values, vec![0, 1, 1, 2, 3, 5, 8, 13]);
+}
+
+#[test]
+fn finite_generator() {
+    let it = instruction_table();
+    let mut builder: Builder<Operand> = Builder::new(&it);
+    builder.push("jump", vec![Operand::from("end")]);
+    builder.label("greetings");
+    builder.push("push", vec![Operand::from("hello")]);
+    builder.push("yield", vec![]);
+    builder.push("push", vec![Operand::from("goodbye")]);
+    builder.push("yield", vec![]);
+    builder.label("end");
+    let constants: WriteManyTable<Operand> = WriteManyTable::new();
+    let mut machine: Machine<Operand> = Machine::new(Code::from(builder), &constants, &it);
+
+    let id = machine.coroutine("greetings");
+    assert_eq!(machine.resume(id), Some(Operand::from("hello")));
+    assert_eq!(machine.resume(id), Some(Operand::from("goodbye")));
+    assert!(!machine.is_coroutine_finished(id));
+    assert_eq!(machine.resume(id), None);
+    assert!(machine.is_coroutine_finished(id));
+    assert_eq!(machine.resume(id), None);
+}
+
+#[test]
+fn script_generator() {
+    let it = instruction_table();
+    let mut builder: Builder<Operand> = Builder::new(&it);
+    builder.push("spawn", vec![Operand::from("fibonacci")]);
+    builder.push("next", vec![]);
+    builder.push("drop", vec![]);
+    builder.push("next", vec![]);
+    builder.push("drop", vec![]);
+    builder.push("next", vec![]);
+    builder.push("drop", vec![]);
+    builder.push("next", vec![]);
+    builder.push("jump", vec![Operand::from("end")]);
+    fibonacci(&mut builder);
+    builder.label("end");
+    let constants: WriteManyTable<Operand> = WriteManyTable::new();
+    let mut machine: Machine<Operand> = Machine::new(Code::from(builder), &constants, &it);
+    machine.run();
+
+    let result = machine.operand_pop().to_i().unwrap();
+    assert_eq!(result, 2);
+}
@@ -1,3 +1,7 @@
+mod actors;
 mod arithmetic;
 mod conditionals;
+mod coroutines;
 mod functions;
+mod parallel;
+mod threads;
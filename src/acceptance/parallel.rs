@@ -0,0 +1,102 @@
+//! A parallel example.
+//!
+//! This module contains an example of running many machines on worker
+//! threads, all sharing a single copy of the program, constants and
+//! instruction table.
+
+use super::super::*;
+use std::sync::Arc;
+use std::thread;
+
+/// Pushes an piece of data from the data section onto the operand stack.
+fn push(machine: &mut Machine<i64>, args: &[usize]) {
+    let arg = *machine.get_data(args[0]);
+    machine.operand_push(arg);
+}
+
+/// Pushes a named constant onto the operand stack.
+fn push_constant(machine: &mut Machine<i64>, _args: &[usize]) {
+    let arg = *machine.constants.get("multiplier").unwrap();
+    machine.operand_push(arg);
+}
+
+/// Pops two operands off the top of the stack, multiples them together and
+/// pushes the result back onto the stack.
+fn mult(machine: &mut Machine<i64>, _args: &[usize]) {
+    let rhs = machine.operand_pop();
+    let lhs = machine.operand_pop();
+    machine.operand_push(lhs * rhs);
+}
+
+fn instruction_table() -> InstructionTable<i64> {
+    let mut it = InstructionTable::new();
+    it.insert(Instruction::new(0, "push", 1, push));
+    it.insert(Instruction::new(1, "push_constant", 0, push_constant));
+    it.insert(Instruction::new(2, "mult", 0, mult));
+    it
+}
+
+fn assert_send<X: Send>() {}
+fn assert_sync<X: Sync>() {}
+
+#[test]
+fn send_and_sync() {
+    assert_send::<Machine<i64>>();
+    assert_sync::<Machine<i64>>();
+    assert_send::<Code<i64>>();
+    assert_sync::<Code<i64>>();
+    assert_send::<InstructionTable<i64>>();
+    assert_sync::<InstructionTable<i64>>();
+}
+
+#[test]
+fn many_machines_on_threads() {
+    let it = Arc::new(instruction_table());
+    let mut builder: Builder<i64> = Builder::new(&it);
+    builder.push("push", vec![7]);
+    builder.push("push_constant", vec![]);
+    builder.push("mult", vec![]);
+    let code = Arc::new(Code::from(builder));
+    let mut constants: WriteOnceTable<i64> = WriteOnceTable::new();
+    constants.insert("multiplier", 6);
+    let constants: Arc<dyn Table<Item = i64> + Send + Sync> = Arc::new(constants);
+
+    let handles: Vec<thread::JoinHandle<i64>> = (0..16)
+        .map(|_| {
+            let mut machine = Machine::new_shared(code.clone(), constants.clone(), it.clone());
+            thread::spawn(move || {
+                machine.run();
+                machine.operand_pop()
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        assert_eq!(handle.join().unwrap(), 42);
+    }
+    assert_eq!(Arc::strong_count(&code), 1);
+}
+
+#[test]
+fn borrowing_machines_on_scoped_threads() {
+    let it = instruction_table();
+    let mut builder: Builder<i64> = Builder::new(&it);
+    builder.push("push", vec![7]);
+    builder.push("push_constant", vec![]);
+    builder.push("mult", vec![]);
+    let code = Arc::new(Code::from(builder));
+    let mut constants: WriteOnceTable<i64> = WriteOnceTable::new();
+    constants.insert("multiplier", 6);
+
+    let machines: Vec<Machine<i64>> = (0..4)
+        .map(|_| Machine::new(code.clone(), &constants, &it))
+        .collect();
+    thread::scope(|scope| {
+        for mut machine in machines {
+            scope.spawn(move || {
+                machine.run();
+                assert_eq!(machine.operand_pop(), 42);
+            });
+        }
+    });
+}
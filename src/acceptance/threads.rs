@@ -0,0 +1,179 @@
+//! A green-thread example.
+//!
+//! This module contains an example of a machine which spawns many threads,
+//! one per character in a game, and lets the scheduler interleave them.
+
+use super::super::*;
+use std::cell::RefCell;
+
+thread_local! {
+    /// A log of `(thread, value)` pairs written by the `log` instruction so
+    /// that we can see the order in which things happened.
+    static LOG: RefCell<Vec<(ThreadId, i64)>> = const { RefCell::new(vec![]) };
+}
+
+/// Our operand type.  An enum to contain multiple types.
+#[derive(Clone, Debug, PartialEq)]
+enum Operand {
+    I(i64),
+    S(String),
+}
+
+impl Operand {
+    fn to_i(&self) -> Option<i64> {
+        match self {
+            Operand::I(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    fn to_s(&self) -> Option<&str> {
+        match self {
+            Operand::S(ref s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+/// Pushes an piece of data from the data section onto the operand stack.
+fn push(machine: &mut Machine<Operand>, args: &[usize]) {
+    let arg = machine.get_data(args[0]).clone();
+    machine.operand_push(arg);
+}
+
+/// Pops an operand and records it in the log along with the current thread.
+fn log(machine: &mut Machine<Operand>, _args: &[usize]) {
+    let value = machine.operand_pop().to_i().unwrap();
+    let thread = machine.current_thread();
+    LOG.with(|log| log.borrow_mut().push((thread, value)));
+}
+
+/// Spawns a thread at the provided label and pushes its handle.
+fn spawn(machine: &mut Machine<Operand>, args: &[usize]) {
+    let label = machine.get_data(args[0]).clone();
+    let id = machine.spawn(label.to_s().unwrap());
+    machine.operand_push(Operand::I(id as i64));
+}
+
+/// Pops a thread handle and waits for that thread to finish.
+fn join(machine: &mut Machine<Operand>, _args: &[usize]) {
+    let id = machine.operand_pop().to_i().unwrap() as ThreadId;
+    machine.join(id);
+}
+
+/// Pops a number of rounds and puts the current thread to sleep.
+fn sleep(machine: &mut Machine<Operand>, _args: &[usize]) {
+    let rounds = machine.operand_pop().to_i().unwrap() as usize;
+    machine.sleep(rounds);
+}
+
+/// `jump` immediately jumps to the provided label.
+fn jump(machine: &mut Machine<Operand>, args: &[usize]) {
+    let label = machine.get_data(args[0]).clone();
+    machine.jump(label.to_s().unwrap());
+}
+
+fn instruction_table() -> InstructionTable<Operand> {
+    let mut it = InstructionTable::new();
+    it.insert(Instruction::new(0, "push", 1, push));
+    it.insert(Instruction::new(1, "log", 0, log));
+    it.insert(Instruction::new(2, "spawn", 1, spawn));
+    it.insert(Instruction::new(3, "join", 0, join));
+    it.insert(Instruction::new(4, "sleep", 0, sleep));
+    it.insert(Instruction::new(5, "jump", 1, jump));
+    it
+}
+
+impl From<i64> for Operand {
+    fn from(i: i64) -> Self {
+        Operand::I(i)
+    }
+}
+
+impl<'a> From<&'a str> for Operand {
+    fn from(s: &'a str) -> Self {
+        Operand::S(s.to_string())
+    }
+}
+
+fn take_log() -> Vec<(ThreadId, i64)> {
+    LOG.with(|log| log.borrow_mut().drain(..).collect())
+}
+
+#[test]
+fn round_robin() {
+    let it = instruction_table();
+    let mut builder: Builder<Operand> = Builder::new(&it);
+    builder.push("spawn", vec![Operand::from("npc")]);
+    builder.push("spawn", vec![Operand::from("npc")]);
+    builder.push("join", vec![]);
+    builder.push("join", vec![]);
+    builder.push("push", vec![Operand::from(99)]);
+    builder.push("log", vec![]);
+    builder.push("jump", vec![Operand::from("end")]);
+    builder.label("npc");
+    builder.push("push", vec![Operand::from(1)]);
+    builder.push("log", vec![]);
+    builder.push("push", vec![Operand::from(2)]);
+    builder.push("log", vec![]);
+    builder.label("end");
+    let constants: WriteManyTable<Operand> = WriteManyTable::new();
+    let mut machine: Machine<Operand> = Machine::new(Code::from(builder), &constants, &it);
+    take_log();
+    machine.run_threads(2);
+
+    assert_eq!(
+        take_log(),
+        vec![(1, 1), (2, 1), (1, 2), (2, 2), (0, 99)]
+    );
+    assert!(machine.is_thread_finished(0));
+    assert!(machine.is_thread_finished(1));
+    assert!(machine.is_thread_finished(2));
+    assert_eq!(machine.current_thread(), 0);
+    assert!(machine.operand_stack.is_empty());
+}
+
+#[test]
+fn sleeping() {
+    let it = instruction_table();
+    let mut builder: Builder<Operand> = Builder::new(&it);
+    builder.push("spawn", vec![Operand::from("sleepy")]);
+    builder.push("push", vec![Operand::from(1)]);
+    builder.push("log", vec![]);
+    builder.push("push", vec![Operand::from(2)]);
+    builder.push("log", vec![]);
+    builder.push("push", vec![Operand::from(3)]);
+    builder.push("log", vec![]);
+    builder.push("join", vec![]);
+    builder.push("jump", vec![Operand::from("end")]);
+    builder.label("sleepy");
+    builder.push("push", vec![Operand::from(2)]);
+    builder.push("sleep", vec![]);
+    builder.push("push", vec![Operand::from(100)]);
+    builder.push("log", vec![]);
+    builder.label("end");
+    let constants: WriteManyTable<Operand> = WriteManyTable::new();
+    let mut machine: Machine<Operand> = Machine::new(Code::from(builder), &constants, &it);
+    take_log();
+    machine.run_threads(3);
+
+    assert_eq!(take_log(), vec![(0, 1), (0, 2), (0, 3), (1, 100)]);
+    assert!(machine.is_finished());
+}
+
+#[test]
+#[should_panic(expected = "Deadlock")]
+fn deadlock() {
+    let it = instruction_table();
+    let mut builder: Builder<Operand> = Builder::new(&it);
+    builder.push("spawn", vec![Operand::from("waiter")]);
+    builder.push("join", vec![]);
+    builder.push("jump", vec![Operand::from("end")]);
+    builder.label("waiter");
+    builder.push("push", vec![Operand::from(0)]);
+    builder.push("join", vec![]);
+    builder.label("end");
+    let constants: WriteManyTable<Operand> = WriteManyTable::new();
+    let mut machine: Machine<Operand> = Machine::new(Code::from(builder), &constants, &it);
+    machine.run_threads(10);
+}
@@ -0,0 +1,219 @@
+//! Backtraces of running code.
+//!
+//! When something goes wrong inside a program it helps to know where the
+//! machine was, and how it got there.  A `Backtrace` lists the address of
+//! the current instruction followed by the return address of each call on
+//! the call stack, innermost first.  Every address is shown relative to the
+//! closest label before it, so a frame reads like `fibonacci+12`.  If the
+//! code has a source map then the source location follows, as in
+//! `fibonacci+12 (fib.src:4:9)`.
+//!
+//! `Machine::backtrace` describes each frame of the call stack with its
+//! `Location`.  The machine's own panics (jumping to an unknown label,
+//! executing an unknown instruction, and so on) include a backtrace in their
+//! message, and `Machine::try_run` turns any panic raised while running into
+//! a `RuntimeError` which carries one.
+//!
+//! ## Examples
+//!
+//! ```
+//! use stack_vm::{Instruction, InstructionTable, Builder, Code, Machine, WriteManyTable};
+//!
+//! fn call(machine: &mut Machine<i64>, _args: &[usize]) {
+//!     machine.call("function");
+//! }
+//!
+//! fn fail(machine: &mut Machine<i64>, _args: &[usize]) {
+//!     machine.jump("nowhere");
+//! }
+//!
+//! let mut instruction_table = InstructionTable::new();
+//! instruction_table.insert(Instruction::new(0, "call", 0, call));
+//! instruction_table.insert(Instruction::new(1, "fail", 0, fail));
+//! let mut builder: Builder<i64> = Builder::new(&instruction_table);
+//! builder.push("call", vec![]);
+//! builder.label("function");
+//! builder.push("fail", vec![]);
+//!
+//! let constants: WriteManyTable<i64> = WriteManyTable::new();
+//! let mut machine = Machine::new(Code::from(builder), &constants, &instruction_table);
+//! let error = machine.try_run().unwrap_err();
+//! assert_eq!(error.message, "Attempted to jump to unknown label nowhere");
+//! assert_eq!(error.backtrace.to_string(), "stack-vm backtrace:\n  0: function+0\n  1: main+2\n");
+//! ```
+
+use crate::code::Code;
+use crate::source_map::SourceLocation;
+use std::error::Error;
+use std::fmt;
+
+/// The heading which starts every backtrace.
+pub(crate) const HEADING: &str = "stack-vm backtrace:";
+
+/// An address in the code, along with the label containing it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    /// The address.
+    pub ip: usize,
+    /// The closest label at or before the address, if there is one.
+    pub label: Option<String>,
+    /// The distance from the label to the address.
+    pub offset: usize,
+    /// The source which produced the instruction, if the code has a source
+    /// map.
+    pub source: Option<SourceLocation>,
+}
+
+impl Location {
+    /// Find the label containing `ip`.
+    pub fn new<T: fmt::Debug>(code: &Code<T>, ip: usize) -> Location {
+        Location::within(code, ip, ip)
+    }
+
+    /// Find the label containing a return address.
+    ///
+    /// A return address points just past the call which pushed it, which
+    /// might be the first address of the next label, so the label and source
+    /// location are found from the address before.
+    pub fn of_return<T: fmt::Debug>(code: &Code<T>, return_address: usize) -> Location {
+        Location::within(code, return_address, return_address.saturating_sub(1))
+    }
+
+    fn within<T: fmt::Debug>(code: &Code<T>, ip: usize, inside: usize) -> Location {
+        let source = code.source_location(inside).cloned();
+        match code.label_for_ip(inside) {
+            Some((label_ip, name)) => Location {
+                ip,
+                label: Some(name.clone()),
+                offset: ip - label_ip,
+                source,
+            },
+            None => Location {
+                ip,
+                label: None,
+                offset: ip,
+                source,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.label {
+            Some(ref label) => write!(f, "{}+{}", label, self.offset)?,
+            None => write!(f, "{}", self.ip)?,
+        }
+        match self.source {
+            Some(ref source) => write!(f, " ({})", source),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The locations of the current instruction and of each pending call,
+/// innermost first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backtrace {
+    pub frames: Vec<Location>,
+}
+
+impl Backtrace {
+    /// Build a backtrace from the current instruction pointer and a call
+    /// stack's return addresses, outermost first.
+    ///
+    /// The outermost frame's return address is the end of the code rather
+    /// than a call, so it is left out.
+    pub fn new<T: fmt::Debug>(code: &Code<T>, ip: usize, return_addresses: &[usize]) -> Backtrace {
+        let mut frames = vec![Location::new(code, ip)];
+        for return_address in return_addresses.iter().skip(1).rev() {
+            frames.push(Location::of_return(code, *return_address));
+        }
+        Backtrace { frames }
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", HEADING)?;
+        for (depth, location) in self.frames.iter().enumerate() {
+            writeln!(f, "{:>3}: {}", depth, location)?;
+        }
+        Ok(())
+    }
+}
+
+/// A panic raised while the machine was running.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    /// The panic's message, without any backtrace.
+    pub message: String,
+    /// Where the machine was when it panicked.
+    pub backtrace: Backtrace,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}\n{}", self.message, self.backtrace)
+    }
+}
+
+impl Error for RuntimeError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builder::Builder;
+    use crate::instruction::Instruction;
+    use crate::instruction_table::InstructionTable;
+    use crate::machine::Machine;
+
+    fn noop(_machine: &mut Machine<usize>, _args: &[usize]) {}
+
+    fn code() -> Code<usize> {
+        let mut it = InstructionTable::new();
+        it.insert(Instruction::new(0, "noop", 0, noop));
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("noop", vec![]);
+        builder.push("noop", vec![]);
+        builder.label("function");
+        builder.push("noop", vec![]);
+        Code::from(builder)
+    }
+
+    #[test]
+    fn locations() {
+        let code = code();
+        assert_eq!(Location::new(&code, 2).to_string(), "main+2");
+        assert_eq!(Location::new(&code, 4).to_string(), "function+0");
+        assert_eq!(Location::of_return(&code, 4).to_string(), "main+4");
+    }
+
+    #[test]
+    fn backtrace() {
+        let code = code();
+        let backtrace = Backtrace::new(&code, 4, &[6, 2, 4]);
+        assert_eq!(
+            backtrace.to_string(),
+            "stack-vm backtrace:\n  0: function+0\n  1: main+4\n  2: main+2\n"
+        );
+    }
+
+    #[test]
+    fn source_locations() {
+        let mut it = InstructionTable::new();
+        it.insert(Instruction::new(0, "noop", 0, noop));
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.set_location("example.src", 1, 1);
+        builder.push("noop", vec![]);
+        builder.set_location("example.src", 3, 5);
+        builder.push("noop", vec![]);
+        builder.clear_location();
+        builder.label("function");
+        builder.push("noop", vec![]);
+        let code = Code::from(builder);
+        assert_eq!(Location::new(&code, 2).to_string(), "main+2 (example.src:3:5)");
+        assert_eq!(Location::of_return(&code, 4).to_string(), "main+4 (example.src:3:5)");
+        assert_eq!(Location::new(&code, 4).to_string(), "function+0");
+    }
+}
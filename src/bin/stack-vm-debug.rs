@@ -0,0 +1,263 @@
+//! An interactive debugger for `stack-vm` bytecode.
+//!
+//! `stack-vm` doesn't know anything about the operands or instructions of
+//! your machine, so this binary comes with a small instruction set of its
+//! own and can only debug bytecode built against it:
+//!
+//! | Op code | Name    | Arity | Description                                   |
+//! |---------|---------|-------|-----------------------------------------------|
+//! | 0       | `push`  | 1     | Push a constant onto the operand stack.       |
+//! | 1       | `pop`   | 0     | Discard the top of the operand stack.         |
+//! | 2       | `add`   | 0     | Add the top two integers.                     |
+//! | 3       | `mul`   | 0     | Multiply the top two integers.                |
+//! | 4       | `store` | 1     | Pop into the local named by a constant.       |
+//! | 5       | `load`  | 1     | Push the local named by a constant.           |
+//! | 6       | `call`  | 1     | Call the label named by a constant.           |
+//! | 7       | `ret`   | 0     | Return from the current call.                 |
+//! | 8       | `print` | 0     | Print the top of the operand stack to stderr. |
+//!
+//! Operands are integers or strings, encoded as a MsgPack array of a tag and
+//! a value: `[0, 42]` or `[1, "name"]`.
+//!
+//! If you have your own instruction set, embed `stack_vm::Repl` in a binary
+//! of your own instead.
+//!
+//! ## Usage
+//!
+//! ```text
+//! stack-vm-debug <bytecode>
+//! stack-vm-debug --dap
+//! stack-vm-debug --example <bytecode>
+//! ```
+//!
+//! The first form starts an interactive debugger.  The second serves the
+//! Debug Adapter Protocol on standard input and output, for use from an
+//! editor, and the program to debug is named in the `launch` request.  The
+//! third writes an example program which you can then debug.
+
+extern crate rmp;
+extern crate stack_vm;
+
+use stack_vm::{
+    Builder, Code, DapServer, FromByteCode, Instruction, InstructionTable, Machine, Repl,
+    ToByteCode, WriteManyTable,
+};
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::process;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Operand {
+    I(i64),
+    S(String),
+}
+
+impl Operand {
+    fn to_i(&self) -> i64 {
+        match self {
+            Operand::I(i) => *i,
+            Operand::S(s) => panic!("Expected an integer but found {:?}", s),
+        }
+    }
+
+    fn to_s(&self) -> &str {
+        match self {
+            Operand::S(s) => s,
+            Operand::I(i) => panic!("Expected a string but found {}", i),
+        }
+    }
+}
+
+impl From<i64> for Operand {
+    fn from(i: i64) -> Self {
+        Operand::I(i)
+    }
+}
+
+impl<'a> From<&'a str> for Operand {
+    fn from(s: &'a str) -> Self {
+        Operand::S(s.to_string())
+    }
+}
+
+impl ToByteCode for Operand {
+    fn to_byte_code(&self, mut buf: &mut dyn Write) {
+        rmp::encode::write_array_len(&mut buf, 2).unwrap();
+        match self {
+            Operand::I(i) => {
+                rmp::encode::write_uint(&mut buf, 0).unwrap();
+                rmp::encode::write_sint(&mut buf, *i).unwrap();
+            }
+            Operand::S(s) => {
+                rmp::encode::write_uint(&mut buf, 1).unwrap();
+                rmp::encode::write_str(&mut buf, s).unwrap();
+            }
+        }
+    }
+}
+
+impl FromByteCode for Operand {
+    fn from_byte_code(mut buf: &mut dyn Read) -> Operand {
+        let len = rmp::decode::read_array_len(&mut buf).unwrap();
+        assert_eq!(len, 2);
+        let tag: u8 = rmp::decode::read_int(&mut buf).unwrap();
+        match tag {
+            0 => Operand::I(rmp::decode::read_int(&mut buf).unwrap()),
+            1 => {
+                let len = rmp::decode::read_str_len(&mut buf).unwrap();
+                let mut bytes = vec![0u8; len as usize];
+                buf.read_exact(&mut bytes).unwrap();
+                Operand::S(String::from_utf8(bytes).unwrap())
+            }
+            tag => panic!("Unknown operand tag {}", tag),
+        }
+    }
+}
+
+fn push(machine: &mut Machine<Operand>, args: &[usize]) {
+    let arg = machine.get_data(args[0]).clone();
+    machine.operand_push(arg);
+}
+
+fn pop(machine: &mut Machine<Operand>, _args: &[usize]) {
+    machine.operand_pop();
+}
+
+fn add(machine: &mut Machine<Operand>, _args: &[usize]) {
+    let rhs = machine.operand_pop().to_i();
+    let lhs = machine.operand_pop().to_i();
+    machine.operand_push(Operand::I(lhs + rhs));
+}
+
+fn mul(machine: &mut Machine<Operand>, _args: &[usize]) {
+    let rhs = machine.operand_pop().to_i();
+    let lhs = machine.operand_pop().to_i();
+    machine.operand_push(Operand::I(lhs * rhs));
+}
+
+fn store(machine: &mut Machine<Operand>, args: &[usize]) {
+    let name = machine.get_data(args[0]).to_s().to_string();
+    let value = machine.operand_pop();
+    machine.set_local(&name, value);
+}
+
+fn load(machine: &mut Machine<Operand>, args: &[usize]) {
+    let name = machine.get_data(args[0]).to_s().to_string();
+    let value = machine
+        .get_local_deep(&name)
+        .unwrap_or_else(|| panic!("Unknown local {}", name))
+        .clone();
+    machine.operand_push(value);
+}
+
+fn call(machine: &mut Machine<Operand>, args: &[usize]) {
+    let label = machine.get_data(args[0]).to_s().to_string();
+    machine.call(&label);
+}
+
+fn ret(machine: &mut Machine<Operand>, _args: &[usize]) {
+    machine.ret();
+}
+
+/// Program output goes to stderr, as stdout carries the protocol when we're
+/// running as a debug adapter.
+fn print(machine: &mut Machine<Operand>, _args: &[usize]) {
+    let value = machine.operand_pop();
+    eprintln!("{:?}", value);
+    machine.operand_push(value);
+}
+
+fn instruction_table() -> InstructionTable<Operand> {
+    let mut it = InstructionTable::new();
+    it.insert(Instruction::new(0, "push", 1, push));
+    it.insert(Instruction::new(1, "pop", 0, pop));
+    it.insert(Instruction::new(2, "add", 0, add));
+    it.insert(Instruction::new(3, "mul", 0, mul));
+    it.insert(Instruction::new(4, "store", 1, store));
+    it.insert(Instruction::new(5, "load", 1, load));
+    it.insert(Instruction::new(6, "call", 1, call));
+    it.insert(Instruction::new(7, "ret", 0, ret));
+    it.insert(Instruction::new(8, "print", 0, print));
+    it
+}
+
+/// Squares a number in a function and prints the result.
+fn example(it: &InstructionTable<Operand>) -> Code<Operand> {
+    let mut builder: Builder<Operand> = Builder::new(it);
+    builder.push("push", vec![Operand::from(7)]);
+    builder.push("call", vec![Operand::from("square")]);
+    builder.push("store", vec![Operand::from("result")]);
+    builder.push("load", vec![Operand::from("result")]);
+    builder.push("print", vec![]);
+    builder.push("ret", vec![]);
+
+    builder.label("square");
+    builder.push("store", vec![Operand::from("x")]);
+    builder.push("load", vec![Operand::from("x")]);
+    builder.push("load", vec![Operand::from("x")]);
+    builder.push("mul", vec![]);
+    builder.push("ret", vec![]);
+    Code::from(builder)
+}
+
+/// Make sure the code was built against our instruction set.
+fn check_symbols(code: &Code<Operand>, it: &InstructionTable<Operand>) -> Result<(), String> {
+    for (op_code, name) in code.symbols() {
+        match it.by_op_code(*op_code) {
+            Some(instruction) if instruction.name == *name => (),
+            _ => return Err(format!("Unknown instruction {} ({})", name, op_code)),
+        }
+    }
+    Ok(())
+}
+
+fn usage() -> ! {
+    eprintln!("usage: stack-vm-debug <bytecode>");
+    eprintln!("       stack-vm-debug --dap");
+    eprintln!("       stack-vm-debug --example <bytecode>");
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let it = instruction_table();
+
+    match args.len() {
+        1 if args[0] == "--dap" => {
+            let constants: WriteManyTable<Operand> = WriteManyTable::new();
+            let stdin = io::stdin();
+            let stdout = io::stdout();
+            DapServer::new(&it, &constants)
+                .run(stdin.lock(), stdout.lock())
+                .unwrap();
+        }
+        1 => {
+            let file = File::open(&args[0]).unwrap_or_else(|error| {
+                eprintln!("Unable to open {}: {}", args[0], error);
+                process::exit(1);
+            });
+            let code: Code<Operand> = Code::from_byte_code(&mut BufReader::new(file));
+            if let Err(error) = check_symbols(&code, &it) {
+                eprintln!("{}", error);
+                process::exit(1);
+            }
+
+            let constants: WriteManyTable<Operand> = WriteManyTable::new();
+            let mut machine = Machine::new(code, &constants, &it);
+            let stdin = io::stdin();
+            let stdout = io::stdout();
+            Repl::new(&mut machine)
+                .run(stdin.lock(), &mut stdout.lock())
+                .unwrap();
+        }
+        2 if args[0] == "--example" => {
+            let mut file = File::create(&args[1]).unwrap_or_else(|error| {
+                eprintln!("Unable to create {}: {}", args[1], error);
+                process::exit(1);
+            });
+            example(&it).to_byte_code(&mut file);
+        }
+        _ => usage(),
+    }
+}
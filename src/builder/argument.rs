@@ -0,0 +1,173 @@
+//! Instruction arguments.
+//!
+//! `Builder::push` takes operands which are all stored in the data section.
+//! Instructions which declare immediate or label arguments (see
+//! `Instruction::with_arg_kinds`) are pushed with `Builder::emit` instead,
+//! which takes an `Argument` of the right kind for each position.
+//!
+//! ## Examples
+//!
+//! ```
+//! use stack_vm::{ArgKind, Argument, Instruction, InstructionTable, Builder, Code, Machine};
+//!
+//! fn push(machine: &mut Machine<i64>, args: &[usize]) {
+//!     let arg = *machine.get_data(args[0]);
+//!     machine.operand_push(arg);
+//! }
+//!
+//! fn push_small(machine: &mut Machine<i64>, args: &[usize]) {
+//!     machine.operand_push(args[0] as i64);
+//! }
+//!
+//! fn jump(machine: &mut Machine<i64>, args: &[usize]) {
+//!     machine.jump_ip(args[0]);
+//! }
+//!
+//! let mut instruction_table = InstructionTable::new();
+//! instruction_table.insert(Instruction::new(0, "push", 1, push));
+//! instruction_table.insert(
+//!     Instruction::new(1, "push_small", 1, push_small).with_arg_kinds(vec![ArgKind::Immediate]),
+//! );
+//! instruction_table.insert(
+//!     Instruction::new(2, "jump", 1, jump).with_arg_kinds(vec![ArgKind::Label]),
+//! );
+//!
+//! let mut builder: Builder<i64> = Builder::new(&instruction_table);
+//! builder.emit("jump", vec![Argument::Label("end".to_string())]);
+//! builder.push("push", vec![-1]);
+//! builder.label("end");
+//! builder.emit("push_small", vec![Argument::Immediate(7)]);
+//!
+//! let code = Code::from(builder);
+//! assert_eq!(code.code, [2, 1, 6, 0, 1, 0, 1, 1, 7]);
+//! assert_eq!(code.data, [-1]);
+//! ```
+
+use super::{BuildError, Builder};
+use crate::instruction::ArgKind;
+use std::fmt;
+
+/// An argument to an instruction pushed with `Builder::emit`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Argument<T> {
+    /// An operand to store in the data section.
+    Data(T),
+    /// A value to store directly in the code.
+    Immediate(usize),
+    /// The name of a label, whose address is stored in the code.
+    Label(String),
+}
+
+impl<T> Argument<T> {
+    /// The kind of argument an instruction must declare to accept this one.
+    pub fn kind(&self) -> ArgKind {
+        match self {
+            Argument::Data(_) => ArgKind::Data,
+            Argument::Immediate(_) => ArgKind::Immediate,
+            Argument::Label(_) => ArgKind::Label,
+        }
+    }
+}
+
+impl<'a, T: fmt::Debug + PartialEq> Builder<'a, T> {
+    /// Push an instruction with arguments of any kind into the code.
+    ///
+    /// Label arguments may name labels which haven't been placed yet; their
+    /// addresses are filled in when the builder is finished.  Local names
+    /// (starting with a `.`) are resolved from where the builder is now.
+    ///
+    /// Panics if there's no such instruction, it takes a different number of
+    /// arguments or an argument is of the wrong kind.  Use `try_emit` to
+    /// handle those errors yourself.
+    pub fn emit(&mut self, name: &str, args: Vec<Argument<T>>) {
+        self.try_emit(name, args).unwrap_or_else(|error| panic!("{}", error));
+    }
+
+    /// Push an instruction with arguments of any kind into the code, or
+    /// return an error if there's no such instruction, it takes a different
+    /// number of arguments or an argument is of the wrong kind.
+    ///
+    /// Nothing is pushed if there's an error.
+    pub fn try_emit(&mut self, name: &str, args: Vec<Argument<T>>) -> Result<(), BuildError> {
+        self.try_push_args(name, args, true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::code::Code;
+    use crate::instruction::Instruction;
+    use crate::instruction_table::InstructionTable;
+    use crate::machine::Machine;
+
+    fn noop(_machine: &mut Machine<usize>, _args: &[usize]) {}
+
+    fn example_instruction_table() -> InstructionTable<usize> {
+        let mut it = InstructionTable::new();
+        it.insert(Instruction::new(0, "push", 1, noop));
+        it.insert(
+            Instruction::new(1, "jump_n", 2, noop)
+                .with_arg_kinds(vec![ArgKind::Immediate, ArgKind::Label]),
+        );
+        it
+    }
+
+    #[test]
+    fn emit() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("push", vec![123]);
+        builder.label("function");
+        builder.emit(
+            "jump_n",
+            vec![Argument::Immediate(3), Argument::Label(".end".to_string())],
+        );
+        builder.emit("push", vec![Argument::Data(123)]);
+        builder.label(".end");
+        let listing = "@0 = 123\n\n.main:\n\tpush @0\n\n.function:\n\tjump_n 3 .function.end\n\tpush @0\n\n.function.end:\n";
+        assert_eq!(format!("{:?}", builder), listing);
+
+        let code = Code::from(builder);
+        assert_eq!(code.code, [0, 1, 0, 1, 2, 3, 10, 0, 1, 0]);
+        assert_eq!(code.arg_kinds, [(1, vec![ArgKind::Immediate, ArgKind::Label])]);
+        assert_eq!(format!("{:?}", code), listing);
+    }
+
+    #[test]
+    fn wrong_argument_kind() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        assert_eq!(
+            builder.try_emit("jump_n", vec![Argument::Immediate(3), Argument::Data(4)]),
+            Err(BuildError::WrongArgumentKind {
+                name: "jump_n".to_string(),
+                position: 1,
+                expected: ArgKind::Label
+            })
+        );
+        assert_eq!(
+            builder.try_push("jump_n", vec![1, 2]),
+            Err(BuildError::WrongArgumentKind {
+                name: "jump_n".to_string(),
+                position: 0,
+                expected: ArgKind::Immediate
+            })
+        );
+        assert!(builder.is_empty());
+    }
+
+    #[test]
+    fn unknown_label() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.emit(
+            "jump_n",
+            vec![Argument::Immediate(3), Argument::Label("nowhere".to_string())],
+        );
+        assert_eq!(
+            builder.finish().unwrap_err(),
+            BuildError::UnknownLabel("nowhere".to_string())
+        );
+    }
+}
@@ -0,0 +1,463 @@
+//! Structured control flow.
+//!
+//! Conditionals and loops in stack-vm code are made of labels and jumps,
+//! and writing them out by hand means inventing a unique label name for
+//! every branch.  These helpers generate the labels and emit the jumps for
+//! you, using whichever jump instructions your instruction table provides.
+//!
+//! They expect two instructions, each taking a label name as its only
+//! argument:
+//!
+//! * an unconditional jump, and
+//! * a conditional jump which pops a condition from the operand stack and
+//!   jumps if it's true.
+//!
+//! Label names are passed to these instructions as operands, so your
+//! operand type must implement `From<&str>`.  If a jump instruction declares
+//! it's argument as `ArgKind::Label` then it's given the label's address
+//! instead.
+//!
+//! ## Examples
+//!
+//! ```
+//! use stack_vm::{Instruction, InstructionTable, Builder, Code, Machine, WriteManyTable};
+//!
+//! #[derive(Clone, Debug, PartialEq)]
+//! enum Operand {
+//!     I(i64),
+//!     S(String),
+//! }
+//!
+//! impl<'a> From<&'a str> for Operand {
+//!     fn from(s: &'a str) -> Operand {
+//!         Operand::S(s.to_string())
+//!     }
+//! }
+//!
+//! fn push(machine: &mut Machine<Operand>, args: &[usize]) {
+//!     let arg = machine.get_data(args[0]).clone();
+//!     machine.operand_push(arg);
+//! }
+//!
+//! fn jump(machine: &mut Machine<Operand>, args: &[usize]) {
+//!     if let Operand::S(label) = machine.get_data(args[0]).clone() {
+//!         machine.jump(&label);
+//!     }
+//! }
+//!
+//! fn jump_if(machine: &mut Machine<Operand>, args: &[usize]) {
+//!     if machine.operand_pop() != Operand::I(0) {
+//!         jump(machine, args);
+//!     }
+//! }
+//!
+//! let mut instruction_table = InstructionTable::new();
+//! instruction_table.insert(Instruction::new(0, "push", 1, push));
+//! instruction_table.insert(Instruction::new(1, "jump", 1, jump));
+//! instruction_table.insert(Instruction::new(2, "jump_if", 1, jump_if));
+//!
+//! let mut builder: Builder<Operand> = Builder::new(&instruction_table);
+//! builder.push("push", vec![Operand::I(1)]);
+//! builder.if_else(
+//!     "jump_if",
+//!     "jump",
+//!     |builder| builder.push("push", vec![Operand::from("yes")]),
+//!     |builder| builder.push("push", vec![Operand::from("no")]),
+//! );
+//!
+//! let constants: WriteManyTable<Operand> = WriteManyTable::new();
+//! let mut machine = Machine::new(Code::from(builder), &constants, &instruction_table);
+//! machine.run();
+//! assert_eq!(machine.operand_pop(), Operand::from("yes"));
+//! ```
+
+use super::{Argument, BuildError, Builder};
+use crate::instruction::ArgKind;
+use crate::table::Table;
+use std::fmt;
+
+impl<'a, T: fmt::Debug + PartialEq> Builder<'a, T> {
+    /// Generate a fresh label name.
+    ///
+    /// The name starts with `prefix` and is different from every label in
+    /// the builder, placed or only declared, and every name generated
+    /// before.  It isn't placed; pass it to `label` to do that.
+    pub fn gensym(&mut self, prefix: &str) -> String {
+        loop {
+            let name = format!("{}#{}", prefix, self.gensyms);
+            self.gensyms += 1;
+            let declared = self.declared.iter().any(|(declared, _)| *declared == name);
+            if !self.labels.contains_key(&name) && !declared {
+                return name;
+            }
+        }
+    }
+}
+
+impl<'a, T> Builder<'a, T>
+where
+    T: fmt::Debug + PartialEq + for<'l> From<&'l str>,
+{
+    /// Emit code which runs `then` if the condition on top of the operand
+    /// stack is true.
+    pub fn if_then<F>(&mut self, jump_if: &str, jump: &str, then: F)
+    where
+        F: FnOnce(&mut Self),
+    {
+        self.if_else(jump_if, jump, then, |_builder| ());
+    }
+
+    /// Emit code which runs `then` if the condition on top of the operand
+    /// stack is true, and `otherwise` if it isn't.
+    pub fn if_else<F, G>(&mut self, jump_if: &str, jump: &str, then: F, otherwise: G)
+    where
+        F: FnOnce(&mut Self),
+        G: FnOnce(&mut Self),
+    {
+        let then_label = self.gensym("then");
+        let end_label = self.gensym("end_if");
+
+        self.jump_to(jump_if, &then_label);
+        otherwise(self);
+        self.jump_to(jump, &end_label);
+        self.insert_label(&then_label);
+        then(self);
+        self.insert_label(&end_label);
+    }
+
+    /// Emit a loop which runs `condition`, and then `body` if the condition
+    /// it left on the operand stack is true, for as long as it is.
+    ///
+    /// `break_loop` and `continue_loop` may be used inside `body`.
+    pub fn while_loop<F, G>(&mut self, jump_if: &str, jump: &str, condition: F, body: G)
+    where
+        F: FnOnce(&mut Self),
+        G: FnOnce(&mut Self),
+    {
+        let start_label = self.gensym("while");
+        let body_label = self.gensym("do");
+        let end_label = self.gensym("end_while");
+
+        self.insert_label(&start_label);
+        condition(self);
+        self.jump_to(jump_if, &body_label);
+        self.jump_to(jump, &end_label);
+        self.insert_label(&body_label);
+        self.loops.push((start_label.clone(), end_label.clone()));
+        body(self);
+        self.loops.pop();
+        self.jump_to(jump, &start_label);
+        self.insert_label(&end_label);
+    }
+
+    /// Emit a loop which runs `body` until it breaks out with `break_loop`.
+    ///
+    /// `continue_loop` may also be used inside `body`.
+    pub fn loop_forever<F>(&mut self, jump: &str, body: F)
+    where
+        F: FnOnce(&mut Self),
+    {
+        let start_label = self.gensym("loop");
+        let end_label = self.gensym("end_loop");
+
+        self.insert_label(&start_label);
+        self.loops.push((start_label.clone(), end_label.clone()));
+        body(self);
+        self.loops.pop();
+        self.jump_to(jump, &start_label);
+        self.insert_label(&end_label);
+    }
+
+    /// Jump out of the innermost loop.
+    ///
+    /// Panics if there's no loop to break out of.  Use `try_break_loop` to
+    /// handle that yourself.
+    pub fn break_loop(&mut self, jump: &str) {
+        self.try_break_loop(jump)
+            .unwrap_or_else(|error| panic!("{}", error));
+    }
+
+    /// Jump out of the innermost loop, or return an error if there's no loop
+    /// or the jump instruction can't be pushed.
+    pub fn try_break_loop(&mut self, jump: &str) -> Result<(), BuildError> {
+        let label = match self.loops.last() {
+            Some((_start, end)) => end.clone(),
+            None => return Err(BuildError::OutsideLoop("break".to_string())),
+        };
+        self.try_jump_to(jump, &label)
+    }
+
+    /// Jump back to the start of the innermost loop.
+    ///
+    /// In a `while_loop` this checks the condition again.  Panics if there's
+    /// no loop to continue.  Use `try_continue_loop` to handle that
+    /// yourself.
+    pub fn continue_loop(&mut self, jump: &str) {
+        self.try_continue_loop(jump)
+            .unwrap_or_else(|error| panic!("{}", error));
+    }
+
+    /// Jump back to the start of the innermost loop, or return an error if
+    /// there's no loop or the jump instruction can't be pushed.
+    pub fn try_continue_loop(&mut self, jump: &str) -> Result<(), BuildError> {
+        let label = match self.loops.last() {
+            Some((start, _end)) => start.clone(),
+            None => return Err(BuildError::OutsideLoop("continue".to_string())),
+        };
+        self.try_jump_to(jump, &label)
+    }
+
+    fn jump_to(&mut self, jump: &str, label: &str) {
+        self.try_jump_to(jump, label)
+            .unwrap_or_else(|error| panic!("{}", error));
+    }
+
+    fn try_jump_to(&mut self, jump: &str, label: &str) -> Result<(), BuildError> {
+        let kind = self
+            .instruction_table
+            .by_name(jump)
+            .map(|instruction| instruction.arg_kind(0));
+        if kind == Some(ArgKind::Label) {
+            self.try_emit(jump, vec![Argument::Label(label.to_string())])
+        } else {
+            self.try_push(jump, vec![T::from(label)])
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::code::Code;
+    use crate::instruction::Instruction;
+    use crate::instruction_table::InstructionTable;
+    use crate::machine::Machine;
+    use crate::write_many_table::WriteManyTable;
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum Operand {
+        I(i64),
+        S(String),
+    }
+
+    impl<'a> From<&'a str> for Operand {
+        fn from(s: &'a str) -> Operand {
+            Operand::S(s.to_string())
+        }
+    }
+
+    impl Operand {
+        fn to_i(&self) -> i64 {
+            match self {
+                Operand::I(i) => *i,
+                Operand::S(s) => panic!("Expected an integer but found {:?}", s),
+            }
+        }
+    }
+
+    fn push(machine: &mut Machine<Operand>, args: &[usize]) {
+        let arg = machine.get_data(args[0]).clone();
+        machine.operand_push(arg);
+    }
+
+    fn dup(machine: &mut Machine<Operand>, _args: &[usize]) {
+        let value = machine.operand_pop();
+        machine.operand_push(value.clone());
+        machine.operand_push(value);
+    }
+
+    fn add(machine: &mut Machine<Operand>, _args: &[usize]) {
+        let rhs = machine.operand_pop().to_i();
+        let lhs = machine.operand_pop().to_i();
+        machine.operand_push(Operand::I(lhs + rhs));
+    }
+
+    fn equal(machine: &mut Machine<Operand>, _args: &[usize]) {
+        let rhs = machine.operand_pop();
+        let lhs = machine.operand_pop();
+        machine.operand_push(Operand::I((lhs == rhs) as i64));
+    }
+
+    fn jump(machine: &mut Machine<Operand>, args: &[usize]) {
+        match machine.get_data(args[0]).clone() {
+            Operand::S(label) => machine.jump(&label),
+            Operand::I(i) => panic!("Expected a label but found {}", i),
+        }
+    }
+
+    fn jump_if(machine: &mut Machine<Operand>, args: &[usize]) {
+        if machine.operand_pop().to_i() != 0 {
+            jump(machine, args);
+        }
+    }
+
+    fn instruction_table() -> InstructionTable<Operand> {
+        let mut it = InstructionTable::new();
+        it.insert(Instruction::new(0, "push", 1, push));
+        it.insert(Instruction::new(1, "dup", 0, dup));
+        it.insert(Instruction::new(2, "add", 0, add));
+        it.insert(Instruction::new(3, "equal", 0, equal));
+        it.insert(Instruction::new(4, "jump", 1, jump));
+        it.insert(Instruction::new(5, "jump_if", 1, jump_if));
+        it
+    }
+
+    fn run(builder: Builder<Operand>, it: &InstructionTable<Operand>) -> Vec<Operand> {
+        let constants: WriteManyTable<Operand> = WriteManyTable::new();
+        let mut machine = Machine::new(Code::from(builder), &constants, it);
+        machine.run();
+        machine.operand_stack.as_slice().to_vec()
+    }
+
+    #[test]
+    fn gensym() {
+        let it = instruction_table();
+        let mut builder: Builder<Operand> = Builder::new(&it);
+        builder.label("else#0");
+        builder.declare_label("else#1");
+        assert_eq!(builder.gensym("else"), "else#2");
+        assert_eq!(builder.gensym("else"), "else#3");
+        assert_eq!(builder.gensym("end"), "end#4");
+    }
+
+    #[test]
+    fn if_else() {
+        let it = instruction_table();
+        for condition in 0..2 {
+            let mut builder: Builder<Operand> = Builder::new(&it);
+            builder.push("push", vec![Operand::I(condition)]);
+            builder.if_else(
+                "jump_if",
+                "jump",
+                |builder| builder.push("push", vec![Operand::from("then")]),
+                |builder| builder.push("push", vec![Operand::from("else")]),
+            );
+            let expected = if condition == 0 { "else" } else { "then" };
+            assert_eq!(run(builder, &it), vec![Operand::from(expected)]);
+        }
+    }
+
+    #[test]
+    fn if_then() {
+        let it = instruction_table();
+        let mut builder: Builder<Operand> = Builder::new(&it);
+        builder.push("push", vec![Operand::I(0)]);
+        builder.if_then("jump_if", "jump", |builder| {
+            builder.push("push", vec![Operand::from("then")])
+        });
+        builder.push("push", vec![Operand::from("after")]);
+        assert_eq!(run(builder, &it), vec![Operand::from("after")]);
+    }
+
+    #[test]
+    fn while_loop() {
+        // Counts up from zero while the counter isn't three.
+        let it = instruction_table();
+        let mut builder: Builder<Operand> = Builder::new(&it);
+        builder.push("push", vec![Operand::I(0)]);
+        builder.while_loop(
+            "jump_if",
+            "jump",
+            |builder| {
+                builder.push("dup", vec![]);
+                builder.push("push", vec![Operand::I(3)]);
+                builder.push("equal", vec![]);
+                builder.if_else(
+                    "jump_if",
+                    "jump",
+                    |builder| builder.push("push", vec![Operand::I(0)]),
+                    |builder| builder.push("push", vec![Operand::I(1)]),
+                );
+            },
+            |builder| {
+                builder.push("push", vec![Operand::I(1)]);
+                builder.push("add", vec![]);
+            },
+        );
+        assert_eq!(run(builder, &it), vec![Operand::I(3)]);
+    }
+
+    #[test]
+    fn loop_with_break_and_continue() {
+        // Counts up to five, skipping over the check at two.
+        let it = instruction_table();
+        let mut builder: Builder<Operand> = Builder::new(&it);
+        builder.push("push", vec![Operand::I(0)]);
+        builder.loop_forever("jump", |builder| {
+            builder.push("push", vec![Operand::I(1)]);
+            builder.push("add", vec![]);
+            builder.push("dup", vec![]);
+            builder.push("push", vec![Operand::I(2)]);
+            builder.push("equal", vec![]);
+            builder.if_then("jump_if", "jump", |builder| builder.continue_loop("jump"));
+            builder.push("dup", vec![]);
+            builder.push("push", vec![Operand::I(5)]);
+            builder.push("equal", vec![]);
+            builder.if_then("jump_if", "jump", |builder| builder.break_loop("jump"));
+        });
+        assert_eq!(run(builder, &it), vec![Operand::I(5)]);
+    }
+
+    #[test]
+    fn label_arguments() {
+        fn jump_ip(machine: &mut Machine<Operand>, args: &[usize]) {
+            machine.jump_ip(args[0]);
+        }
+
+        fn jump_ip_if(machine: &mut Machine<Operand>, args: &[usize]) {
+            if machine.operand_pop().to_i() != 0 {
+                machine.jump_ip(args[0]);
+            }
+        }
+
+        let mut it = instruction_table();
+        it.insert(Instruction::new(6, "jump_ip", 1, jump_ip).with_arg_kinds(vec![ArgKind::Label]));
+        it.insert(
+            Instruction::new(7, "jump_ip_if", 1, jump_ip_if).with_arg_kinds(vec![ArgKind::Label]),
+        );
+        let mut builder: Builder<Operand> = Builder::new(&it);
+        builder.push("push", vec![Operand::I(1)]);
+        builder.if_else(
+            "jump_ip_if",
+            "jump_ip",
+            |builder| builder.push("push", vec![Operand::from("then")]),
+            |builder| builder.push("push", vec![Operand::from("else")]),
+        );
+        assert_eq!(
+            builder.data(),
+            [Operand::I(1), Operand::from("else"), Operand::from("then")]
+        );
+        assert_eq!(run(builder, &it), vec![Operand::from("then")]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Attempted to break outside of a loop")]
+    fn break_outside_loop() {
+        let it = instruction_table();
+        let mut builder: Builder<Operand> = Builder::new(&it);
+        builder.break_loop("jump");
+    }
+
+    #[test]
+    fn try_break_and_continue() {
+        let it = instruction_table();
+        let mut builder: Builder<Operand> = Builder::new(&it);
+        assert_eq!(
+            builder.try_break_loop("jump"),
+            Err(BuildError::OutsideLoop("break".to_string()))
+        );
+        assert_eq!(
+            builder.try_continue_loop("jump"),
+            Err(BuildError::OutsideLoop("continue".to_string()))
+        );
+        builder.loop_forever("jump", |builder| {
+            assert_eq!(
+                builder.try_break_loop("nowhere"),
+                Err(BuildError::UnknownInstruction("nowhere".to_string()))
+            );
+            assert_eq!(builder.try_continue_loop("jump"), Ok(()));
+            assert_eq!(builder.try_break_loop("jump"), Ok(()));
+        });
+        assert!(builder.finish().is_ok());
+    }
+}
@@ -0,0 +1,172 @@
+//! The data section.
+//!
+//! Every operand pushed with an instruction is stored in the builder's data
+//! section, and an operand equal to one which is already there shares its
+//! slot, keeping the code small.
+//!
+//! Finding an equal operand means comparing against every operand so far,
+//! which makes building large programs slow.  If your operand type
+//! implements `Hash` and `Eq` then create the builder with
+//! `Builder::new_hashed` and operands are looked up by their hash instead.
+//!
+//! Sometimes two equal operands must stay separate, for example when an
+//! instruction modifies its operand in place.  Push those with
+//! `Builder::push_distinct`, which always gives each of its operands a slot
+//! of its own that no other operand will share.
+//!
+//! ## Examples
+//!
+//! ```
+//! use stack_vm::{Instruction, InstructionTable, Builder, Machine};
+//!
+//! fn push(machine: &mut Machine<i64>, args: &[usize]) {
+//!     let arg = *machine.get_data(args[0]);
+//!     machine.operand_push(arg);
+//! }
+//!
+//! let mut instruction_table = InstructionTable::new();
+//! instruction_table.insert(Instruction::new(0, "push", 1, push));
+//!
+//! let mut builder: Builder<i64> = Builder::new_hashed(&instruction_table);
+//! builder.push("push", vec![1]);
+//! builder.push("push", vec![1]);
+//! builder.push_distinct("push", vec![1]);
+//! assert_eq!(builder.data(), [1, 1]);
+//! ```
+
+use super::{Argument, BuildError, Builder};
+use crate::instruction_table::InstructionTable;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+impl<'a, T: fmt::Debug + PartialEq + Eq + Hash> Builder<'a, T> {
+    /// Create a new `Builder` which finds duplicate operands by their hash.
+    pub fn new_hashed(instruction_table: &'a InstructionTable<T>) -> Builder<T> {
+        let mut builder = Builder::new(instruction_table);
+        builder.hasher = Some(hash_operand::<T>);
+        builder
+    }
+}
+
+impl<'a, T: fmt::Debug + PartialEq> Builder<'a, T> {
+    /// Push an instruction into the code without sharing its operands with
+    /// any others.
+    ///
+    /// Panics if there's no such instruction or it takes a different number
+    /// of arguments.  Use `try_push_distinct` to handle those errors
+    /// yourself.
+    pub fn push_distinct(&mut self, name: &str, args: Vec<T>) {
+        self.try_push_distinct(name, args).unwrap_or_else(|error| panic!("{}", error));
+    }
+
+    /// Push an instruction into the code without sharing its operands with
+    /// any others, or return an error if there's no such instruction or it
+    /// takes a different number of arguments.
+    pub fn try_push_distinct(&mut self, name: &str, args: Vec<T>) -> Result<(), BuildError> {
+        let args = args.into_iter().map(Argument::Data).collect();
+        self.try_push_args(name, args, false)
+    }
+
+    /// Store an operand in the data section and return its index.
+    pub(super) fn push_data(&mut self, data: T, dedup: bool) -> usize {
+        if !dedup {
+            self.data.push(data);
+            let pos = self.data.len() - 1;
+            self.distinct.insert(pos);
+            return pos;
+        }
+
+        match self.hasher {
+            Some(hasher) => {
+                let existing = &self.data;
+                let candidates = self.data_index.entry(hasher(&data)).or_default();
+                if let Some(pos) = candidates.iter().find(|pos| existing[**pos] == data) {
+                    return *pos;
+                }
+                candidates.push(existing.len());
+                self.data.push(data);
+                self.data.len() - 1
+            }
+            None => {
+                let distinct = &self.distinct;
+                let pos = self
+                    .data
+                    .iter()
+                    .enumerate()
+                    .position(|(pos, d)| d == &data && !distinct.contains(&pos));
+                match pos {
+                    Some(pos) => pos,
+                    None => {
+                        self.data.push(data);
+                        self.data.len() - 1
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn hash_operand<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instruction::Instruction;
+    use crate::machine::Machine;
+
+    fn noop(_machine: &mut Machine<usize>, _args: &[usize]) {}
+
+    fn example_instruction_table() -> InstructionTable<usize> {
+        let mut it = InstructionTable::new();
+        it.insert(Instruction::new(0, "push", 1, noop));
+        it.insert(Instruction::new(1, "push2", 2, noop));
+        it
+    }
+
+    #[test]
+    fn hashed_data_is_deduped() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new_hashed(&it);
+        builder.push("push", vec![123]);
+        builder.push("push2", vec![456, 123]);
+        builder.push("push", vec![456]);
+        assert_eq!(builder.data(), [123, 456]);
+        assert_eq!(builder.instructions, [0, 1, 0, 1, 2, 1, 0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn distinct_data_is_not_shared() {
+        let it = example_instruction_table();
+        for hashed in &[false, true] {
+            let mut builder: Builder<usize> = if *hashed {
+                Builder::new_hashed(&it)
+            } else {
+                Builder::new(&it)
+            };
+            builder.push_distinct("push", vec![123]);
+            builder.push("push", vec![123]);
+            builder.push("push", vec![123]);
+            builder.push_distinct("push", vec![123]);
+            assert_eq!(builder.data(), [123, 123, 123]);
+            assert_eq!(builder.instructions, [0, 1, 0, 0, 1, 1, 0, 1, 1, 0, 1, 2]);
+        }
+    }
+
+    #[test]
+    fn hash_collisions() {
+        fn collide(_value: &usize) -> u64 {
+            0
+        }
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.hasher = Some(collide);
+        builder.push("push2", vec![1, 2]);
+        builder.push("push2", vec![2, 1]);
+        assert_eq!(builder.data(), [1, 2]);
+    }
+}
@@ -0,0 +1,124 @@
+//! Errors building code.
+
+use crate::instruction::ArgKind;
+use std::error;
+use std::fmt;
+
+/// The reasons a `Builder` can refuse an instruction or label, or refuse to
+/// finish.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BuildError {
+    /// There is no instruction with this name in the instruction table.
+    UnknownInstruction(String),
+    /// The instruction was given the wrong number of arguments.
+    WrongArity {
+        name: String,
+        expected: usize,
+        actual: usize,
+    },
+    /// A variadic instruction was given too few or too many arguments.
+    ArityOutOfRange {
+        name: String,
+        min: usize,
+        max: Option<usize>,
+        actual: usize,
+    },
+    /// An argument was of the wrong kind, such as an immediate where the
+    /// instruction expects data.
+    WrongArgumentKind {
+        name: String,
+        position: usize,
+        expected: ArgKind,
+    },
+    /// A label used as an argument was never placed.
+    UnknownLabel(String),
+    /// A label with this name has already been placed.
+    DuplicateLabel(String),
+    /// A label was declared but never placed.
+    UnplacedLabel(String),
+    /// The instruction takes label or local names, but no function to read
+    /// names from operands was set with `Builder::set_operand_name`.
+    NoOperandName(String),
+    /// An operand passed as a label name doesn't hold a name.  Holds the
+    /// operand's debug output.
+    NotALabelName(String),
+    /// A `break` or `continue` was emitted outside of a loop.
+    OutsideLoop(String),
+    /// A function was defined while another one was being built.
+    NestedFunction { name: String, outer: String },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::UnknownInstruction(name) => {
+                write!(f, "Unable to find instruction with name {:?}", name)
+            }
+            BuildError::WrongArity {
+                name,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Instruction {} has arity of {}, but you provided {} arguments.",
+                name, expected, actual
+            ),
+            BuildError::ArityOutOfRange {
+                name,
+                min,
+                max: Some(max),
+                actual,
+            } => write!(
+                f,
+                "Instruction {} takes between {} and {} arguments, but you provided {}.",
+                name, min, max, actual
+            ),
+            BuildError::ArityOutOfRange {
+                name,
+                min,
+                max: None,
+                actual,
+            } => write!(
+                f,
+                "Instruction {} takes at least {} arguments, but you provided {}.",
+                name, min, actual
+            ),
+            BuildError::WrongArgumentKind {
+                name,
+                position,
+                expected,
+            } => write!(
+                f,
+                "Argument {} of instruction {} should be {:?}.",
+                position, name, expected
+            ),
+            BuildError::UnknownLabel(name) => write!(f, "Unable to find label {}", name),
+            BuildError::DuplicateLabel(name) => write!(f, "Label {} has already been placed", name),
+            BuildError::UnplacedLabel(name) => {
+                write!(f, "Label {} was declared but never placed", name)
+            }
+            BuildError::NoOperandName(name) => write!(
+                f,
+                "Instruction {} takes names, but no operand name function has been set",
+                name
+            ),
+            BuildError::NotALabelName(operand) => {
+                write!(
+                    f,
+                    "Operand {} was used as a label name, but it doesn't hold a name",
+                    operand
+                )
+            }
+            BuildError::OutsideLoop(jump) => {
+                write!(f, "Attempted to {} outside of a loop", jump)
+            }
+            BuildError::NestedFunction { name, outer } => write!(
+                f,
+                "Unable to define function {} inside function {}",
+                name, outer
+            ),
+        }
+    }
+}
+
+impl error::Error for BuildError {}
@@ -0,0 +1,340 @@
+//! Function definitions.
+//!
+//! `Builder::function` places a function's label, runs a closure to build
+//! its body, and records a `Function` in the code describing it, so that
+//! tools can find the program's functions and their parameters and locals.
+//!
+//! How arguments reach a function and how it returns is up to your
+//! instruction set, so the code emitted at the start and end of every
+//! function is configurable.  Set a prologue with `Builder::set_prologue`
+//! (for example to pop each argument into a local) and an epilogue with
+//! `Builder::set_epilogue` (for example to return).  Both are closures, so
+//! they can capture whatever state your front end needs.
+//!
+//! ## Examples
+//!
+//! ```
+//! use stack_vm::{Instruction, InstructionTable, Builder, Code, Function, Machine};
+//!
+//! fn store(machine: &mut Machine<String>, args: &[usize]) {
+//!     let name = machine.get_data(args[0]).clone();
+//!     let value = machine.operand_pop();
+//!     machine.set_local(&name, value);
+//! }
+//!
+//! fn ret(machine: &mut Machine<String>, _args: &[usize]) {
+//!     machine.ret();
+//! }
+//!
+//! let mut instruction_table = InstructionTable::new();
+//! instruction_table.insert(Instruction::new(0, "store", 1, store));
+//! instruction_table.insert(Instruction::new(1, "ret", 0, ret));
+//!
+//! let mut builder: Builder<String> = Builder::new(&instruction_table);
+//! builder.set_prologue(|builder, function: &Function| {
+//!     for param in function.params.iter().rev() {
+//!         builder.push("store", vec![param.clone()]);
+//!     }
+//! });
+//! builder.set_epilogue(|builder, _function: &Function| builder.push("ret", vec![]));
+//! builder.push("ret", vec![]);
+//! builder.function("swap", &["a", "b"], |builder| {
+//!     builder.declare_local("tmp");
+//! });
+//!
+//! let code = Code::from(builder);
+//! let swap = code.function("swap").unwrap();
+//! assert_eq!(swap.arity(), 2);
+//! assert_eq!(swap.local_count(), 1);
+//! assert_eq!((swap.start, swap.end), (2, 10));
+//! ```
+
+use super::{BuildError, Builder};
+use crate::code::Function;
+use std::fmt;
+
+/// Emits code at the start or end of a function.
+///
+/// It's given the builder and a description of the function so far.
+pub type FunctionHook<'a, T> = Box<dyn Fn(&mut Builder<'a, T>, &Function) + 'a>;
+
+impl<'a, T: fmt::Debug + PartialEq> Builder<'a, T> {
+    /// Define a function.
+    ///
+    /// Places a label called `name`, emits the prologue, runs `body` to build
+    /// the rest of the function, and then emits the epilogue.  The function
+    /// is recorded in the code's functions, along with its parameters and
+    /// any locals declared in `body` with `declare_local`.
+    ///
+    /// Local labels (starting with a `.`) placed in `body` belong to the
+    /// function.  Panics if called while another function is being built,
+    /// or if the label has already been placed.
+    pub fn function<F>(&mut self, name: &str, params: &[&str], body: F)
+    where
+        F: FnOnce(&mut Self),
+    {
+        self.try_function(name, params, body)
+            .unwrap_or_else(|error| panic!("{}", error));
+    }
+
+    /// Define a function, or return an error if another function is being
+    /// built or the label has already been placed.
+    pub fn try_function<F>(&mut self, name: &str, params: &[&str], body: F) -> Result<(), BuildError>
+    where
+        F: FnOnce(&mut Self),
+    {
+        if let Some(ref outer) = self.function {
+            return Err(BuildError::NestedFunction {
+                name: name.to_string(),
+                outer: outer.name.clone(),
+            });
+        }
+
+        self.try_label(name)?;
+        let start = self.len();
+        self.function = Some(Function {
+            name: name.to_string(),
+            start,
+            end: start,
+            params: params.iter().map(|param| param.to_string()).collect(),
+            locals: vec![],
+        });
+
+        // The hooks are taken out of the builder while they run, as they
+        // need to borrow it mutably.
+        if let Some(prologue) = self.prologue.take() {
+            let function = self.current_function().unwrap().clone();
+            prologue(self, &function);
+            self.prologue = Some(prologue);
+        }
+        body(self);
+        if let Some(epilogue) = self.epilogue.take() {
+            let function = self.current_function().unwrap().clone();
+            epilogue(self, &function);
+            self.epilogue = Some(epilogue);
+        }
+
+        let mut function = self.function.take().unwrap();
+        function.end = self.len();
+        self.functions.push(function);
+        Ok(())
+    }
+
+    /// Declare a local in the function being built.
+    ///
+    /// Declaring a parameter, or the same local twice, has no effect.
+    /// Panics if no function is being built.
+    pub fn declare_local(&mut self, name: &str) {
+        let function = self
+            .function
+            .as_mut()
+            .unwrap_or_else(|| panic!("Unable to declare local {} outside of a function", name));
+        if !function.params.iter().chain(function.locals.iter()).any(|local| local == name) {
+            function.locals.push(name.to_string());
+        }
+    }
+
+    /// Returns the function being built, if there is one.
+    pub fn current_function(&self) -> Option<&Function> {
+        self.function.as_ref()
+    }
+
+    /// Set the code emitted at the start of every function.
+    pub fn set_prologue<F>(&mut self, prologue: F)
+    where
+        F: Fn(&mut Builder<'a, T>, &Function) + 'a,
+    {
+        self.prologue = Some(Box::new(prologue));
+    }
+
+    /// Set the code emitted at the end of every function.
+    pub fn set_epilogue<F>(&mut self, epilogue: F)
+    where
+        F: Fn(&mut Builder<'a, T>, &Function) + 'a,
+    {
+        self.epilogue = Some(Box::new(epilogue));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::code::Code;
+    use crate::instruction::Instruction;
+    use crate::instruction_table::InstructionTable;
+    use crate::machine::Machine;
+    use crate::write_many_table::WriteManyTable;
+
+    fn push(machine: &mut Machine<i64>, args: &[usize]) {
+        let arg = *machine.get_data(args[0]);
+        machine.operand_push(arg);
+    }
+
+    fn store(machine: &mut Machine<i64>, args: &[usize]) {
+        let name = format!("local{}", machine.get_data(args[0]));
+        let value = machine.operand_pop();
+        machine.set_local(&name, value);
+    }
+
+    fn load(machine: &mut Machine<i64>, args: &[usize]) {
+        let name = format!("local{}", machine.get_data(args[0]));
+        let value = *machine.get_local(&name).unwrap();
+        machine.operand_push(value);
+    }
+
+    fn sub(machine: &mut Machine<i64>, _args: &[usize]) {
+        let rhs = machine.operand_pop();
+        let lhs = machine.operand_pop();
+        machine.operand_push(lhs - rhs);
+    }
+
+    fn call(machine: &mut Machine<i64>, _args: &[usize]) {
+        machine.call("subtract");
+    }
+
+    fn ret(machine: &mut Machine<i64>, _args: &[usize]) {
+        machine.ret();
+    }
+
+    fn instruction_table() -> InstructionTable<i64> {
+        let mut it = InstructionTable::new();
+        it.insert(Instruction::new(0, "push", 1, push));
+        it.insert(Instruction::new(1, "store", 1, store));
+        it.insert(Instruction::new(2, "load", 1, load));
+        it.insert(Instruction::new(3, "sub", 0, sub));
+        it.insert(Instruction::new(4, "call", 0, call));
+        it.insert(Instruction::new(5, "ret", 0, ret));
+        it
+    }
+
+    // Locals are numbered in this instruction set, so parameters are stored
+    // by their position.
+    fn prologue(builder: &mut Builder<i64>, function: &Function) {
+        for index in (0..function.arity()).rev() {
+            builder.push("store", vec![index as i64]);
+        }
+    }
+
+    fn epilogue(builder: &mut Builder<i64>, _function: &Function) {
+        builder.push("ret", vec![]);
+    }
+
+    #[test]
+    fn function() {
+        let it = instruction_table();
+        let mut builder: Builder<i64> = Builder::new(&it);
+        builder.set_prologue(prologue);
+        builder.set_epilogue(epilogue);
+        builder.push("push", vec![10]);
+        builder.push("push", vec![3]);
+        builder.push("call", vec![]);
+        builder.push("ret", vec![]);
+        builder.function("subtract", &["lhs", "rhs"], |builder| {
+            builder.declare_local("lhs");
+            builder.declare_local("unused");
+            builder.declare_local("unused");
+            builder.push("load", vec![0]);
+            builder.push("load", vec![1]);
+            builder.push("sub", vec![]);
+        });
+
+        let code = Code::from(builder);
+        assert_eq!(
+            code.functions(),
+            &[Function {
+                name: "subtract".to_string(),
+                start: 10,
+                end: 26,
+                params: vec!["lhs".to_string(), "rhs".to_string()],
+                locals: vec!["unused".to_string()],
+            }]
+        );
+        assert_eq!(code.function_for_ip(25).unwrap().name, "subtract");
+        assert_eq!(code.function_for_ip(26), None);
+        assert_eq!(code.get_label_ip("subtract"), Some(10));
+
+        let constants: WriteManyTable<i64> = WriteManyTable::new();
+        let mut machine = Machine::new(code, &constants, &it);
+        assert_eq!(machine.current_function(), None);
+        for _i in 0..3 {
+            machine.step();
+        }
+        assert_eq!(machine.current_function().unwrap().name, "subtract");
+        machine.run();
+        assert_eq!(machine.operand_pop(), 7);
+    }
+
+    #[test]
+    fn without_hooks() {
+        let it = instruction_table();
+        let mut builder: Builder<i64> = Builder::new(&it);
+        builder.function("empty", &[], |_builder| ());
+        let code = Code::from(builder);
+        assert_eq!(code.function("empty").unwrap().start, 0);
+        assert_eq!(code.function("empty").unwrap().end, 0);
+    }
+
+    #[test]
+    fn hooks_capture_state() {
+        let it = instruction_table();
+        let mut builder: Builder<i64> = Builder::new(&it);
+        // Parameters are stored after the locals the front end reserved.
+        let reserved = 2;
+        builder.set_prologue(move |builder, function| {
+            for index in (0..function.arity()).rev() {
+                builder.push("store", vec![reserved + index as i64]);
+            }
+        });
+        let ret = "ret".to_string();
+        builder.set_epilogue(move |builder, _function| builder.push(&ret, vec![]));
+        builder.function("identity", &["x"], |builder| {
+            builder.push("load", vec![2]);
+        });
+
+        let code = Code::from(builder);
+        assert_eq!(code.data(), [2]);
+        assert_eq!(code.code(), [1, 1, 0, 2, 1, 0, 5, 0]);
+    }
+
+    #[test]
+    fn try_function() {
+        let it = instruction_table();
+        let mut builder: Builder<i64> = Builder::new(&it);
+        builder.label("taken");
+        assert_eq!(
+            builder.try_function("taken", &[], |_builder| ()),
+            Err(BuildError::DuplicateLabel("taken".to_string()))
+        );
+        let mut nested = Ok(());
+        builder
+            .try_function("outer", &[], |builder| {
+                nested = builder.try_function("inner", &[], |_builder| ());
+            })
+            .unwrap();
+        assert_eq!(
+            nested,
+            Err(BuildError::NestedFunction {
+                name: "inner".to_string(),
+                outer: "outer".to_string()
+            })
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Unable to define function inner inside function outer")]
+    fn nested() {
+        let it = instruction_table();
+        let mut builder: Builder<i64> = Builder::new(&it);
+        builder.function("outer", &[], |builder| {
+            builder.function("inner", &[], |_builder| ());
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "outside of a function")]
+    fn declare_local_outside_function() {
+        let it = instruction_table();
+        let mut builder: Builder<i64> = Builder::new(&it);
+        builder.declare_local("x");
+    }
+}
@@ -0,0 +1,210 @@
+//! Label handles.
+//!
+//! `Builder::label` names the current position, but a compiler often needs
+//! to refer to a label before it knows where it goes, such as the end of an
+//! `if` statement or a function which is called before it's defined.  A
+//! `Label` is a handle to a label which has been declared but not yet
+//! placed.  Use its name as the operand of a jump or call straight away and
+//! place it later.  If any declared label was never placed then
+//! `Builder::finish` returns `BuildError::UnplacedLabel`, and `Code::from`
+//! panics.
+//!
+//! Labels whose names start with a `.` are local to the label placed before
+//! them, so every function can have its own `.loop` without the names
+//! clashing.  Anonymous labels get a fresh name of their own.
+//!
+//! ## Examples
+//!
+//! ```
+//! use stack_vm::{Instruction, InstructionTable, Builder, Code, Machine};
+//!
+//! fn jump(machine: &mut Machine<String>, args: &[usize]) {
+//!     let label = machine.get_data(args[0]).clone();
+//!     machine.jump(&label);
+//! }
+//!
+//! let mut instruction_table = InstructionTable::new();
+//! instruction_table.insert(Instruction::new(0, "jump", 1, jump));
+//! let mut builder: Builder<String> = Builder::new(&instruction_table);
+//!
+//! let end = builder.declare_label("end");
+//! let name = builder.label_name(end).to_string();
+//! builder.push("jump", vec![name]);
+//! builder.place_label(end);
+//!
+//! builder.label("function");
+//! let again = builder.declare_label(".again");
+//! assert_eq!(builder.label_name(again), "function.again");
+//! builder.place_label(again);
+//!
+//! let code = Code::from(builder);
+//! assert_eq!(code.get_label_ip("end"), Some(3));
+//! ```
+
+use super::{BuildError, Builder};
+use crate::table::Table;
+use std::fmt;
+
+/// A handle to a label declared with `Builder::declare_label` or
+/// `Builder::anonymous_label`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Label(usize);
+
+impl<'a, T: fmt::Debug + PartialEq> Builder<'a, T> {
+    /// Declare a label without placing it.
+    ///
+    /// Local names (starting with a `.`) belong to the label placed most
+    /// recently, just as they do with `label`.
+    pub fn declare_label(&mut self, name: &str) -> Label {
+        let opens_scope = !name.starts_with('.');
+        let name = self.local_name(name);
+        self.declare(name, opens_scope)
+    }
+
+    /// Declare a label with a fresh name of its own.
+    ///
+    /// Anonymous labels don't start a new scope for local labels when
+    /// placed.
+    pub fn anonymous_label(&mut self) -> Label {
+        let name = self.gensym("anon");
+        self.declare(name, false)
+    }
+
+    /// Place a declared label at this point in the code.
+    ///
+    /// Panics if the label has already been placed.
+    pub fn place_label(&mut self, label: Label) {
+        self.try_place_label(label).unwrap_or_else(|error| panic!("{}", error));
+    }
+
+    /// Place a declared label at this point in the code, or return an error
+    /// if it has already been placed.
+    pub fn try_place_label(&mut self, label: Label) -> Result<(), BuildError> {
+        let (name, opens_scope) = self.declared[label.0].clone();
+        self.try_insert_label(&name)?;
+        if opens_scope {
+            self.scope = name;
+        }
+        Ok(())
+    }
+
+    /// The full name of a declared label.
+    ///
+    /// This is the name to use when referring to the label from an
+    /// instruction.
+    pub fn label_name(&self, label: Label) -> &str {
+        &self.declared[label.0].0
+    }
+
+    /// Returns the full name of the label `name` from where the builder is
+    /// now.
+    ///
+    /// A local name, starting with a `.`, is prefixed with the name of the
+    /// label which most recently started a scope: `.loop` after
+    /// `label("square")` is `square.loop`.  Any other name is returned as it
+    /// is.
+    pub fn local_name(&self, name: &str) -> String {
+        if name.starts_with('.') {
+            format!("{}{}", self.scope, name)
+        } else {
+            name.to_string()
+        }
+    }
+
+    /// The names of labels which have been declared but not placed.
+    pub fn unplaced_labels(&self) -> Vec<&str> {
+        self.declared
+            .iter()
+            .filter(|(name, _opens_scope)| !self.labels.contains_key(name))
+            .map(|(name, _opens_scope)| name.as_str())
+            .collect()
+    }
+
+    fn declare(&mut self, name: String, opens_scope: bool) -> Label {
+        self.declared.push((name, opens_scope));
+        Label(self.declared.len() - 1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::code::Code;
+    use crate::instruction::Instruction;
+    use crate::instruction_table::InstructionTable;
+    use crate::machine::Machine;
+
+    fn noop(_machine: &mut Machine<usize>, _args: &[usize]) {}
+
+    fn example_instruction_table() -> InstructionTable<usize> {
+        let mut it = InstructionTable::new();
+        it.insert(Instruction::new(0, "noop", 0, noop));
+        it
+    }
+
+    #[test]
+    fn forward_reference() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        let function = builder.declare_label("function");
+        builder.push("noop", vec![]);
+        assert_eq!(builder.unplaced_labels(), vec!["function"]);
+        builder.place_label(function);
+        builder.push("noop", vec![]);
+        assert!(builder.unplaced_labels().is_empty());
+        let code = Code::from(builder);
+        assert_eq!(code.get_label_ip("function"), Some(2));
+    }
+
+    #[test]
+    fn local_labels() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.label(".start");
+        builder.label("first");
+        builder.push("noop", vec![]);
+        builder.label(".loop");
+        let second = builder.declare_label("second");
+        builder.place_label(second);
+        builder.label(".loop");
+        let anonymous = builder.anonymous_label();
+        builder.place_label(anonymous);
+        assert_eq!(builder.local_name(".end"), "second.end");
+        let code = Code::from(builder);
+        assert_eq!(code.get_label_ip("main.start"), Some(0));
+        assert_eq!(code.get_label_ip("first.loop"), Some(2));
+        assert_eq!(code.get_label_ip("second.loop"), Some(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "Label function was declared but never placed")]
+    fn unplaced_label() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.declare_label("function");
+        let _code: Code<usize> = Code::from(builder);
+    }
+
+    #[test]
+    fn unplaced_label_error() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.declare_label("function");
+        let end = builder.anonymous_label();
+        builder.place_label(end);
+        assert_eq!(
+            builder.finish().unwrap_err(),
+            BuildError::UnplacedLabel("function".to_string())
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "has already been placed")]
+    fn place_twice() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        let label = builder.declare_label("function");
+        builder.place_label(label);
+        builder.place_label(label);
+    }
+}
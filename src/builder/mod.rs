@@ -0,0 +1,652 @@
+//! The instruction builder.
+//!
+//! Use this module to build code for your machine to execute.
+//!
+//! ## Examples
+//!
+//! ```
+//! use stack_vm::{Instruction, InstructionTable, Builder, Machine};
+//!
+//! fn push(machine: &mut Machine<f64>, args: &[usize]) {
+//!     let arg = machine.get_data(args[0]).clone();
+//!     machine.operand_push(arg)
+//! }
+//!
+//! let mut instruction_table: InstructionTable<f64> = InstructionTable::new();
+//! instruction_table.insert(Instruction::new(0, "push", 1, push));
+//!
+//! let mut builder: Builder<f64> = Builder::new(&instruction_table);
+//! builder.push("push", vec![1.23]);
+//! ```
+
+use crate::code::{describe_data, Code, Function, OperandName};
+use crate::instruction::ArgKind;
+use crate::instruction_table::InstructionTable;
+use crate::source_map::{SourceLocation, SourceMap};
+use crate::table::Table;
+use crate::write_once_table::WriteOnceTable;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+mod argument;
+mod control_flow;
+mod data;
+mod error;
+mod function;
+mod labels;
+
+pub use self::argument::Argument;
+pub use self::error::BuildError;
+pub use self::function::FunctionHook;
+pub use self::labels::Label;
+
+/// The builder struct.
+///
+/// Contains:
+/// * an `InstructionTable`.
+/// * a list of instructions that have been pushed into this builder.
+/// * a `Table` of labels used for jumping.
+/// * a list of `T` to be stored in the builder's data section, and an index
+///   used to find duplicates in it.
+/// * a `SourceMap` of the source locations of the instructions.
+/// * a list of the `Function`s defined so far.
+pub struct Builder<'a, T: 'a + fmt::Debug + PartialEq> {
+    pub instruction_table: &'a InstructionTable<T>,
+    pub instructions: Vec<usize>,
+    pub labels: WriteOnceTable<usize>,
+    data: Vec<T>,
+    hasher: Option<fn(&T) -> u64>,
+    operand_name: Option<OperandName<T>>,
+    data_index: HashMap<u64, Vec<usize>>,
+    distinct: BTreeSet<usize>,
+    pub source_map: SourceMap,
+    location: Option<SourceLocation>,
+    gensyms: usize,
+    loops: Vec<(String, String)>,
+    scope: String,
+    declared: Vec<(String, bool)>,
+    pub functions: Vec<Function>,
+    function: Option<Function>,
+    prologue: Option<FunctionHook<'a, T>>,
+    epilogue: Option<FunctionHook<'a, T>>,
+    fixups: Vec<(usize, String)>,
+    label_names: Vec<usize>,
+}
+
+impl<'a, T: fmt::Debug + PartialEq> Builder<'a, T> {
+    /// Create a new `Builder` from an `InstructionTable`.
+    ///
+    /// Operands are deduplicated by comparing each one with every operand in
+    /// the data section so far.  If your operands can be hashed then use
+    /// `new_hashed` instead, which is much faster for large programs.
+    pub fn new(instruction_table: &'a InstructionTable<T>) -> Builder<T> {
+        let mut labels = WriteOnceTable::new();
+        labels.insert("main", 0);
+        Builder {
+            instruction_table,
+            instructions: vec![],
+            labels,
+            data: vec![],
+            hasher: None,
+            operand_name: None,
+            data_index: HashMap::new(),
+            distinct: BTreeSet::new(),
+            source_map: SourceMap::new(),
+            location: None,
+            gensyms: 0,
+            loops: vec![],
+            scope: "main".to_string(),
+            declared: vec![],
+            functions: vec![],
+            function: None,
+            prologue: None,
+            epilogue: None,
+            fixups: vec![],
+            label_names: vec![],
+        }
+    }
+
+    /// Push an instruction into the code.
+    ///
+    /// * `name` should match that of an instruction in the `InstructionTable`.
+    /// * `args` a vector of operands to be pushed into the builder's data
+    ///   section.  An operand equal to one already there is shared with it.
+    ///
+    /// Panics if there's no such instruction or it takes a different number
+    /// of arguments.  Use `try_push` to handle those errors yourself.
+    pub fn push(&mut self, name: &str, args: Vec<T>) {
+        self.try_push(name, args)
+            .unwrap_or_else(|error| panic!("{}", error));
+    }
+
+    /// Push an instruction into the code, or return an error if there's no
+    /// such instruction or it takes a different number of arguments.
+    ///
+    /// Nothing is pushed if there's an error.
+    pub fn try_push(&mut self, name: &str, args: Vec<T>) -> Result<(), BuildError> {
+        let args = args.into_iter().map(Argument::Data).collect();
+        self.try_push_args(name, args, true)
+    }
+
+    fn try_push_args(
+        &mut self,
+        name: &str,
+        args: Vec<Argument<T>>,
+        dedup: bool,
+    ) -> Result<(), BuildError> {
+        let instr = self
+            .instruction_table
+            .by_name(name)
+            .ok_or_else(|| BuildError::UnknownInstruction(name.to_string()))?;
+
+        if instr.is_variadic() && !instr.accepts_arity(args.len()) {
+            return Err(BuildError::ArityOutOfRange {
+                name: instr.name.clone(),
+                min: instr.arity,
+                max: instr.max_arity,
+                actual: args.len(),
+            });
+        }
+        if !instr.accepts_arity(args.len()) {
+            return Err(BuildError::WrongArity {
+                name: instr.name.clone(),
+                expected: instr.arity,
+                actual: args.len(),
+            });
+        }
+
+        for (position, arg) in args.iter().enumerate() {
+            let expected = instr.arg_kind(position);
+            let is_name = expected == ArgKind::LabelName || expected == ArgKind::LocalName;
+            if is_name && self.operand_name.is_none() {
+                return Err(BuildError::NoOperandName(instr.name.clone()));
+            }
+            let matches = match arg {
+                Argument::Data(_) => expected.is_data(),
+                _ => arg.kind() == expected,
+            };
+            if !matches {
+                return Err(BuildError::WrongArgumentKind {
+                    name: instr.name.clone(),
+                    position,
+                    expected,
+                });
+            }
+        }
+
+        let start = self.instructions.len();
+        self.instructions.push(instr.op_code);
+        self.instructions.push(args.len());
+        let label_names: Vec<bool> = (0..args.len())
+            .map(|position| instr.arg_kind(position) == ArgKind::LabelName)
+            .collect();
+        for (arg, is_label_name) in args.into_iter().zip(label_names) {
+            let word = match arg {
+                Argument::Data(data) => {
+                    let index = self.push_data(data, dedup);
+                    if is_label_name {
+                        self.label_names.push(index);
+                    }
+                    index
+                }
+                Argument::Immediate(value) => value,
+                Argument::Label(label) => {
+                    let label = self.local_name(&label);
+                    self.fixups.push((self.instructions.len(), label));
+                    0
+                }
+            };
+            self.instructions.push(word);
+        }
+
+        if let Some(ref location) = self.location {
+            self.source_map
+                .insert(start, self.instructions.len(), location.clone());
+        }
+        Ok(())
+    }
+
+    /// Set the source location of the instructions pushed from now on.
+    ///
+    /// The location stays in effect until it is changed or cleared, and is
+    /// recorded in the code's source map.
+    pub fn set_location(&mut self, file: &str, line: usize, column: usize) {
+        self.location = Some(SourceLocation::new(file, line, column));
+    }
+
+    /// Stop recording a source location for the instructions pushed from now
+    /// on.
+    ///
+    /// Useful for instructions the compiler generates which don't correspond
+    /// to anything in the source.
+    pub fn clear_location(&mut self) {
+        self.location = None;
+    }
+
+    /// Returns the current source location, if one is set.
+    pub fn location(&self) -> Option<&SourceLocation> {
+        self.location.as_ref()
+    }
+
+    /// Set the function which reads the names held by operands.
+    ///
+    /// Instructions with arguments declared as `ArgKind::LabelName` or
+    /// `ArgKind::LocalName` can only be pushed once it's set.  The builder
+    /// uses it to check label names, and passes it on to the `Code`.
+    pub fn set_operand_name(&mut self, operand_name: OperandName<T>) {
+        self.operand_name = Some(operand_name);
+    }
+
+    /// Returns the data section built so far.
+    ///
+    /// Operands are added by `push`, which keeps the index used to find
+    /// duplicates up to date, so the data section can't be changed directly.
+    pub fn data(&self) -> &[T] {
+        &self.data
+    }
+
+    /// Insert a label at this point in the code.
+    ///
+    /// Labels are used as targets for jumps.  When you call this method a
+    /// label is stored which points to the position of the next instruction.
+    ///
+    /// A name starting with a `.` is a local label, which belongs to the
+    /// label placed before it (see `local_name`).  Any other name starts a
+    /// new scope for local labels.
+    ///
+    /// Panics if the label has already been placed.  Use `try_label` to
+    /// handle that yourself.
+    pub fn label(&mut self, name: &str) {
+        self.try_label(name)
+            .unwrap_or_else(|error| panic!("{}", error));
+    }
+
+    /// Insert a label at this point in the code, or return an error if the
+    /// label has already been placed.
+    pub fn try_label(&mut self, name: &str) -> Result<(), BuildError> {
+        let full_name = self.local_name(name);
+        self.try_insert_label(&full_name)?;
+        if !name.starts_with('.') {
+            self.scope = full_name;
+        }
+        Ok(())
+    }
+
+    /// Validate the code built so far and convert it into `Code`.
+    ///
+    /// Fills in the addresses of labels used as arguments, and returns an
+    /// error if one of them doesn't exist or a label was declared with
+    /// `declare_label` but never placed.  `Code::from` does the same, but
+    /// panics instead.
+    pub fn finish(self) -> Result<Code<T>, BuildError> {
+        if let Some(name) = self.unplaced_labels().first() {
+            return Err(BuildError::UnplacedLabel(name.to_string()));
+        }
+
+        let mut instructions = self.instructions;
+        for (position, label) in &self.fixups {
+            match self.labels.get(label) {
+                Some(ip) => instructions[*position] = *ip,
+                None => return Err(BuildError::UnknownLabel(label.clone())),
+            }
+        }
+        for index in &self.label_names {
+            let data = &self.data[*index];
+            match self
+                .operand_name
+                .and_then(|operand_name| operand_name(data))
+            {
+                Some(name) if self.labels.contains_key(name) => (),
+                Some(name) => return Err(BuildError::UnknownLabel(name.to_string())),
+                None => return Err(BuildError::NotALabelName(format!("{:?}", data))),
+            }
+        }
+
+        let symbols = self.instruction_table.symbols();
+        let arg_kinds = self.instruction_table.arg_kinds();
+        let mut labels = vec![];
+        for key in self.labels.keys() {
+            let idx = self.labels.get(&key).unwrap();
+            labels.push((*idx, key.clone()));
+        }
+        labels.sort_by(|lhs, rhs| lhs.0.cmp(&rhs.0).then_with(|| lhs.1.cmp(&rhs.1)));
+
+        Ok(Code {
+            symbols,
+            code: instructions,
+            data: self.data,
+            labels,
+            source_map: self.source_map,
+            functions: self.functions,
+            arg_kinds,
+            operand_name: self.operand_name,
+        })
+    }
+
+    fn insert_label(&mut self, name: &str) {
+        self.try_insert_label(name)
+            .unwrap_or_else(|error| panic!("{}", error));
+    }
+
+    fn try_insert_label(&mut self, name: &str) -> Result<(), BuildError> {
+        if self.labels.contains_key(name) {
+            return Err(BuildError::DuplicateLabel(name.to_string()));
+        }
+        let idx = self.instructions.len();
+        self.labels.insert(name, idx);
+        Ok(())
+    }
+
+    /// Return the length of the instructions vector.
+    ///
+    /// i.e. the number of instructions pushed so far.
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    /// Return whether the Builder contains any instructions.
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+}
+
+impl<'a, T: 'a + fmt::Debug + PartialEq> fmt::Debug for Builder<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut result = String::new();
+
+        for i in 0..self.data.len() {
+            result.push_str(&format!("@{} = {:?}\n", i, self.data[i]));
+        }
+
+        let mut ip = 0;
+        let len = self.instructions.len();
+        loop {
+            for label in self.labels.keys() {
+                let idx = *self.labels.get(&label).unwrap();
+                if idx == ip {
+                    result.push_str(&format!("\n.{}:\n", label));
+                }
+            }
+
+            if ip == len {
+                break;
+            }
+
+            let op_code = self.instructions[ip];
+            ip += 1;
+            let arity = self.instructions[ip];
+
+            let instr = self
+                .instruction_table
+                .by_op_code(op_code)
+                .unwrap_or_else(|| panic!("Unable to find instruction with op code {}", op_code));
+
+            result.push_str(&format!("\t{}", &instr.name));
+
+            for j in 0..arity {
+                ip += 1;
+                let arg = self.instructions[ip];
+                match instr.arg_kind(j) {
+                    ArgKind::Immediate => result.push_str(&format!(" {}", arg)),
+                    ArgKind::Label => {
+                        let fixup = self.fixups.iter().find(|fixup| fixup.0 == ip).unwrap();
+                        result.push_str(&format!(" .{}", fixup.1));
+                    }
+                    kind => result.push_str(&format!(
+                        " {}",
+                        describe_data(kind, arg, &self.data, self.operand_name)
+                    )),
+                }
+            }
+            result.push('\n');
+
+            ip += 1;
+        }
+
+        write!(f, "{}", result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instruction::Instruction;
+    use crate::instruction_table::InstructionTable;
+    use crate::machine::Machine;
+
+    fn noop(_machine: &mut Machine<usize>, _args: &[usize]) {}
+
+    fn example_instruction_table() -> InstructionTable<usize> {
+        let mut it = InstructionTable::new();
+        it.insert(Instruction::new(0, "noop", 0, noop));
+        it.insert(Instruction::new(1, "push", 1, noop));
+        it.insert(Instruction::new(2, "pop", 0, noop));
+        it
+    }
+
+    #[test]
+    fn new() {
+        let it = example_instruction_table();
+        let builder: Builder<usize> = Builder::new(&it);
+        assert!(builder.instructions.is_empty());
+    }
+
+    #[test]
+    fn push() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("noop", vec![]);
+        assert!(!builder.instructions.is_empty());
+    }
+
+    #[test]
+    fn set_location() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("noop", vec![]);
+        builder.set_location("example.src", 1, 1);
+        builder.push("push", vec![1]);
+        builder.push("pop", vec![]);
+        builder.set_location("example.src", 2, 3);
+        builder.push("noop", vec![]);
+        builder.clear_location();
+        builder.push("noop", vec![]);
+        assert_eq!(
+            builder.source_map.ranges(),
+            &[
+                (2, 7, SourceLocation::new("example.src", 1, 1)),
+                (7, 9, SourceLocation::new("example.src", 2, 3))
+            ]
+        );
+        assert_eq!(builder.location(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "has arity of")]
+    fn push_with_incorrect_arity() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("noop", vec![1]);
+    }
+
+    #[test]
+    fn label() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("noop", vec![]);
+        builder.label("wow");
+        assert_eq!(*builder.labels.get("wow").unwrap(), 2);
+    }
+
+    #[test]
+    fn try_push() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        assert_eq!(
+            builder.try_push("nope", vec![]),
+            Err(BuildError::UnknownInstruction("nope".to_string()))
+        );
+        assert_eq!(
+            builder.try_push("push", vec![]),
+            Err(BuildError::WrongArity {
+                name: "push".to_string(),
+                expected: 1,
+                actual: 0
+            })
+        );
+        assert!(builder.is_empty());
+        assert_eq!(builder.try_push("push", vec![1]), Ok(()));
+        assert_eq!(builder.len(), 3);
+    }
+
+    #[test]
+    fn try_label() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        assert_eq!(
+            builder.try_label("main"),
+            Err(BuildError::DuplicateLabel("main".to_string()))
+        );
+        assert_eq!(builder.try_label("function"), Ok(()));
+        assert_eq!(builder.try_label(".loop"), Ok(()));
+        assert_eq!(
+            builder.try_label(".loop"),
+            Err(BuildError::DuplicateLabel("function.loop".to_string()))
+        );
+    }
+
+    #[test]
+    fn finish() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.declare_label("function");
+        builder.push("noop", vec![]);
+        assert_eq!(
+            builder.finish().unwrap_err(),
+            BuildError::UnplacedLabel("function".to_string())
+        );
+
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("push", vec![2]);
+        let code = builder.finish().unwrap();
+        assert_eq!(code.code, [1, 1, 0]);
+        assert_eq!(code.data, [2]);
+    }
+
+    #[test]
+    fn variadic() {
+        let mut it = example_instruction_table();
+        it.insert(Instruction::new(10, "make_list", 0, noop).with_variadic_arity(None));
+        it.insert(Instruction::new(11, "call_native", 1, noop).with_variadic_arity(Some(2)));
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("make_list", vec![]);
+        builder.push("make_list", vec![4, 5, 6]);
+        builder.push("call_native", vec![7, 4]);
+        assert_eq!(builder.instructions, [10, 0, 10, 3, 0, 1, 2, 11, 2, 3, 0]);
+        assert_eq!(
+            format!("{:?}", builder),
+            concat!(
+                "@0 = 4\n@1 = 5\n@2 = 6\n@3 = 7\n\n",
+                ".main:\n\tmake_list\n\tmake_list @0 @1 @2\n\tcall_native @3 @0\n"
+            )
+        );
+        assert_eq!(
+            builder.try_push("call_native", vec![]),
+            Err(BuildError::ArityOutOfRange {
+                name: "call_native".to_string(),
+                min: 1,
+                max: Some(2),
+                actual: 0
+            })
+        );
+        assert_eq!(
+            builder
+                .try_push("call_native", vec![1, 2, 3])
+                .unwrap_err()
+                .to_string(),
+            "Instruction call_native takes between 1 and 2 arguments, but you provided 3."
+        );
+
+        let code = Code::from(builder);
+        assert_eq!(code.verify(&it), Ok(()));
+        assert!(format!("{:?}", code).ends_with("\tmake_list @0 @1 @2\n\tcall_native @3 @0\n"));
+    }
+
+    #[test]
+    fn label_names() {
+        fn noop(_machine: &mut Machine<String>, _args: &[usize]) {}
+
+        let mut it = InstructionTable::new();
+        it.insert(Instruction::new(0, "jump", 1, noop).with_arg_kinds(vec![ArgKind::LabelName]));
+        let name: OperandName<String> = |operand| Some(operand);
+        let mut builder: Builder<String> = Builder::new(&it);
+        assert_eq!(
+            builder.try_push("jump", vec!["later".to_string()]),
+            Err(BuildError::NoOperandName("jump".to_string()))
+        );
+        builder.set_operand_name(name);
+        builder.push("jump", vec!["later".to_string()]);
+        builder.label("later");
+        assert_eq!(
+            format!("{:?}", builder),
+            "@0 = \"later\"\n\n.main:\n\tjump .later\n\n.later:\n"
+        );
+        assert!(builder.finish().is_ok());
+
+        let mut builder: Builder<String> = Builder::new(&it);
+        builder.set_operand_name(name);
+        builder.push("jump", vec!["nowhere".to_string()]);
+        assert_eq!(
+            builder.finish().unwrap_err(),
+            BuildError::UnknownLabel("nowhere".to_string())
+        );
+
+        let mut builder: Builder<String> = Builder::new(&it);
+        builder.set_operand_name(|operand| {
+            if operand.is_empty() {
+                None
+            } else {
+                Some(operand)
+            }
+        });
+        builder.push("jump", vec![String::new()]);
+        assert_eq!(
+            builder.finish().unwrap_err(),
+            BuildError::NotALabelName("\"\"".to_string())
+        );
+    }
+
+    #[test]
+    fn data_is_deduped() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("push", vec![123]);
+        builder.push("push", vec![123]);
+        builder.push("push", vec![123]);
+        assert_eq!(builder.data().len(), 1);
+    }
+
+    #[test]
+    fn debug_format() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("noop", vec![]);
+        builder.push("push", vec![123]);
+        builder.push("push", vec![456]);
+        builder.label("some_function");
+        builder.push("pop", vec![]);
+
+        let actual = format!("{:?}", builder);
+        let expected = "@0 = 123
+@1 = 456
+
+.main:
+\tnoop
+\tpush @0
+\tpush @1
+
+.some_function:
+\tpop
+";
+        assert_eq!(actual, expected);
+    }
+}
@@ -0,0 +1,251 @@
+//! Message-passing channels.
+//!
+//! Channels allow several `Machine`s to talk to each other by sending
+//! operands to named mailboxes.  A `Router` owns the mailboxes; each machine
+//! which is connected to the router can send to and receive from any of
+//! them.
+//!
+//! Receiving from an empty mailbox blocks: the machine rewinds to the start
+//! of the receiving instruction and suspends, so that the instruction is
+//! executed again the next time the machine is run.  `Router::run` takes
+//! care of running a group of machines until they have all finished or none
+//! of them can make progress, for example because they're all waiting for
+//! messages which haven't been sent.
+//!
+//! Green threads within a single machine can talk through mailboxes as
+//! well.  A thread which receives from an empty mailbox is not scheduled
+//! again until a message arrives there, and `Machine::run_threads` returns
+//! `false` when every thread is waiting and one of them is waiting for a
+//! message, so that the host can run other machines and try again.
+//!
+//! Everything happens in-process, so no networking is required.
+//!
+//! ## Examples
+//!
+//! ```
+//! use stack_vm::{Instruction, InstructionTable, Builder, Code, Machine, Router, WriteManyTable};
+//!
+//! fn push(machine: &mut Machine<i64>, args: &[usize]) {
+//!     let arg = *machine.get_data(args[0]);
+//!     machine.operand_push(arg);
+//! }
+//!
+//! fn send(machine: &mut Machine<i64>, _args: &[usize]) {
+//!     let value = machine.operand_pop();
+//!     machine.send("inbox", value);
+//! }
+//!
+//! fn receive(machine: &mut Machine<i64>, _args: &[usize]) {
+//!     if let Some(value) = machine.receive("inbox") {
+//!         machine.operand_push(value);
+//!     }
+//! }
+//!
+//! let mut instruction_table = InstructionTable::new();
+//! instruction_table.insert(Instruction::new(0, "push", 1, push));
+//! instruction_table.insert(Instruction::new(1, "send", 0, send));
+//! instruction_table.insert(Instruction::new(2, "receive", 0, receive));
+//!
+//! let mut builder: Builder<i64> = Builder::new(&instruction_table);
+//! builder.push("receive", vec![]);
+//! let receiver = Code::from(builder);
+//!
+//! let mut builder: Builder<i64> = Builder::new(&instruction_table);
+//! builder.push("push", vec![42]);
+//! builder.push("send", vec![]);
+//! let sender = Code::from(builder);
+//!
+//! let constants: WriteManyTable<i64> = WriteManyTable::new();
+//! let router = Router::new();
+//! let mut receiver = Machine::new(receiver, &constants, &instruction_table);
+//! let mut sender = Machine::new(sender, &constants, &instruction_table);
+//! receiver.connect(&router);
+//! sender.connect(&router);
+//!
+//! assert!(router.run(&mut [&mut receiver, &mut sender]));
+//! assert_eq!(receiver.operand_pop(), 42);
+//! ```
+
+use crate::machine::Machine;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// A queue of messages waiting to be received.
+///
+/// Mailboxes are cheap to clone; every clone refers to the same queue.
+#[derive(Debug)]
+pub struct Mailbox<T>(Arc<Mutex<VecDeque<T>>>);
+
+impl<T> Mailbox<T> {
+    /// Create a new, empty mailbox.
+    pub fn new() -> Mailbox<T> {
+        Mailbox(Arc::new(Mutex::new(VecDeque::new())))
+    }
+
+    /// Add a message to the back of the queue.
+    pub fn send(&self, value: T) {
+        self.0.lock().unwrap().push_back(value);
+    }
+
+    /// Take the message at the front of the queue, if there is one.
+    pub fn receive(&self) -> Option<T> {
+        self.0.lock().unwrap().pop_front()
+    }
+
+    /// The number of messages waiting in the queue.
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    /// Returns `true` if there are no messages waiting.
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+}
+
+impl<T> Clone for Mailbox<T> {
+    fn clone(&self) -> Mailbox<T> {
+        Mailbox(self.0.clone())
+    }
+}
+
+impl<T> Default for Mailbox<T> {
+    fn default() -> Mailbox<T> {
+        Mailbox::new()
+    }
+}
+
+#[derive(Debug)]
+struct Mailboxes<T> {
+    boxes: HashMap<String, Mailbox<T>>,
+    sent: usize,
+}
+
+/// A collection of named mailboxes shared between machines.
+///
+/// Routers are cheap to clone; every clone refers to the same mailboxes.
+/// Mailboxes are created the first time they are used.
+#[derive(Debug)]
+pub struct Router<T>(Arc<Mutex<Mailboxes<T>>>);
+
+impl<T> Router<T> {
+    /// Create a new router with no mailboxes.
+    pub fn new() -> Router<T> {
+        Router(Arc::new(Mutex::new(Mailboxes {
+            boxes: HashMap::new(),
+            sent: 0,
+        })))
+    }
+
+    /// Retrieve the mailbox with the given name, creating it if needed.
+    pub fn mailbox(&self, name: &str) -> Mailbox<T> {
+        let mut mailboxes = self.0.lock().unwrap();
+        mailboxes
+            .boxes
+            .entry(name.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Send a message to a named mailbox.
+    pub fn send(&self, name: &str, value: T) {
+        self.mailbox(name).send(value);
+        self.0.lock().unwrap().sent += 1;
+    }
+
+    /// Receive a message from a named mailbox without blocking.
+    pub fn receive(&self, name: &str) -> Option<T> {
+        self.mailbox(name).receive()
+    }
+
+    /// The total number of messages which have been sent through this
+    /// router.
+    pub fn sent(&self) -> usize {
+        self.0.lock().unwrap().sent
+    }
+}
+
+impl<T: fmt::Debug> Router<T> {
+    /// Run a group of machines until they are all finished.
+    ///
+    /// Each unfinished machine is run in turn until it finishes or
+    /// suspends.  Returns `true` once every machine has finished, or `false`
+    /// if a whole pass executed no instructions and sent no messages.  That
+    /// happens when the remaining machines are all waiting to receive
+    /// messages (i.e. they are deadlocked until the host sends them
+    /// something), or have been suspended without moving on, for example by
+    /// an observer.
+    pub fn run(&self, machines: &mut [&mut Machine<T>]) -> bool {
+        loop {
+            let sent = self.sent();
+            let mut progress = false;
+            let mut all_finished = true;
+
+            for machine in machines.iter_mut() {
+                if machine.is_finished() {
+                    continue;
+                }
+                let executed = machine.executed();
+                machine.run();
+                progress |= machine.executed() != executed;
+                if !machine.is_finished() {
+                    all_finished = false;
+                }
+            }
+
+            if all_finished {
+                return true;
+            }
+            if !progress && self.sent() == sent {
+                return false;
+            }
+        }
+    }
+}
+
+impl<T> Clone for Router<T> {
+    fn clone(&self) -> Router<T> {
+        Router(self.0.clone())
+    }
+}
+
+impl<T> Default for Router<T> {
+    fn default() -> Router<T> {
+        Router::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mailbox_is_fifo() {
+        let mailbox: Mailbox<usize> = Mailbox::new();
+        assert!(mailbox.is_empty());
+        mailbox.send(1);
+        mailbox.send(2);
+        assert_eq!(mailbox.len(), 2);
+        assert_eq!(mailbox.receive(), Some(1));
+        assert_eq!(mailbox.receive(), Some(2));
+        assert_eq!(mailbox.receive(), None);
+    }
+
+    #[test]
+    fn mailbox_clones_share_queue() {
+        let mailbox: Mailbox<usize> = Mailbox::new();
+        let other = mailbox.clone();
+        mailbox.send(13);
+        assert_eq!(other.receive(), Some(13));
+    }
+
+    #[test]
+    fn router_send_and_receive() {
+        let router: Router<usize> = Router::new();
+        router.send("example", 13);
+        assert_eq!(router.sent(), 1);
+        assert_eq!(router.receive("other"), None);
+        assert_eq!(router.receive("example"), Some(13));
+    }
+}
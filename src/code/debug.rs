@@ -11,39 +11,16 @@ impl<T: fmt::Debug> fmt::Debug for Code<T> {
         }
 
         // Loop through the code and print out useful stuff.
-        let mut ip = 0;
-        let len = self.code.len();
-        loop {
+        let end = self.code.len();
+        for ip in self.instruction_addresses().into_iter().chain(Some(end)) {
             // If this IP has a label, then print it out.
-            for label in self.labels() {
-                if ip == label.0 {
-                    result.push_str(&format!("\n.{}:\n", label.1));
-                    break;
-                }
+            if let Some(label) = self.label_at(ip) {
+                result.push_str(&format!("\n.{}:\n", label));
             }
 
-            if ip == len {
-                break;
+            if ip < end {
+                result.push_str(&format!("\t{}\n", self.describe_instruction(ip)));
             }
-
-            let op_code = self.code[ip];
-            let arity = self.code[ip + 1];
-            ip += 2;
-
-            // Print this instruction's name
-            for symbol in self.symbols() {
-                if op_code == symbol.0 {
-                    result.push_str(&format!("\t{}", symbol.1));
-                    break;
-                }
-            }
-
-            for _i in 0..arity {
-                let const_idx = self.code[ip];
-                ip += 1;
-                result.push_str(&format!(" @{}", const_idx));
-            }
-            result.push('\n');
         }
 
         write!(f, "{}", result)
@@ -2,13 +2,15 @@ use crate::from_byte_code::FromByteCode;
 use std::io::Read;
 use rmp::decode;
 use std::fmt;
-use super::Code;
+use super::{Code, Function};
+use crate::instruction::ArgKind;
+use crate::source_map::{SourceLocation, SourceMap};
 
 impl<T: FromByteCode + fmt::Debug> FromByteCode for Code<T> {
     fn from_byte_code(mut buf: &mut dyn Read) -> Code<T> {
-        // We expect a four-element map.
+        // We expect a four-element map, plus up to three optional sections.
         let map_len = decode::read_map_len(&mut buf).unwrap();
-        assert_eq!(map_len, 4);
+        assert!((4..=7).contains(&map_len));
 
         // We expect the code section next:
         let section = read_string(&mut buf);
@@ -42,7 +44,7 @@ impl<T: FromByteCode + fmt::Debug> FromByteCode for Code<T> {
             symbols.push((idx, symbol));
         }
 
-        // Lastly, labels.
+        // Next, labels.
         let section = read_string(&mut buf);
         assert_eq!(section, "labels");
 
@@ -54,18 +56,89 @@ impl<T: FromByteCode + fmt::Debug> FromByteCode for Code<T> {
             labels.push((idx, label));
         }
 
+        // Lastly, the optional debug, functions and args sections.
+        let mut source_map = SourceMap::new();
+        let mut functions: Vec<Function> = vec![];
+        let mut arg_kinds: Vec<(usize, Vec<ArgKind>)> = vec![];
+        for _i in 4..map_len {
+            let section = read_string(&mut buf);
+            match section.as_str() {
+                "debug" => {
+                    let debug_len = decode::read_array_len(&mut buf).unwrap();
+                    assert!(
+                        debug_len.is_multiple_of(5),
+                        "The debug section holds {} items, which isn't five per range",
+                        debug_len
+                    );
+                    for _i in 0..debug_len / 5 {
+                        let start = decode::read_int(&mut buf).unwrap();
+                        let end = decode::read_int(&mut buf).unwrap();
+                        let file = read_string(&mut buf);
+                        let line = decode::read_int(&mut buf).unwrap();
+                        let column = decode::read_int(&mut buf).unwrap();
+                        source_map.insert(start, end, SourceLocation::new(&file, line, column));
+                    }
+                }
+                "functions" => {
+                    let functions_len = decode::read_array_len(&mut buf).unwrap();
+                    for _i in 0..functions_len {
+                        let function_len = decode::read_array_len(&mut buf).unwrap();
+                        assert_eq!(function_len, 5);
+                        let name = read_string(&mut buf);
+                        let start = decode::read_int(&mut buf).unwrap();
+                        let end = decode::read_int(&mut buf).unwrap();
+                        let params = read_strings(&mut buf);
+                        let locals = read_strings(&mut buf);
+                        functions.push(Function { name, start, end, params, locals });
+                    }
+                }
+                "args" => {
+                    let args_len = decode::read_array_len(&mut buf).unwrap();
+                    assert!(
+                        args_len.is_multiple_of(2),
+                        "The args section holds {} items, which isn't two per op code",
+                        args_len
+                    );
+                    for _i in 0..args_len / 2 {
+                        let op_code = decode::read_int(&mut buf).unwrap();
+                        let kinds_len = decode::read_array_len(&mut buf).unwrap();
+                        let mut kinds = vec![];
+                        for _j in 0..kinds_len {
+                            let byte = decode::read_int(&mut buf).unwrap();
+                            kinds.push(
+                                ArgKind::from_byte(byte)
+                                    .unwrap_or_else(|| panic!("Unknown argument kind {}", byte)),
+                            );
+                        }
+                        arg_kinds.push((op_code, kinds));
+                    }
+                }
+                section => panic!("Unknown bytecode section {:?}", section),
+            }
+        }
+
         Code {
             symbols,
             code,
             data,
-            labels
+            labels,
+            source_map,
+            functions,
+            arg_kinds,
+            operand_name: None
         }
     }
 }
 
-fn read_string(mut buf: &mut dyn Read) -> String {
+/// Read a MessagePack string.
+pub(crate) fn read_string(mut buf: &mut dyn Read) -> String {
     let len = decode::read_str_len(&mut buf).unwrap();
     let mut strbuf: Vec<u8> = vec![0u8; len as usize];
     buf.read_exact(&mut strbuf).unwrap();
     String::from_utf8(strbuf).unwrap()
 }
+
+fn read_strings(mut buf: &mut dyn Read) -> Vec<String> {
+    let len = decode::read_array_len(&mut buf).unwrap();
+    (0..len).map(|_i| read_string(&mut buf)).collect()
+}
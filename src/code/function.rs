@@ -0,0 +1,45 @@
+use std::fmt;
+
+/// A function defined with `Builder::function`.
+///
+/// Records where the function's code is and the names of its parameters and
+/// locals, so that tools can describe a program's functions without knowing
+/// anything about the compiler which produced it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    /// The name of the function, which is also the label of its first
+    /// instruction.
+    pub name: String,
+    /// The address of the function's first instruction.
+    pub start: usize,
+    /// The address just past the function's last instruction.
+    pub end: usize,
+    /// The names of the function's parameters, in order.
+    pub params: Vec<String>,
+    /// The names of the function's locals, not including its parameters.
+    pub locals: Vec<String>,
+}
+
+impl Function {
+    /// The number of parameters the function takes.
+    pub fn arity(&self) -> usize {
+        self.params.len()
+    }
+
+    /// The number of locals the function declares, not including its
+    /// parameters.
+    pub fn local_count(&self) -> usize {
+        self.locals.len()
+    }
+
+    /// Returns `true` if the instruction at `ip` belongs to this function.
+    pub fn contains(&self, ip: usize) -> bool {
+        self.start <= ip && ip < self.end
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}({})", self.name, self.params.join(", "))
+    }
+}
@@ -82,12 +82,25 @@
 //! ```
 
 use crate::builder::Builder;
-use crate::table::Table;
+use crate::instruction::ArgKind;
+use crate::source_map::{SourceLocation, SourceMap};
 use std::convert::From;
 use std::fmt;
 mod debug;
 mod from_byte_code;
+mod function;
+mod stack_depth;
 mod to_byte_code;
+mod verify;
+
+pub use self::function::Function;
+pub use self::verify::VerifyError;
+pub(crate) use self::from_byte_code::read_string;
+
+/// Reads the name held by an operand which names a label or a local.
+///
+/// Returns `None` for operands which don't hold a name.
+pub type OperandName<T> = fn(&T) -> Option<&str>;
 
 /// A structure containing runnable or dumpable code.
 ///
@@ -97,6 +110,10 @@ pub struct Code<T> {
     pub code: Vec<usize>,
     pub data: Vec<T>,
     pub labels: Vec<(usize, String)>,
+    pub source_map: SourceMap,
+    pub functions: Vec<Function>,
+    pub arg_kinds: Vec<(usize, Vec<ArgKind>)>,
+    pub(crate) operand_name: Option<OperandName<T>>,
 }
 
 impl<T: fmt::Debug> Code<T> {
@@ -109,6 +126,10 @@ impl<T: fmt::Debug> Code<T> {
             code: vec![],
             data: vec![],
             labels: vec![],
+            source_map: SourceMap::new(),
+            functions: vec![],
+            arg_kinds: vec![],
+            operand_name: None,
         }
     }
 
@@ -145,6 +166,182 @@ impl<T: fmt::Debug> Code<T> {
         self.labels.as_slice()
     }
 
+    /// Returns the name of the instruction with the given op code.
+    pub fn symbol(&self, op_code: usize) -> Option<&str> {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.0 == op_code)
+            .map(|symbol| symbol.1.as_str())
+    }
+
+    /// Retrieve the argument kinds of every instruction which has arguments
+    /// other than data indexes.
+    ///
+    /// This is a list of tuples containing op codes and argument kinds.
+    pub fn arg_kinds(&self) -> &[(usize, Vec<ArgKind>)] {
+        self.arg_kinds.as_slice()
+    }
+
+    /// Returns the kind of an instruction's argument.
+    pub fn arg_kind(&self, op_code: usize, position: usize) -> ArgKind {
+        self.arg_kinds
+            .iter()
+            .find(|kinds| kinds.0 == op_code)
+            .and_then(|kinds| kinds.1.get(position).cloned())
+            .unwrap_or(ArgKind::Data)
+    }
+
+    /// Describe an instruction's argument for a listing.
+    ///
+    /// Data indexes are shown as `@3`, immediates as `3` and label addresses
+    /// as the name of the label, such as `.loop`.  Label names are shown the
+    /// same way as label addresses, and local names as the bare name.
+    pub fn describe_arg(&self, op_code: usize, position: usize, value: usize) -> String {
+        match self.arg_kind(op_code, position) {
+            ArgKind::Immediate => format!("{}", value),
+            ArgKind::Label => match self.labels.iter().find(|label| label.0 == value) {
+                Some((_ip, name)) => format!(".{}", name),
+                None => format!("{}", value),
+            },
+            kind => describe_data(kind, value, &self.data, self.operand_name),
+        }
+    }
+
+    /// Set the function which reads the names held by operands.
+    ///
+    /// It's needed to check and show arguments declared as
+    /// `ArgKind::LabelName` or `ArgKind::LocalName`.  Code built with
+    /// `Builder::set_operand_name` already has it, but code loaded from
+    /// bytecode doesn't.
+    pub fn set_operand_name(&mut self, operand_name: OperandName<T>) {
+        self.operand_name = Some(operand_name);
+    }
+
+    /// Returns the name held by the operand at `index` in the data section,
+    /// if there's a function to read it and the operand holds one.
+    pub fn data_name(&self, index: usize) -> Option<&str> {
+        let operand_name = self.operand_name?;
+        self.data.get(index).and_then(operand_name)
+    }
+
+    /// Returns the label at or immediately before the given IP.
+    ///
+    /// This is the label which "contains" the instruction at `ip`, and is
+    /// useful for describing addresses to humans.  If more than one label
+    /// points at the same address then the last one (by name) is returned.
+    pub fn label_for_ip(&self, ip: usize) -> Option<&(usize, String)> {
+        self.labels.iter().rev().find(|label| label.0 <= ip)
+    }
+
+    /// Returns a fingerprint of the executable parts of the code.
+    ///
+    /// The fingerprint is a 64-bit FNV-1a hash of the instructions, the
+    /// data section, the symbols, the labels and the argument kinds.  Data
+    /// is hashed through its `Debug` representation.  The fingerprint is
+    /// stable across builds and platforms (as long as that representation
+    /// is) so it can be stored alongside data (such as a machine snapshot)
+    /// which is only valid for this exact program.
+    pub fn fingerprint(&self) -> u64 {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        let mut write = |bytes: &[u8]| {
+            for byte in bytes {
+                hash ^= u64::from(*byte);
+                hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+            }
+        };
+        for word in self.code() {
+            write(&(*word as u64).to_le_bytes());
+        }
+        write(&(self.data.len() as u64).to_le_bytes());
+        for datum in self.data() {
+            let debug = format!("{:?}", datum);
+            write(&(debug.len() as u64).to_le_bytes());
+            write(debug.as_bytes());
+        }
+        for (ip, name) in self.symbols().iter().chain(self.labels().iter()) {
+            write(&(*ip as u64).to_le_bytes());
+            write(name.as_bytes());
+        }
+        for (op_code, kinds) in self.arg_kinds() {
+            write(&(*op_code as u64).to_le_bytes());
+            for kind in kinds {
+                write(&[kind.to_byte()]);
+            }
+        }
+        hash
+    }
+
+    /// Returns `true` if `ip` is the address of an instruction, or the
+    /// address just past the last one.
+    pub fn is_instruction_boundary(&self, ip: usize) -> bool {
+        ip == self.code.len() || self.instruction_addresses().contains(&ip)
+    }
+
+    /// Returns the address of every instruction, in order.
+    ///
+    /// Each instruction's arity word is used to find the next one.  A last
+    /// instruction which is cut short is still included, so check the code
+    /// with `verify` before reading the words at these addresses.
+    pub fn instruction_addresses(&self) -> Vec<usize> {
+        let mut addresses = vec![];
+        let mut ip = 0;
+        while ip < self.code.len() {
+            addresses.push(ip);
+            match self.code.get(ip + 1) {
+                Some(arity) => ip = ip.saturating_add(2).saturating_add(*arity),
+                None => break,
+            }
+        }
+        addresses
+    }
+
+    /// Returns the name of the label placed at `ip`, if there is one.
+    pub fn label_at(&self, ip: usize) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|label| label.0 == ip)
+            .map(|label| label.1.as_str())
+    }
+
+    /// Describe the instruction at `ip` as it appears in the disassembly,
+    /// such as `push @0`.
+    pub fn describe_instruction(&self, ip: usize) -> String {
+        let op_code = self.code[ip];
+        let mut result = self.symbol(op_code).unwrap_or("?").to_string();
+        for position in 0..self.code[ip + 1] {
+            let arg = self.code[ip + 2 + position];
+            result.push_str(&format!(" {}", self.describe_arg(op_code, position, arg)));
+        }
+        result
+    }
+
+    /// Retrieve the map from addresses to the source which produced them.
+    pub fn source_map(&self) -> &SourceMap {
+        &self.source_map
+    }
+
+    /// Returns the source location of the instruction at `ip`, if the code
+    /// was built with one.
+    pub fn source_location(&self, ip: usize) -> Option<&SourceLocation> {
+        self.source_map.lookup(ip)
+    }
+
+    /// Retrieve the functions defined with `Builder::function`, in address
+    /// order.
+    pub fn functions(&self) -> &[Function] {
+        self.functions.as_slice()
+    }
+
+    /// Returns the function with the given name.
+    pub fn function(&self, name: &str) -> Option<&Function> {
+        self.functions.iter().find(|function| function.name == name)
+    }
+
+    /// Returns the function containing the instruction at `ip`.
+    pub fn function_for_ip(&self, ip: usize) -> Option<&Function> {
+        self.functions.iter().find(|function| function.contains(ip))
+    }
+
     /// Returns the IP for a given label.
     ///
     /// This function is used within the `Machine` to perform jumps.
@@ -162,23 +359,26 @@ impl<'a, T: fmt::Debug + PartialEq> From<Builder<'a, T>> for Code<T> {
     /// Convert a `Builder` into `Code`.
     ///
     /// This function consumes the builder and returns a `Code`.
+    ///
+    /// Panics if a label was declared with `Builder::declare_label` but
+    /// never placed.  Use `Builder::finish` to handle that yourself.
     fn from(builder: Builder<T>) -> Code<T> {
-        let symbols = builder.instruction_table.symbols();
-        let code = builder.instructions;
-        let data = builder.data;
-        let mut labels = vec![];
-        for key in builder.labels.keys() {
-            let idx = builder.labels.get(&key).unwrap();
-            labels.push((*idx, key.clone()));
-        }
-        labels.sort_by(|lhs, rhs| lhs.0.cmp(&rhs.0));
+        builder.finish().unwrap_or_else(|error| panic!("{}", error))
+    }
+}
 
-        Code {
-            symbols,
-            code,
-            data,
-            labels,
-        }
+/// Describe an argument which is an index into `data`.
+pub(crate) fn describe_data<T>(
+    kind: ArgKind,
+    index: usize,
+    data: &[T],
+    operand_name: Option<OperandName<T>>,
+) -> String {
+    let name = operand_name.and_then(|operand_name| data.get(index).and_then(operand_name));
+    match (kind, name) {
+        (ArgKind::LabelName, Some(name)) => format!(".{}", name),
+        (ArgKind::LocalName, Some(name)) => name.to_string(),
+        _ => format!("@{}", index),
     }
 }
 
@@ -242,6 +442,84 @@ mod test {
         assert_eq!(code.get_label_ip("main").unwrap(), 0);
     }
 
+    #[test]
+    fn symbol() {
+        let it = example_instruction_table();
+        let builder: Builder<usize> = Builder::new(&it);
+        let code: Code<usize> = Code::from(builder);
+        assert_eq!(code.symbol(1), Some("push"));
+        assert_eq!(code.symbol(7), None);
+    }
+
+    #[test]
+    fn label_for_ip() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("noop", vec![]);
+        builder.label("some_function");
+        builder.push("push", vec![123]);
+        builder.push("pop", vec![]);
+        let code: Code<usize> = Code::from(builder);
+        assert_eq!(code.label_for_ip(0).unwrap().1, "main");
+        assert_eq!(code.label_for_ip(1).unwrap().1, "main");
+        assert_eq!(code.label_for_ip(2).unwrap().1, "some_function");
+        assert_eq!(code.label_for_ip(5).unwrap().1, "some_function");
+    }
+
+    #[test]
+    fn fingerprint() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("push", vec![13]);
+        let code: Code<usize> = Code::from(builder);
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("push", vec![13]);
+        let same: Code<usize> = Code::from(builder);
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("push", vec![14]);
+        let other_data: Code<usize> = Code::from(builder);
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("pop", vec![]);
+        let other_code: Code<usize> = Code::from(builder);
+
+        assert_eq!(code.fingerprint(), same.fingerprint());
+        assert_ne!(code.fingerprint(), other_data.fingerprint());
+        assert_ne!(code.fingerprint(), other_code.fingerprint());
+    }
+
+    #[test]
+    fn is_instruction_boundary() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("push", vec![13]);
+        builder.push("pop", vec![]);
+        let code: Code<usize> = Code::from(builder);
+        assert!(code.is_instruction_boundary(0));
+        assert!(!code.is_instruction_boundary(1));
+        assert!(!code.is_instruction_boundary(2));
+        assert!(code.is_instruction_boundary(3));
+        assert!(code.is_instruction_boundary(5));
+        assert!(!code.is_instruction_boundary(6));
+    }
+
+    #[test]
+    fn instruction_addresses() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("push", vec![13]);
+        builder.label("next");
+        builder.push("pop", vec![]);
+        let mut code: Code<usize> = Code::from(builder);
+        assert_eq!(code.instruction_addresses(), [0, 3]);
+        assert_eq!(code.label_at(0), Some("main"));
+        assert_eq!(code.label_at(3), Some("next"));
+        assert_eq!(code.label_at(1), None);
+        assert_eq!(code.describe_instruction(0), "push @0");
+
+        code.code.truncate(4);
+        assert_eq!(code.instruction_addresses(), [0, 3]);
+    }
+
     #[test]
     fn debug_formatter() {
         let it = example_instruction_table();
@@ -319,6 +597,110 @@ mod test {
                 (0_usize, "main".to_string()),
                 (8_usize, "some_function".to_string())
             ]
-        )
+        );
+        assert!(code.source_map.is_empty());
+    }
+
+    #[test]
+    fn source_map_round_trip() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.set_location("example.src", 1, 1);
+        builder.push("push", vec![123]);
+        builder.set_location("example.src", 2, 5);
+        builder.push("pop", vec![]);
+        let code = Code::from(builder);
+        assert_eq!(code.source_location(1).unwrap().line, 1);
+        assert_eq!(code.source_location(3).unwrap().line, 2);
+        assert_eq!(code.source_location(5), None);
+
+        let mut bytecode: Vec<u8> = vec![];
+        code.to_byte_code(&mut bytecode);
+        let loaded: Code<usize> = Code::from_byte_code(&mut &bytecode[..]);
+        assert_eq!(loaded.source_map, code.source_map);
+        assert_eq!(loaded.code, code.code);
+    }
+
+    #[test]
+    #[should_panic(expected = "The debug section holds 4 items, which isn't five per range")]
+    fn incomplete_source_map() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.set_location("example.src", 1, 1);
+        builder.push("noop", vec![]);
+        let mut bytecode: Vec<u8> = vec![];
+        Code::from(builder).to_byte_code(&mut bytecode);
+
+        // Shorten the debug section's array from five items to four.
+        let header = bytecode
+            .windows(7)
+            .position(|bytes| bytes == b"\xa5debug\x95")
+            .unwrap();
+        bytecode[header + 6] = 0x94;
+        let _code: Code<usize> = Code::from_byte_code(&mut &bytecode[..]);
+    }
+
+    #[test]
+    #[should_panic(expected = "The args section holds 3 items, which isn't two per op code")]
+    fn incomplete_arg_kinds() {
+        let mut it = example_instruction_table();
+        it.insert(Instruction::new(3, "add", 1, noop).with_arg_kinds(vec![ArgKind::Immediate]));
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("noop", vec![]);
+        let mut bytecode: Vec<u8> = vec![];
+        Code::from(builder).to_byte_code(&mut bytecode);
+
+        // Lengthen the args section's array from two items to three.
+        let header = bytecode
+            .windows(6)
+            .position(|bytes| bytes == b"\xa4args\x92")
+            .unwrap();
+        bytecode[header + 5] = 0x93;
+        let _code: Code<usize> = Code::from_byte_code(&mut &bytecode[..]);
+    }
+
+    #[test]
+    fn functions_round_trip() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("noop", vec![]);
+        builder.function("function", &["x"], |builder| {
+            builder.declare_local("y");
+            builder.push("pop", vec![]);
+        });
+        builder.set_location("example.src", 1, 1);
+        builder.function("other", &[], |builder| builder.push("noop", vec![]));
+        let code = Code::from(builder);
+
+        let mut bytecode: Vec<u8> = vec![];
+        code.to_byte_code(&mut bytecode);
+        let loaded: Code<usize> = Code::from_byte_code(&mut &bytecode[..]);
+        assert_eq!(loaded.functions, code.functions);
+        assert_eq!(loaded.function("function").unwrap().locals, ["y"]);
+        assert_eq!(loaded.source_map, code.source_map);
+    }
+
+    #[test]
+    fn operand_names() {
+        #[derive(Debug)]
+        enum Operand {
+            I(i64),
+            S(String),
+        }
+
+        fn name(operand: &Operand) -> Option<&str> {
+            match operand {
+                Operand::S(ref name) => Some(name),
+                Operand::I(_) => None,
+            }
+        }
+
+        let data = [Operand::S("say \"hi\"\n".to_string()), Operand::I(1)];
+        assert_eq!(describe_data(ArgKind::LabelName, 0, &data, Some(name)), ".say \"hi\"\n");
+        assert_eq!(describe_data(ArgKind::LocalName, 0, &data, Some(name)), "say \"hi\"\n");
+        assert_eq!(describe_data(ArgKind::LocalName, 1, &data, Some(name)), "@1");
+        assert_eq!(describe_data(ArgKind::LocalName, 2, &data, Some(name)), "@2");
+        assert_eq!(describe_data(ArgKind::Data, 0, &data, Some(name)), "@0");
+        assert_eq!(describe_data(ArgKind::LabelName, 0, &data, None), "@0");
     }
 }
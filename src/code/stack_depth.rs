@@ -0,0 +1,264 @@
+use super::{Code, VerifyError};
+use crate::instruction::{ArgKind, Flow, Instruction};
+use crate::instruction_table::InstructionTable;
+use std::collections::BTreeMap;
+use std::fmt;
+
+impl<T: fmt::Debug> Code<T> {
+    /// Work out the depth of the operand stack before every reachable
+    /// instruction, without running the code.
+    ///
+    /// Every path through the code is followed from address 0 with an empty
+    /// stack, and from the start of every function with one operand per
+    /// parameter.  Variadic instructions pop according to the number of
+    /// arguments stored with each use.  Returns an error if any instruction
+    /// on the way doesn't declare a stack effect, pops more operands than the
+    /// stack holds, or is reached by two paths with different depths.
+    ///
+    /// The code is checked with `verify` first, and any error it finds is
+    /// returned.  Every instruction is then known to be complete and to
+    /// have an op code in the instruction table, which the analysis relies
+    /// on when it reads each instruction's words.
+    ///
+    /// Only jumps to arguments declared as `ArgKind::Label` or
+    /// `ArgKind::LabelName` are followed, so code which jumps to labels it
+    /// computes at runtime may be partly unreachable as far as this is
+    /// concerned.
+    pub fn stack_depths(
+        &self,
+        instruction_table: &InstructionTable<T>,
+    ) -> Result<BTreeMap<usize, usize>, VerifyError> {
+        self.verify(instruction_table)?;
+
+        let mut pending = vec![];
+        if self.functions.iter().all(|function| function.start != 0) {
+            pending.push((0, 0));
+        }
+        for function in self.functions.iter().rev() {
+            pending.push((function.start, function.arity()));
+        }
+
+        let mut depths = BTreeMap::new();
+        while let Some((ip, depth)) = pending.pop() {
+            if ip >= self.code.len() {
+                continue;
+            }
+            match depths.get(&ip) {
+                Some(expected) if *expected == depth => continue,
+                Some(expected) => {
+                    return Err(VerifyError::StackMismatch {
+                        ip,
+                        expected: *expected,
+                        actual: depth,
+                    })
+                }
+                None => depths.insert(ip, depth),
+            };
+
+            let instruction = instruction_table.by_op_code(self.code[ip]).unwrap();
+            let effect = match instruction.stack_effect {
+                Some(effect) => effect,
+                None => {
+                    return Err(VerifyError::UnknownStackEffect {
+                        ip,
+                        name: instruction.name.clone(),
+                    })
+                }
+            };
+            let pops = effect.pops(self.code[ip + 1] - instruction.arity);
+            if depth < pops {
+                return Err(VerifyError::StackUnderflow { ip, depth, pops });
+            }
+            let depth = depth - pops + effect.pushes;
+
+            if instruction.flow == Flow::Next || instruction.flow == Flow::Branch {
+                pending.push((ip + 2 + self.code[ip + 1], depth));
+            }
+            if instruction.flow == Flow::Branch || instruction.flow == Flow::Jump {
+                for target in self.jump_targets(ip, instruction) {
+                    pending.push((target, depth));
+                }
+            }
+        }
+        Ok(depths)
+    }
+
+    /// The addresses the instruction at `ip` can jump to.
+    fn jump_targets(&self, ip: usize, instruction: &Instruction<T>) -> Vec<usize> {
+        let mut targets = vec![];
+        for position in 0..self.code[ip + 1] {
+            let arg = self.code[ip + 2 + position];
+            match instruction.arg_kind(position) {
+                ArgKind::Label => targets.push(arg),
+                ArgKind::LabelName => {
+                    let name = self.data_name(arg);
+                    if let Some(label) = self
+                        .labels
+                        .iter()
+                        .find(|label| Some(label.1.as_str()) == name)
+                    {
+                        targets.push(label.0);
+                    }
+                }
+                _ => (),
+            }
+        }
+        targets
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builder::{Argument, Builder};
+    use crate::machine::Machine;
+
+    fn noop(_machine: &mut Machine<usize>, _args: &[usize]) {}
+
+    fn example_instruction_table() -> InstructionTable<usize> {
+        let mut it = InstructionTable::new();
+        it.insert(Instruction::new(0, "push", 1, noop).with_stack_effect(0, 1));
+        it.insert(Instruction::new(1, "add", 0, noop).with_stack_effect(2, 1));
+        it.insert(
+            Instruction::new(2, "jump_if", 1, noop)
+                .with_arg_kinds(vec![ArgKind::Label])
+                .with_stack_effect(1, 0)
+                .with_flow(Flow::Branch),
+        );
+        it.insert(
+            Instruction::new(3, "jump", 1, noop)
+                .with_arg_kinds(vec![ArgKind::Label])
+                .with_stack_effect(0, 0)
+                .with_flow(Flow::Jump),
+        );
+        it.insert(
+            Instruction::new(4, "ret", 0, noop)
+                .with_stack_effect(1, 0)
+                .with_flow(Flow::Stop),
+        );
+        it.insert(Instruction::new(5, "print", 0, noop));
+        it.insert(
+            Instruction::new(6, "make_list", 0, noop)
+                .with_variadic_arity(None)
+                .with_variadic_stack_effect(0, 1, 1),
+        );
+        it
+    }
+
+    fn label(name: &str) -> Vec<Argument<usize>> {
+        vec![Argument::Label(name.to_string())]
+    }
+
+    #[test]
+    fn branches() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("push", vec![1]);
+        builder.emit("jump_if", label("else"));
+        builder.push("push", vec![2]);
+        builder.emit("jump", label("end"));
+        builder.label("else");
+        builder.push("push", vec![3]);
+        builder.label("end");
+        builder.push("ret", vec![]);
+        let depths = Code::from(builder).stack_depths(&it).unwrap();
+        let expected: Vec<(usize, usize)> = vec![(0, 0), (3, 1), (6, 0), (9, 1), (12, 0), (15, 1)];
+        assert_eq!(depths.into_iter().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn functions() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("push", vec![5]);
+        builder.push("ret", vec![]);
+        builder.function("increment", &["x"], |builder| {
+            builder.push("push", vec![1]);
+            builder.push("add", vec![]);
+            builder.push("ret", vec![]);
+        });
+        let depths = Code::from(builder).stack_depths(&it).unwrap();
+        assert_eq!(depths[&5], 1);
+        assert_eq!(depths[&8], 2);
+        assert_eq!(depths[&10], 1);
+    }
+
+    #[test]
+    fn underflow() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("push", vec![1]);
+        builder.push("add", vec![]);
+        assert_eq!(
+            Code::from(builder).stack_depths(&it),
+            Err(VerifyError::StackUnderflow {
+                ip: 3,
+                depth: 1,
+                pops: 2
+            })
+        );
+    }
+
+    #[test]
+    fn mismatch() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("push", vec![1]);
+        builder.emit("jump_if", label("end"));
+        builder.push("push", vec![2]);
+        builder.push("push", vec![3]);
+        builder.label("end");
+        builder.push("push", vec![4]);
+        assert_eq!(
+            Code::from(builder).stack_depths(&it),
+            Err(VerifyError::StackMismatch {
+                ip: 12,
+                expected: 0,
+                actual: 2
+            })
+        );
+    }
+
+    #[test]
+    fn unknown_stack_effect() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("push", vec![1]);
+        builder.push("print", vec![]);
+        assert_eq!(
+            Code::from(builder).stack_depths(&it),
+            Err(VerifyError::UnknownStackEffect {
+                ip: 3,
+                name: "print".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn variadic() {
+        let it = example_instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("push", vec![1]);
+        builder.push("push", vec![2]);
+        builder.push("push", vec![3]);
+        builder.push("make_list", vec![1, 2, 3]);
+        builder.push("make_list", vec![]);
+        builder.push("push", vec![4]);
+        let depths = Code::from(builder).stack_depths(&it).unwrap();
+        assert_eq!(depths[&9], 3);
+        assert_eq!(depths[&14], 1);
+        assert_eq!(depths[&16], 2);
+
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("push", vec![1]);
+        builder.push("make_list", vec![1, 2]);
+        assert_eq!(
+            Code::from(builder).stack_depths(&it),
+            Err(VerifyError::StackUnderflow {
+                ip: 3,
+                depth: 1,
+                pops: 2
+            })
+        );
+    }
+}
@@ -13,12 +13,33 @@ impl<T: ToByteCode + fmt::Debug> ToByteCode for Code<T> {
     ///     "code" => [ 0, 1, 0, 0, 1, 1, 1, 0 ],
     ///     "data" => [ 123, 456 ],
     ///     "symbols" => [ 0, "push", 1, "add" ],
-    ///     "labels" => [ 0, "main" ]
+    ///     "labels" => [ 0, "main" ],
+    ///     "debug" => [ 0, 5, "main.src", 1, 1 ],
+    ///     "functions" => [ [ "square", 8, 20, [ "x" ], [ "y" ] ] ],
+    ///     "args" => [ 2, [ 1, 2 ] ]
     /// }
     /// ```
+    ///
+    /// The "debug" section holds the source map as a start address, end
+    /// address, file, line and column for each range, the "functions"
+    /// section holds the name, start and end addresses, parameters and locals
+    /// of each function, and the "args" section holds the op code and
+    /// argument kinds of each instruction whose arguments aren't all data
+    /// indexes.  Each is only written when it isn't empty, so code built
+    /// without them produces the same 4-element map as it always has.
     fn to_byte_code(&self, mut buf: &mut dyn Write) {
-        // We're creating a 4-element map.
-        encode::write_map_len(&mut buf, 4).unwrap();
+        // We're creating a 4-element map, plus the optional sections.
+        let mut map_len = 4;
+        if !self.source_map.is_empty() {
+            map_len += 1;
+        }
+        if !self.functions.is_empty() {
+            map_len += 1;
+        }
+        if !self.arg_kinds.is_empty() {
+            map_len += 1;
+        }
+        encode::write_map_len(&mut buf, map_len).unwrap();
 
         // First, the code.
         encode::write_str(&mut buf, "code").unwrap();
@@ -42,12 +63,56 @@ impl<T: ToByteCode + fmt::Debug> ToByteCode for Code<T> {
             encode::write_str(&mut buf, &symbol.1).unwrap();
         }
 
-        // Lastly, the labels.
+        // Next, the labels.
         encode::write_str(&mut buf, "labels").unwrap();
         encode::write_array_len(&mut buf, (self.labels.len() * 2) as u32).unwrap();
         for label in self.labels() {
             encode::write_uint(&mut buf, label.0 as u64).unwrap();
             encode::write_str(&mut buf, &label.1).unwrap();
         }
+
+        // Next, the source map, if there is one.
+        if !self.source_map.is_empty() {
+            encode::write_str(&mut buf, "debug").unwrap();
+            encode::write_array_len(&mut buf, (self.source_map.len() * 5) as u32).unwrap();
+            for (start, end, location) in self.source_map.ranges() {
+                encode::write_uint(&mut buf, *start as u64).unwrap();
+                encode::write_uint(&mut buf, *end as u64).unwrap();
+                encode::write_str(&mut buf, &location.file).unwrap();
+                encode::write_uint(&mut buf, location.line as u64).unwrap();
+                encode::write_uint(&mut buf, location.column as u64).unwrap();
+            }
+        }
+
+        // Next, the functions, if there are any.
+        if !self.functions.is_empty() {
+            encode::write_str(&mut buf, "functions").unwrap();
+            encode::write_array_len(&mut buf, self.functions.len() as u32).unwrap();
+            for function in self.functions() {
+                encode::write_array_len(&mut buf, 5).unwrap();
+                encode::write_str(&mut buf, &function.name).unwrap();
+                encode::write_uint(&mut buf, function.start as u64).unwrap();
+                encode::write_uint(&mut buf, function.end as u64).unwrap();
+                for names in &[&function.params, &function.locals] {
+                    encode::write_array_len(&mut buf, names.len() as u32).unwrap();
+                    for name in names.iter() {
+                        encode::write_str(&mut buf, name).unwrap();
+                    }
+                }
+            }
+        }
+
+        // Lastly, the argument kinds, if any instruction needs them.
+        if !self.arg_kinds.is_empty() {
+            encode::write_str(&mut buf, "args").unwrap();
+            encode::write_array_len(&mut buf, (self.arg_kinds.len() * 2) as u32).unwrap();
+            for (op_code, kinds) in self.arg_kinds() {
+                encode::write_uint(&mut buf, *op_code as u64).unwrap();
+                encode::write_array_len(&mut buf, kinds.len() as u32).unwrap();
+                for kind in kinds {
+                    encode::write_uint(&mut buf, u64::from(kind.to_byte())).unwrap();
+                }
+            }
+        }
     }
 }
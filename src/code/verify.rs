@@ -0,0 +1,444 @@
+use super::Code;
+use crate::instruction::ArgKind;
+use crate::instruction_table::InstructionTable;
+use std::error;
+use std::fmt;
+
+/// The reasons `Code::verify` can reject code.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyError {
+    /// The code was built with an instruction which is missing from the
+    /// instruction table, or has a different name there.
+    UnknownSymbol { op_code: usize, name: String },
+    /// The instruction at `ip` has an op code missing from the instruction
+    /// table.
+    UnknownOpCode { ip: usize, op_code: usize },
+    /// The instruction at `ip` runs past the end of the code.
+    Truncated { ip: usize },
+    /// The instruction at `ip` has the wrong number of arguments.
+    WrongArity {
+        ip: usize,
+        expected: usize,
+        actual: usize,
+    },
+    /// The variadic instruction at `ip` has too few or too many arguments.
+    ArityOutOfRange {
+        ip: usize,
+        min: usize,
+        max: Option<usize>,
+        actual: usize,
+    },
+    /// An argument of the instruction at `ip` is an index past the end of
+    /// the data section.
+    InvalidData { ip: usize, index: usize },
+    /// An argument of the instruction at `ip` is an address which isn't the
+    /// start of an instruction or the end of the code.
+    InvalidTarget { ip: usize, target: usize },
+    /// A label points to an address which isn't the start of an instruction
+    /// or the end of the code.
+    InvalidLabel { name: String, ip: usize },
+    /// A label name argument of the instruction at `ip` doesn't name a
+    /// label.
+    UnknownLabelName { ip: usize, name: String },
+    /// A local name argument of the instruction at `ip` names a local which
+    /// isn't a parameter or declared local of the function it's in.
+    UnknownLocal { ip: usize, name: String },
+    /// The instruction at `ip` takes a label or local name, but the code
+    /// has no function to read names with.  See `Code::set_operand_name`.
+    NoOperandName { ip: usize },
+    /// The instruction at `ip` doesn't declare a stack effect, so the stack
+    /// depth after it can't be known.
+    UnknownStackEffect { ip: usize, name: String },
+    /// The instruction at `ip` pops more operands than can be on the stack.
+    StackUnderflow {
+        ip: usize,
+        depth: usize,
+        pops: usize,
+    },
+    /// Two paths reach `ip` with different stack depths.
+    StackMismatch {
+        ip: usize,
+        expected: usize,
+        actual: usize,
+    },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::UnknownSymbol { op_code, name } => write!(
+                f,
+                "Code uses instruction {} ({}) which isn't in the instruction table",
+                name, op_code
+            ),
+            VerifyError::UnknownOpCode { ip, op_code } => {
+                write!(f, "Unknown op code {} at address {}", op_code, ip)
+            }
+            VerifyError::Truncated { ip } => {
+                write!(f, "Instruction at address {} runs past the end of the code", ip)
+            }
+            VerifyError::WrongArity {
+                ip,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Instruction at address {} has {} arguments but should have {}",
+                ip, actual, expected
+            ),
+            VerifyError::ArityOutOfRange {
+                ip,
+                min,
+                max: Some(max),
+                actual,
+            } => write!(
+                f,
+                "Instruction at address {} has {} arguments but should have between {} and {}",
+                ip, actual, min, max
+            ),
+            VerifyError::ArityOutOfRange {
+                ip,
+                min,
+                max: None,
+                actual,
+            } => write!(
+                f,
+                "Instruction at address {} has {} arguments but should have at least {}",
+                ip, actual, min
+            ),
+            VerifyError::InvalidData { ip, index } => write!(
+                f,
+                "Instruction at address {} refers to missing data @{}",
+                ip, index
+            ),
+            VerifyError::InvalidTarget { ip, target } => write!(
+                f,
+                "Instruction at address {} refers to address {} which isn't an instruction",
+                ip, target
+            ),
+            VerifyError::InvalidLabel { name, ip } => write!(
+                f,
+                "Label {} points to address {} which isn't an instruction",
+                name, ip
+            ),
+            VerifyError::UnknownLabelName { ip, name } => write!(
+                f,
+                "Instruction at address {} refers to missing label {}",
+                ip, name
+            ),
+            VerifyError::UnknownLocal { ip, name } => write!(
+                f,
+                "Instruction at address {} refers to local {} which isn't declared",
+                ip, name
+            ),
+            VerifyError::NoOperandName { ip } => write!(
+                f,
+                "Instruction at address {} takes a name, but the code can't read names from operands",
+                ip
+            ),
+            VerifyError::UnknownStackEffect { ip, name } => write!(
+                f,
+                "Instruction {} at address {} has no stack effect",
+                name, ip
+            ),
+            VerifyError::StackUnderflow { ip, depth, pops } => write!(
+                f,
+                "Instruction at address {} pops {} operands but the stack only has {}",
+                ip, pops, depth
+            ),
+            VerifyError::StackMismatch {
+                ip,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Address {} is reached with a stack depth of both {} and {}",
+                ip, expected, actual
+            ),
+        }
+    }
+}
+
+impl error::Error for VerifyError {}
+
+impl<T: fmt::Debug> Code<T> {
+    /// Check that the code can be run with an instruction table.
+    ///
+    /// Checks that every instruction exists in the table and has the right
+    /// number of arguments, that data arguments are in the data section, and
+    /// that label arguments and labels point at instructions.
+    ///
+    /// Label name arguments must name a label.  Local name arguments inside
+    /// a function defined with `Builder::function` must name one of its
+    /// parameters or declared locals; outside of one any name is allowed.
+    pub fn verify(&self, instruction_table: &InstructionTable<T>) -> Result<(), VerifyError> {
+        for (op_code, name) in self.symbols() {
+            match instruction_table.by_op_code(*op_code) {
+                Some(instruction) if instruction.name == *name => (),
+                _ => {
+                    return Err(VerifyError::UnknownSymbol {
+                        op_code: *op_code,
+                        name: name.clone(),
+                    })
+                }
+            }
+        }
+
+        let starts = self.instruction_starts(instruction_table)?;
+        let is_target = |ip: usize| ip == self.code.len() || starts.binary_search(&ip).is_ok();
+
+        for ip in &starts {
+            let op_code = self.code[*ip];
+            let instruction = instruction_table.by_op_code(op_code).unwrap();
+            let arity = self.code[ip + 1];
+            for (position, arg) in self.code[ip + 2..ip + 2 + arity].iter().enumerate() {
+                let kind = instruction.arg_kind(position);
+                if kind.is_data() && *arg >= self.data.len() {
+                    return Err(VerifyError::InvalidData { ip: *ip, index: *arg });
+                }
+                match kind {
+                    ArgKind::LabelName | ArgKind::LocalName if self.operand_name.is_none() => {
+                        return Err(VerifyError::NoOperandName { ip: *ip })
+                    }
+                    ArgKind::LabelName => {
+                        let name = self.name_or_debug(*arg);
+                        if !self.labels.iter().any(|label| label.1 == name) {
+                            return Err(VerifyError::UnknownLabelName { ip: *ip, name });
+                        }
+                    }
+                    ArgKind::LocalName => {
+                        let name = self.name_or_debug(*arg);
+                        if let Some(function) = self.function_for_ip(*ip) {
+                            let mut declared = function.params.iter().chain(function.locals.iter());
+                            if !declared.any(|local| *local == name) {
+                                return Err(VerifyError::UnknownLocal { ip: *ip, name });
+                            }
+                        }
+                    }
+                    ArgKind::Label if !is_target(*arg) => {
+                        return Err(VerifyError::InvalidTarget {
+                            ip: *ip,
+                            target: *arg,
+                        })
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        for (ip, name) in self.labels() {
+            if !is_target(*ip) {
+                return Err(VerifyError::InvalidLabel {
+                    name: name.clone(),
+                    ip: *ip,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The name held by the operand at `index`, or its debug output if it
+    /// doesn't hold one.
+    fn name_or_debug(&self, index: usize) -> String {
+        match self.data_name(index) {
+            Some(name) => name.to_string(),
+            None => format!("{:?}", self.data[index]),
+        }
+    }
+
+    /// The address of every instruction, checking that each one is complete
+    /// and known to the instruction table.
+    fn instruction_starts(
+        &self,
+        instruction_table: &InstructionTable<T>,
+    ) -> Result<Vec<usize>, VerifyError> {
+        let mut starts = vec![];
+        let mut ip = 0;
+        while ip < self.code.len() {
+            if ip + 1 >= self.code.len() {
+                return Err(VerifyError::Truncated { ip });
+            }
+            let op_code = self.code[ip];
+            let arity = self.code[ip + 1];
+            let instruction = instruction_table
+                .by_op_code(op_code)
+                .ok_or(VerifyError::UnknownOpCode { ip, op_code })?;
+            if instruction.is_variadic() && !instruction.accepts_arity(arity) {
+                return Err(VerifyError::ArityOutOfRange {
+                    ip,
+                    min: instruction.arity,
+                    max: instruction.max_arity,
+                    actual: arity,
+                });
+            }
+            if !instruction.accepts_arity(arity) {
+                return Err(VerifyError::WrongArity {
+                    ip,
+                    expected: instruction.arity,
+                    actual: arity,
+                });
+            }
+            if ip + 2 + arity > self.code.len() {
+                return Err(VerifyError::Truncated { ip });
+            }
+            starts.push(ip);
+            ip += 2 + arity;
+        }
+        Ok(starts)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builder::{Argument, Builder};
+    use crate::instruction::Instruction;
+    use crate::machine::Machine;
+
+    fn noop(_machine: &mut Machine<usize>, _args: &[usize]) {}
+
+    fn example_instruction_table() -> InstructionTable<usize> {
+        let mut it = InstructionTable::new();
+        it.insert(Instruction::new(0, "push", 1, noop));
+        it.insert(Instruction::new(1, "jump", 1, noop).with_arg_kinds(vec![ArgKind::Label]));
+        it.insert(Instruction::new(2, "pick", 1, noop).with_arg_kinds(vec![ArgKind::Immediate]));
+        it
+    }
+
+    fn example_code(it: &InstructionTable<usize>) -> Code<usize> {
+        let mut builder: Builder<usize> = Builder::new(it);
+        builder.push("push", vec![123]);
+        builder.emit("pick", vec![Argument::Immediate(99)]);
+        builder.emit("jump", vec![Argument::Label("main".to_string())]);
+        Code::from(builder)
+    }
+
+    #[test]
+    fn verify() {
+        let it = example_instruction_table();
+        assert_eq!(example_code(&it).verify(&it), Ok(()));
+    }
+
+    #[test]
+    fn unknown_symbol() {
+        let it = example_instruction_table();
+        let code = example_code(&it);
+        let mut other = InstructionTable::new();
+        other.insert(Instruction::new(0, "pop", 1, noop));
+        assert_eq!(
+            code.verify(&other),
+            Err(VerifyError::UnknownSymbol {
+                op_code: 0,
+                name: "push".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn malformed_instructions() {
+        let it = example_instruction_table();
+        let mut code = example_code(&it);
+        code.code[3] = 7;
+        assert_eq!(code.verify(&it), Err(VerifyError::UnknownOpCode { ip: 3, op_code: 7 }));
+
+        let mut code = example_code(&it);
+        code.code[4] = 2;
+        assert_eq!(
+            code.verify(&it),
+            Err(VerifyError::WrongArity {
+                ip: 3,
+                expected: 1,
+                actual: 2
+            })
+        );
+
+        let mut it = example_instruction_table();
+        it.insert(Instruction::new(0, "push", 1, noop).with_variadic_arity(Some(2)));
+        let mut code = example_code(&it);
+        code.code[1] = 0;
+        assert_eq!(
+            code.verify(&it),
+            Err(VerifyError::ArityOutOfRange {
+                ip: 0,
+                min: 1,
+                max: Some(2),
+                actual: 0
+            })
+        );
+
+        let mut code = example_code(&it);
+        code.code.pop();
+        assert_eq!(code.verify(&it), Err(VerifyError::Truncated { ip: 6 }));
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        let it = example_instruction_table();
+        let mut code = example_code(&it);
+        code.code[2] = 1;
+        assert_eq!(code.verify(&it), Err(VerifyError::InvalidData { ip: 0, index: 1 }));
+
+        let mut code = example_code(&it);
+        code.code[8] = 1;
+        assert_eq!(code.verify(&it), Err(VerifyError::InvalidTarget { ip: 6, target: 1 }));
+
+        let mut code = example_code(&it);
+        code.labels.push((4, "middle".to_string()));
+        assert_eq!(
+            code.verify(&it),
+            Err(VerifyError::InvalidLabel {
+                name: "middle".to_string(),
+                ip: 4
+            })
+        );
+    }
+
+    fn names_code(it: &InstructionTable<String>) -> Code<String> {
+        let mut builder: Builder<String> = Builder::new(it);
+        builder.set_operand_name(|operand| Some(operand));
+        builder.push("load", vec!["global".to_string()]);
+        builder.push("jump", vec!["main".to_string()]);
+        builder.function("function", &["x"], |builder| {
+            builder.push("load", vec!["x".to_string()]);
+        });
+        Code::from(builder)
+    }
+
+    #[test]
+    fn names() {
+        fn noop(_machine: &mut Machine<String>, _args: &[usize]) {}
+
+        let mut it = InstructionTable::new();
+        it.insert(Instruction::new(0, "jump", 1, noop).with_arg_kinds(vec![ArgKind::LabelName]));
+        it.insert(Instruction::new(1, "load", 1, noop).with_arg_kinds(vec![ArgKind::LocalName]));
+        assert_eq!(names_code(&it).verify(&it), Ok(()));
+        let listing = format!("{:?}", names_code(&it));
+        assert!(listing.contains("\tload global\n\tjump .main\n"));
+        assert!(listing.contains("\tload x\n"));
+
+        let mut broken = names_code(&it);
+        broken.data[1] = "nowhere".to_string();
+        assert_eq!(
+            broken.verify(&it),
+            Err(VerifyError::UnknownLabelName {
+                ip: 3,
+                name: "nowhere".to_string()
+            })
+        );
+
+        let mut broken = names_code(&it);
+        broken.data[2] = "y".to_string();
+        assert_eq!(
+            broken.verify(&it),
+            Err(VerifyError::UnknownLocal {
+                ip: 6,
+                name: "y".to_string()
+            })
+        );
+
+        let mut nameless = names_code(&it);
+        nameless.operand_name = None;
+        assert_eq!(nameless.verify(&it), Err(VerifyError::NoOperandName { ip: 0 }));
+    }
+}
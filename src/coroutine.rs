@@ -0,0 +1,138 @@
+//! Coroutines.
+//!
+//! A coroutine is a separate thread of execution within a `Machine`.  It has
+//! its own instruction pointer, call stack and operand stack but shares the
+//! code, constants and instruction table of the machine which created it.
+//!
+//! Coroutines are started from a label and run until they either yield a
+//! value (using `Machine::yield_value`) or return from their outermost frame.
+//! A yielded coroutine can be resumed later and will carry on from the
+//! instruction following the yield.
+//!
+//! ## Examples
+//!
+//! ```
+//! use stack_vm::{Instruction, InstructionTable, Builder, Code, Machine, WriteManyTable};
+//!
+//! fn push(machine: &mut Machine<i64>, args: &[usize]) {
+//!     let arg = *machine.get_data(args[0]);
+//!     machine.operand_push(arg);
+//! }
+//!
+//! fn emit(machine: &mut Machine<i64>, _args: &[usize]) {
+//!     let value = machine.operand_pop();
+//!     machine.yield_value(value);
+//! }
+//!
+//! let mut instruction_table = InstructionTable::new();
+//! instruction_table.insert(Instruction::new(0, "push", 1, push));
+//! instruction_table.insert(Instruction::new(1, "yield", 0, emit));
+//!
+//! let mut builder: Builder<i64> = Builder::new(&instruction_table);
+//! builder.label("numbers");
+//! builder.push("push", vec![1]);
+//! builder.push("yield", vec![]);
+//! builder.push("push", vec![2]);
+//! builder.push("yield", vec![]);
+//!
+//! let constants: WriteManyTable<i64> = WriteManyTable::new();
+//! let mut machine = Machine::new(Code::from(builder), &constants, &instruction_table);
+//! let values: Vec<i64> = machine.generator("numbers").collect();
+//! assert_eq!(values, vec![1, 2]);
+//! ```
+
+use crate::frame::Frame;
+use crate::machine::Machine;
+use crate::stack::Stack;
+use std::fmt;
+
+/// A handle used to refer to a coroutine owned by a `Machine`.
+///
+/// Handles are plain integers so that they can be stored in your operand type
+/// and passed around by your instructions.
+pub type CoroutineId = usize;
+
+/// The execution state of a coroutine.
+///
+/// Contains:
+/// * An instruction pointer.
+/// * A `Stack` of `Frame` used to keep track of calls being executed.
+/// * A `Stack` of `T` which is used as the coroutine's operand stack.
+#[derive(Debug, Clone)]
+pub struct Coroutine<T> {
+    pub ip: usize,
+    pub call_stack: Stack<Frame<T>>,
+    pub operand_stack: Stack<T>,
+    finished: bool,
+}
+
+impl<T: fmt::Debug> Coroutine<T> {
+    /// Create a new coroutine which will start executing at `ip`.
+    ///
+    /// `return_address` is the address the coroutine's outermost frame
+    /// returns to, which should be the end of the code so that the coroutine
+    /// finishes when it returns.
+    pub fn new(ip: usize, return_address: usize) -> Coroutine<T> {
+        let mut call_stack = Stack::new();
+        call_stack.push(Frame::new(return_address));
+        Coroutine {
+            ip,
+            call_stack,
+            operand_stack: Stack::new(),
+            finished: false,
+        }
+    }
+
+    /// Returns `true` once the coroutine has run to completion.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    pub(crate) fn finish(&mut self) {
+        self.finished = true;
+    }
+}
+
+/// An iterator over the values yielded by a coroutine.
+///
+/// Created by `Machine::generator`.  Each call to `next` resumes the
+/// coroutine until it yields again, and the iterator ends when the coroutine
+/// finishes.
+pub struct Generator<'m, 'a: 'm, T: 'a + fmt::Debug> {
+    machine: &'m mut Machine<'a, T>,
+    coroutine: CoroutineId,
+}
+
+impl<'m, 'a: 'm, T: 'a + fmt::Debug> Generator<'m, 'a, T> {
+    /// Wrap an existing coroutine in an iterator.
+    pub fn new(machine: &'m mut Machine<'a, T>, coroutine: CoroutineId) -> Generator<'m, 'a, T> {
+        Generator { machine, coroutine }
+    }
+
+    /// The handle of the coroutine being iterated.
+    pub fn coroutine(&self) -> CoroutineId {
+        self.coroutine
+    }
+}
+
+impl<'m, 'a: 'm, T: 'a + fmt::Debug> Iterator for Generator<'m, 'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.machine.resume(self.coroutine)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn new() {
+        let coroutine: Coroutine<usize> = Coroutine::new(3, 10);
+        assert_eq!(coroutine.ip, 3);
+        assert_eq!(coroutine.call_stack.peek().return_address, 10);
+        assert!(coroutine.operand_stack.is_empty());
+        assert!(!coroutine.is_finished());
+    }
+}
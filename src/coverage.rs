@@ -0,0 +1,391 @@
+//! Code coverage.
+//!
+//! A `Coverage` is an `Observer` which records which instructions a program
+//! executed, how many times, and where execution went next.  Whenever an
+//! instruction moves the instruction pointer somewhere other than the
+//! following instruction (a jump, call or return) the destination is recorded
+//! as a branch, so you can see which paths of a conditional were taken.
+//!
+//! `Coverage::annotate` produces the same listing as `Code`'s `Debug`
+//! formatter with the hit count of every instruction in the left hand
+//! column and the branches taken at the end of the line:
+//!
+//! ```text
+//! .main:
+//!      1    push @0
+//!      1    jump_if @1    ; next 0, .done 1
+//!      0    push @2
+//!
+//! .done:
+//!      1    print
+//! ```
+//!
+//! Coverage collected from several runs of the same code can be combined
+//! with `Coverage::merge`, and it can be saved and loaded as bytecode so that
+//! runs in different processes can be merged too.
+//!
+//! ## Examples
+//!
+//! ```
+//! use stack_vm::{Instruction, InstructionTable, Builder, Code, Machine, WriteManyTable};
+//!
+//! fn push(machine: &mut Machine<i64>, args: &[usize]) {
+//!     let arg = *machine.get_data(args[0]);
+//!     machine.operand_push(arg);
+//! }
+//!
+//! let mut instruction_table = InstructionTable::new();
+//! instruction_table.insert(Instruction::new(0, "push", 1, push));
+//! let mut builder: Builder<i64> = Builder::new(&instruction_table);
+//! builder.push("push", vec![1]);
+//! builder.push("push", vec![2]);
+//!
+//! let constants: WriteManyTable<i64> = WriteManyTable::new();
+//! let mut machine = Machine::new(Code::from(builder), &constants, &instruction_table);
+//! let coverage = machine.cover();
+//! machine.run();
+//!
+//! let coverage = coverage.lock().unwrap();
+//! assert_eq!(coverage.hits(0), 1);
+//! assert_eq!(coverage.hits(3), 1);
+//! assert_eq!(coverage.covered(&machine.code), (2, 2));
+//! ```
+
+use crate::code::{read_string, Code};
+use crate::from_byte_code::FromByteCode;
+use crate::machine::Machine;
+use crate::observer::Observer;
+use crate::to_byte_code::ToByteCode;
+use rmp::{decode, encode};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{Read, Write};
+
+/// An observer which records the instructions executed by a machine.
+///
+/// Register one with `Machine::cover`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Coverage {
+    fingerprint: Option<u64>,
+    hits: BTreeMap<usize, usize>,
+    branches: BTreeMap<(usize, usize), usize>,
+}
+
+impl Coverage {
+    /// Create a new, empty coverage record.
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    /// The fingerprint of the code this coverage was collected from, if any
+    /// has been collected yet.
+    pub fn fingerprint(&self) -> Option<u64> {
+        self.fingerprint
+    }
+
+    /// The number of times the instruction at `ip` was executed.
+    pub fn hits(&self, ip: usize) -> usize {
+        self.hits.get(&ip).cloned().unwrap_or(0)
+    }
+
+    /// The branches taken from the instruction at `ip`, as a list of
+    /// destination addresses and the number of times each was taken.
+    ///
+    /// Falling through to the next instruction isn't included.
+    pub fn branches(&self, ip: usize) -> Vec<(usize, usize)> {
+        self.branches
+            .range((ip, 0)..=(ip, usize::MAX))
+            .map(|(&(_, to), &count)| (to, count))
+            .collect()
+    }
+
+    /// Count the instructions in `code` which were executed.
+    ///
+    /// Returns the number of covered instructions and the total number of
+    /// instructions.
+    pub fn covered<T: fmt::Debug>(&self, code: &Code<T>) -> (usize, usize) {
+        let addresses = code.instruction_addresses();
+        let covered = addresses.iter().filter(|ip| self.hits(**ip) > 0).count();
+        (covered, addresses.len())
+    }
+
+    /// Add the coverage from another run of the same code to this one.
+    ///
+    /// Panics if the two were collected from different code.
+    pub fn merge(&mut self, other: &Coverage) {
+        match (self.fingerprint, other.fingerprint) {
+            (Some(lhs), Some(rhs)) if lhs != rhs => {
+                panic!("Cannot merge coverage collected from different code")
+            }
+            (None, _) => self.fingerprint = other.fingerprint,
+            _ => (),
+        }
+        for (ip, count) in &other.hits {
+            *self.hits.entry(*ip).or_insert(0) += count;
+        }
+        for (edge, count) in &other.branches {
+            *self.branches.entry(*edge).or_insert(0) += count;
+        }
+    }
+
+    /// Annotate the disassembly of `code` with hit counts and branches.
+    pub fn annotate<T: fmt::Debug>(&self, code: &Code<T>) -> String {
+        let mut result = String::new();
+        let end = code.code.len();
+        for ip in code.instruction_addresses().into_iter().chain(Some(end)) {
+            if let Some(label) = code.label_at(ip) {
+                result.push_str(&format!("\n.{}:\n", label));
+            }
+            if ip == end {
+                break;
+            }
+
+            result.push_str(&format!(
+                "{:>6}\t{}",
+                self.hits(ip),
+                code.describe_instruction(ip)
+            ));
+            let branches = self.branches(ip);
+            if !branches.is_empty() {
+                let taken: usize = branches.iter().map(|branch| branch.1).sum();
+                let next = self.hits(ip).saturating_sub(taken);
+                result.push_str(&format!("\t; next {}", next));
+                for (to, count) in branches {
+                    result.push_str(&format!(", {} {}", describe_address(code, to), count));
+                }
+            }
+            result.push('\n');
+        }
+
+        let (covered, total) = self.covered(code);
+        result.push_str(&format!(
+            "\n; {} of {} instructions covered\n",
+            covered, total
+        ));
+        result
+    }
+}
+
+fn describe_address<T: fmt::Debug>(code: &Code<T>, ip: usize) -> String {
+    match code.label_at(ip) {
+        Some(label) => format!(".{}", label),
+        None => format!("{}", ip),
+    }
+}
+
+impl<T: fmt::Debug> Observer<T> for Coverage {
+    fn after_instruction(
+        &mut self,
+        machine: &mut Machine<T>,
+        ip: usize,
+        _op_code: usize,
+        args: &[usize],
+    ) {
+        if self.fingerprint.is_none() {
+            self.fingerprint = Some(machine.code.fingerprint());
+        }
+        *self.hits.entry(ip).or_insert(0) += 1;
+        if machine.ip != ip + 2 + args.len() {
+            *self.branches.entry((ip, machine.ip)).or_insert(0) += 1;
+        }
+    }
+}
+
+impl ToByteCode for Coverage {
+    /// Create bytecode for this `Coverage`.
+    ///
+    /// Encodes into a Map of the following format:
+    /// ```json
+    /// {
+    ///     "fingerprint" => [ 1234567890 ],
+    ///     "hits" => [ [ 0, 1 ], [ 3, 2 ] ],
+    ///     "branches" => [ [ 3, 0, 1 ] ]
+    /// }
+    /// ```
+    ///
+    /// The fingerprint array is empty if no coverage has been collected.
+    fn to_byte_code(&self, mut buf: &mut dyn Write) {
+        encode::write_map_len(&mut buf, 3).unwrap();
+
+        encode::write_str(&mut buf, "fingerprint").unwrap();
+        encode::write_array_len(&mut buf, self.fingerprint.iter().count() as u32).unwrap();
+        if let Some(fingerprint) = self.fingerprint {
+            encode::write_uint(&mut buf, fingerprint).unwrap();
+        }
+
+        encode::write_str(&mut buf, "hits").unwrap();
+        encode::write_array_len(&mut buf, self.hits.len() as u32).unwrap();
+        for (ip, count) in &self.hits {
+            encode::write_array_len(&mut buf, 2).unwrap();
+            encode::write_uint(&mut buf, *ip as u64).unwrap();
+            encode::write_uint(&mut buf, *count as u64).unwrap();
+        }
+
+        encode::write_str(&mut buf, "branches").unwrap();
+        encode::write_array_len(&mut buf, self.branches.len() as u32).unwrap();
+        for ((from, to), count) in &self.branches {
+            encode::write_array_len(&mut buf, 3).unwrap();
+            encode::write_uint(&mut buf, *from as u64).unwrap();
+            encode::write_uint(&mut buf, *to as u64).unwrap();
+            encode::write_uint(&mut buf, *count as u64).unwrap();
+        }
+    }
+}
+
+impl FromByteCode for Coverage {
+    fn from_byte_code(mut buf: &mut dyn Read) -> Coverage {
+        let map_len = decode::read_map_len(&mut buf).unwrap();
+        assert_eq!(map_len, 3);
+
+        let section = read_string(&mut buf);
+        assert_eq!(section, "fingerprint");
+        let fingerprint = match decode::read_array_len(&mut buf).unwrap() {
+            0 => None,
+            1 => Some(decode::read_int(&mut buf).unwrap()),
+            len => panic!("Unexpected fingerprint length {}", len),
+        };
+
+        let section = read_string(&mut buf);
+        assert_eq!(section, "hits");
+        let hits_len = decode::read_array_len(&mut buf).unwrap();
+        let mut hits = BTreeMap::new();
+        for _i in 0..hits_len {
+            let len = decode::read_array_len(&mut buf).unwrap();
+            assert_eq!(len, 2);
+            let ip = decode::read_int(&mut buf).unwrap();
+            let count = decode::read_int(&mut buf).unwrap();
+            hits.insert(ip, count);
+        }
+
+        let section = read_string(&mut buf);
+        assert_eq!(section, "branches");
+        let branches_len = decode::read_array_len(&mut buf).unwrap();
+        let mut branches = BTreeMap::new();
+        for _i in 0..branches_len {
+            let len = decode::read_array_len(&mut buf).unwrap();
+            assert_eq!(len, 3);
+            let from = decode::read_int(&mut buf).unwrap();
+            let to = decode::read_int(&mut buf).unwrap();
+            let count = decode::read_int(&mut buf).unwrap();
+            branches.insert((from, to), count);
+        }
+
+        Coverage {
+            fingerprint,
+            hits,
+            branches,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builder::Builder;
+    use crate::instruction::Instruction;
+    use crate::instruction_table::InstructionTable;
+    use crate::write_many_table::WriteManyTable;
+
+    fn push(machine: &mut Machine<usize>, args: &[usize]) {
+        let arg = *machine.get_data(args[0]);
+        machine.operand_push(arg);
+    }
+
+    fn jump_if_zero(machine: &mut Machine<usize>, _args: &[usize]) {
+        if machine.operand_pop() == 0 {
+            machine.jump("done");
+        }
+    }
+
+    fn instruction_table() -> InstructionTable<usize> {
+        let mut it = InstructionTable::new();
+        it.insert(Instruction::new(0, "push", 1, push));
+        it.insert(Instruction::new(1, "jump_if_zero", 0, jump_if_zero));
+        it
+    }
+
+    fn code(it: &InstructionTable<usize>) -> Code<usize> {
+        let mut builder: Builder<usize> = Builder::new(it);
+        builder.push("jump_if_zero", vec![]);
+        builder.push("push", vec![2]);
+        builder.label("done");
+        builder.push("push", vec![3]);
+        Code::from(builder)
+    }
+
+    fn run(it: &InstructionTable<usize>, value: usize) -> Coverage {
+        let constants: WriteManyTable<usize> = WriteManyTable::new();
+        let mut machine = Machine::new(code(it), &constants, it);
+        machine.operand_push(value);
+        let coverage = machine.cover();
+        machine.run();
+        let coverage = coverage.lock().unwrap();
+        coverage.clone()
+    }
+
+    #[test]
+    fn hits_and_branches() {
+        let it = instruction_table();
+        let coverage = run(&it, 0);
+        assert_eq!(coverage.hits(0), 1);
+        assert_eq!(coverage.hits(2), 0);
+        assert_eq!(coverage.hits(5), 1);
+        assert_eq!(coverage.branches(0), [(5, 1)]);
+        assert!(coverage.branches(2).is_empty());
+        assert_eq!(coverage.covered(&code(&it)), (2, 3));
+    }
+
+    #[test]
+    fn merge() {
+        let it = instruction_table();
+        let mut coverage = run(&it, 0);
+        coverage.merge(&run(&it, 1));
+        assert_eq!(coverage.hits(0), 2);
+        assert_eq!(coverage.hits(2), 1);
+        assert_eq!(coverage.covered(&code(&it)), (3, 3));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot merge coverage collected from different code")]
+    fn merge_different_code() {
+        let it = instruction_table();
+        let mut other = Coverage::new();
+        other.fingerprint = Some(1);
+        run(&it, 0).merge(&other);
+    }
+
+    #[test]
+    fn annotate() {
+        let it = instruction_table();
+        let mut coverage = run(&it, 0);
+        coverage.merge(&run(&it, 1));
+        let expected = "
+.main:
+     2\tjump_if_zero\t; next 1, .done 1
+     1\tpush @0
+
+.done:
+     2\tpush @1
+
+; 3 of 3 instructions covered
+";
+        assert_eq!(coverage.annotate(&code(&it)), expected);
+    }
+
+    #[test]
+    fn round_trip() {
+        let it = instruction_table();
+        let coverage = run(&it, 0);
+        let mut bytecode: Vec<u8> = vec![];
+        coverage.to_byte_code(&mut bytecode);
+        let loaded = Coverage::from_byte_code(&mut &bytecode[..]);
+        assert_eq!(loaded, coverage);
+
+        let mut bytecode: Vec<u8> = vec![];
+        Coverage::new().to_byte_code(&mut bytecode);
+        assert_eq!(
+            Coverage::from_byte_code(&mut &bytecode[..]),
+            Coverage::new()
+        );
+    }
+}
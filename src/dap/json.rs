@@ -0,0 +1,363 @@
+//! Just enough JSON to speak the Debug Adapter Protocol.
+
+use std::fmt;
+
+/// A JSON value.
+///
+/// Object members keep their order, which keeps our output predictable.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Build an object from a list of members.
+    pub fn object(members: Vec<(&str, Json)>) -> Json {
+        Json::Object(
+            members
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    /// Parse a JSON document.
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser {
+            chars: text.chars().collect(),
+            position: 0,
+        };
+        let value = parser.value()?;
+        parser.whitespace();
+        if parser.position != parser.chars.len() {
+            return Err(format!("Unexpected trailing input at {}", parser.position));
+        }
+        Ok(value)
+    }
+
+    /// Look up a member of an object.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members
+                .iter()
+                .find(|member| member.0 == key)
+                .map(|member| &member.1),
+            _ => None,
+        }
+    }
+
+    /// The value of a string.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The value of a non-negative whole number.
+    pub fn as_usize(&self) -> Option<usize> {
+        match self {
+            Json::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as usize),
+            _ => None,
+        }
+    }
+
+    /// The value of a boolean.
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    /// The values of an array.
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Json {
+    fn from(s: &'a str) -> Json {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Json {
+        Json::String(s)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Json {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Json {
+        Json::Bool(b)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(values: Vec<Json>) -> Json {
+        Json::Array(values)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => write_string(f, s),
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).cloned()
+    }
+
+    fn next(&mut self) -> Result<char, String> {
+        let c = self
+            .peek()
+            .ok_or_else(|| "Unexpected end of input".to_string())?;
+        self.position += 1;
+        Ok(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.next()? {
+            c if c == expected => Ok(()),
+            c => Err(format!(
+                "Expected {:?} but found {:?} at {}",
+                expected,
+                c,
+                self.position - 1
+            )),
+        }
+    }
+
+    fn whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.position += 1;
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str, value: Json) -> Result<Json, String> {
+        for expected in keyword.chars() {
+            self.expect(expected)?;
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.whitespace();
+        match self.peek() {
+            Some('n') => self.keyword("null", Json::Null),
+            Some('t') => self.keyword("true", Json::Bool(true)),
+            Some('f') => self.keyword("false", Json::Bool(false)),
+            Some('"') => Ok(Json::String(self.string()?)),
+            Some('[') => self.array(),
+            Some('{') => self.object(),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            Some(c) => Err(format!("Unexpected {:?} at {}", c, self.position)),
+            None => Err("Unexpected end of input".to_string()),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.position;
+        while let Some(c) = self.peek() {
+            if !(c.is_ascii_digit() || c == '-' || c == '+' || c == '.' || c == 'e' || c == 'E') {
+                break;
+            }
+            self.position += 1;
+        }
+        let text: String = self.chars[start..self.position].iter().collect();
+        text.parse()
+            .map(Json::Number)
+            .map_err(|_| format!("Invalid number {:?} at {}", text, start))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut result = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Ok(result),
+                '\\' => match self.next()? {
+                    'n' => result.push('\n'),
+                    'r' => result.push('\r'),
+                    't' => result.push('\t'),
+                    'b' => result.push('\u{8}'),
+                    'f' => result.push('\u{c}'),
+                    'u' => {
+                        let mut code = self.hex()?;
+                        if (0xd800..0xdc00).contains(&code) {
+                            self.expect('\\')?;
+                            self.expect('u')?;
+                            let low = self.hex()?;
+                            code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                        }
+                        result.push(std::char::from_u32(code).unwrap_or('\u{fffd}'));
+                    }
+                    c => result.push(c),
+                },
+                c => result.push(c),
+            }
+        }
+    }
+
+    fn hex(&mut self) -> Result<u32, String> {
+        let mut code = 0;
+        for _i in 0..4 {
+            let c = self.next()?;
+            let digit = c
+                .to_digit(16)
+                .ok_or_else(|| format!("Invalid escape {:?} at {}", c, self.position - 1))?;
+            code = code * 16 + digit;
+        }
+        Ok(code)
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.expect('[')?;
+        let mut values = vec![];
+        self.whitespace();
+        if self.peek() == Some(']') {
+            self.position += 1;
+            return Ok(Json::Array(values));
+        }
+        loop {
+            values.push(self.value()?);
+            self.whitespace();
+            match self.next()? {
+                ',' => (),
+                ']' => return Ok(Json::Array(values)),
+                c => return Err(format!("Unexpected {:?} at {}", c, self.position - 1)),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.expect('{')?;
+        let mut members = vec![];
+        self.whitespace();
+        if self.peek() == Some('}') {
+            self.position += 1;
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.whitespace();
+            let key = self.string()?;
+            self.whitespace();
+            self.expect(':')?;
+            members.push((key, self.value()?));
+            self.whitespace();
+            match self.next()? {
+                ',' => (),
+                '}' => return Ok(Json::Object(members)),
+                c => return Err(format!("Unexpected {:?} at {}", c, self.position - 1)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let json = Json::parse(r#" {"seq": 1, "args": [true, null, -2.5, "a\"bé"], "empty": {}} "#)
+            .unwrap();
+        assert_eq!(json.get("seq").and_then(Json::as_usize), Some(1));
+        let args = json.get("args").and_then(Json::as_array).unwrap();
+        assert_eq!(args[0], Json::Bool(true));
+        assert_eq!(args[1], Json::Null);
+        assert_eq!(args[2], Json::Number(-2.5));
+        assert_eq!(args[3].as_str(), Some("a\"b\u{e9}"));
+        assert_eq!(json.get("empty"), Some(&Json::Object(vec![])));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Json::parse("{").is_err());
+        assert!(Json::parse("[1 2]").is_err());
+        assert!(Json::parse("1 1").is_err());
+    }
+
+    #[test]
+    fn display() {
+        let json = Json::object(vec![
+            ("name", Json::from("a\n\"b\"")),
+            (
+                "values",
+                Json::from(vec![Json::from(1), Json::from(false), Json::Null]),
+            ),
+        ]);
+        assert_eq!(
+            json.to_string(),
+            r#"{"name":"a\n\"b\"","values":[1,false,null]}"#
+        );
+        assert_eq!(Json::parse(&json.to_string()).unwrap(), json);
+    }
+}
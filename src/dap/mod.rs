@@ -0,0 +1,997 @@
+//! A Debug Adapter Protocol server.
+//!
+//! The [Debug Adapter Protocol](https://microsoft.github.io/debug-adapter-protocol/)
+//! lets editors drive a debugger.  `DapServer` speaks it over a pair of
+//! streams, normally standard input and output, so no networking is needed.
+//! The `stack-vm-debug` binary runs one when given the `--dap` flag.
+//!
+//! Like `Repl`, the server needs to know about your operands and
+//! instructions, so you create it with your instruction table and constants
+//! and it loads the bytecode named by the `program` argument of the `launch`
+//! request.  Programs which can't be decoded, or which fail `Code::verify`
+//! against the instruction table, are reported in the `launch` response.
+//!
+//! The server supports:
+//!
+//! * `launch`, with an optional `stopOnEntry`,
+//! * breakpoints on lines (`setBreakpoints`) and labels
+//!   (`setFunctionBreakpoints`),
+//! * `continue`, `next`, `stepIn` and `stepOut`,
+//! * `stackTrace`, built from the call stack,
+//! * `scopes` and `variables`, showing the locals of each frame and the
+//!   operand stack, and
+//! * `source`, which returns the code's disassembly.
+//!
+//! If the code has a source map then breakpoints set on a source file's
+//! path and stack frames refer to lines of the user's source.  Otherwise
+//! lines refer to the disassembly produced by `Code`'s `Debug` formatter,
+//! which is served as a source with a `sourceReference` of `1`.  Either way,
+//! breakpoints on a line which doesn't hold an instruction move to the next
+//! one which does.
+
+mod json;
+
+use self::json::Json;
+use crate::code::{Code, OperandName};
+use crate::debugger::{self, Debugger, Stop};
+use crate::from_byte_code::FromByteCode;
+use crate::instruction_table::InstructionTable;
+use crate::machine::Machine;
+use crate::table::Table;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::panic;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// The only thread we report.
+const THREAD_ID: usize = 1;
+
+/// The `sourceReference` of the disassembly.
+const SOURCE_REFERENCE: usize = 1;
+
+/// The `variablesReference` of the operand stack.  Locals use the frame's
+/// index plus this.
+const OPERAND_STACK_REFERENCE: usize = 1;
+
+/// A Debug Adapter Protocol server for machines with operands of type `T`.
+pub struct DapServer<'a, T: 'a + fmt::Debug> {
+    instruction_table: &'a InstructionTable<T>,
+    constants: &'a (dyn Table<Item = T> + Sync),
+    machine: Option<Machine<'a, T>>,
+    debugger: Arc<Mutex<Debugger<T>>>,
+    stop_on_entry: bool,
+    /// The address of the instruction on each line of the disassembly.
+    lines: Vec<Option<usize>>,
+    line_breakpoints: Vec<usize>,
+    label_breakpoints: Vec<String>,
+    operand_name: Option<OperandName<T>>,
+    seq: usize,
+}
+
+impl<'a, T> DapServer<'a, T>
+where
+    T: 'a + fmt::Debug + Clone + PartialEq + Send + FromByteCode + 'static,
+{
+    /// Create a server which will run programs with the given instruction
+    /// table and constants.
+    pub fn new(
+        instruction_table: &'a InstructionTable<T>,
+        constants: &'a (dyn Table<Item = T> + Sync),
+    ) -> DapServer<'a, T> {
+        DapServer {
+            instruction_table,
+            constants,
+            machine: None,
+            debugger: Arc::new(Mutex::new(Debugger::new())),
+            stop_on_entry: false,
+            lines: vec![],
+            line_breakpoints: vec![],
+            label_breakpoints: vec![],
+            operand_name: None,
+            seq: 0,
+        }
+    }
+
+    /// Set the function which reads label and local names from operands.
+    ///
+    /// Programs are checked with `Code::verify` when they're launched, which
+    /// needs it if any instruction takes names.
+    pub fn set_operand_name(&mut self, operand_name: OperandName<T>) {
+        self.operand_name = Some(operand_name);
+    }
+
+    /// Serve requests until the client disconnects or closes `input`.
+    pub fn run<R: BufRead, W: Write>(&mut self, mut input: R, mut output: W) -> io::Result<()> {
+        while let Some(body) = read_message(&mut input)? {
+            let request = Json::parse(&body)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+            if !self.handle(&request, &mut output)? {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle one request, returning `false` once the client disconnects.
+    fn handle(&mut self, request: &Json, output: &mut dyn Write) -> io::Result<bool> {
+        let command = request.get("command").and_then(Json::as_str).unwrap_or("");
+        let null = Json::Null;
+        let arguments = request.get("arguments").unwrap_or(&null);
+
+        let result = match command {
+            "initialize" => Ok(Json::object(vec![(
+                "supportsConfigurationDoneRequest",
+                Json::from(true),
+            )])),
+            "launch" => self.launch(arguments),
+            "setBreakpoints" => self.set_breakpoints(arguments),
+            "setFunctionBreakpoints" => self.set_function_breakpoints(arguments),
+            "configurationDone" | "continue" | "next" | "stepIn" | "stepOut" => {
+                if self.machine.is_some() {
+                    Ok(Json::object(vec![]))
+                } else {
+                    Err("No program has been launched".to_string())
+                }
+            }
+            "threads" => Ok(Json::object(vec![(
+                "threads",
+                Json::from(vec![Json::object(vec![
+                    ("id", Json::from(THREAD_ID)),
+                    ("name", Json::from("main")),
+                ])]),
+            )])),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => self.scopes(arguments),
+            "variables" => self.variables(arguments),
+            "source" => self.source(),
+            "pause" => Err("not supported".to_string()),
+            "disconnect" => Ok(Json::object(vec![])),
+            _ => Err(format!("Unsupported command {}", command)),
+        };
+        self.respond(request, command, result, output)?;
+
+        match command {
+            "launch" if self.machine.is_some() => self.event("initialized", None, output)?,
+            "configurationDone" if self.machine.is_some() => {
+                if self.stop_on_entry {
+                    self.stopped("entry", output)?;
+                } else {
+                    self.execute(debugger::resume, output)?;
+                }
+            }
+            "continue" if self.machine.is_some() => self.execute(debugger::resume, output)?,
+            "next" if self.machine.is_some() => self.execute(debugger::step_over, output)?,
+            "stepIn" if self.machine.is_some() => self.execute(debugger::step_into, output)?,
+            "stepOut" if self.machine.is_some() => self.execute(debugger::step_out, output)?,
+            "disconnect" => return Ok(false),
+            _ => (),
+        }
+        Ok(true)
+    }
+
+    fn launch(&mut self, arguments: &Json) -> Result<Json, String> {
+        let program = arguments
+            .get("program")
+            .and_then(Json::as_str)
+            .ok_or_else(|| "Missing program".to_string())?;
+        let bytecode =
+            fs::read(program).map_err(|error| format!("Unable to open {}: {}", program, error))?;
+        let mut code: Code<T> = panic::catch_unwind(|| Code::from_byte_code(&mut &bytecode[..]))
+            .map_err(|_| format!("Unable to load {}: it isn't valid bytecode", program))?;
+        if let Some(operand_name) = self.operand_name {
+            code.set_operand_name(operand_name);
+        }
+        code.verify(self.instruction_table)
+            .map_err(|error| format!("Unable to load {}: {}", program, error))?;
+        self.stop_on_entry = arguments
+            .get("stopOnEntry")
+            .and_then(Json::as_bool)
+            .unwrap_or(false);
+
+        self.lines = disassembly_lines(&code);
+        let mut machine = Machine::new(code, self.constants, self.instruction_table);
+        machine.observe(self.debugger.clone());
+        self.machine = Some(machine);
+        Ok(Json::object(vec![]))
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Json) -> Result<Json, String> {
+        let mut debugger = self.debugger.lock().unwrap();
+        for ip in self.line_breakpoints.drain(..) {
+            debugger.remove_breakpoint(ip);
+        }
+
+        let path = arguments
+            .get("source")
+            .and_then(|source| source.get("path"))
+            .and_then(Json::as_str)
+            .filter(|_| self.has_source_map());
+        let mut breakpoints = vec![];
+        let requested = arguments
+            .get("breakpoints")
+            .and_then(Json::as_array)
+            .unwrap_or(&[]);
+        for breakpoint in requested {
+            let line = breakpoint.get("line").and_then(Json::as_usize).unwrap_or(0);
+            let found = match path {
+                Some(path) => self.source_line(path, line),
+                None => self.disassembly_line(line),
+            };
+            match found {
+                Some((line, ip)) => {
+                    debugger.break_at(ip);
+                    self.line_breakpoints.push(ip);
+                    breakpoints.push(Json::object(vec![
+                        ("verified", Json::from(true)),
+                        ("line", Json::from(line)),
+                    ]));
+                }
+                None => breakpoints.push(Json::object(vec![("verified", Json::from(false))])),
+            }
+        }
+        Ok(Json::object(vec![("breakpoints", Json::from(breakpoints))]))
+    }
+
+    fn set_function_breakpoints(&mut self, arguments: &Json) -> Result<Json, String> {
+        let mut debugger = self.debugger.lock().unwrap();
+        for name in self.label_breakpoints.drain(..) {
+            debugger.remove_label_breakpoint(&name);
+        }
+
+        let mut breakpoints = vec![];
+        let requested = arguments
+            .get("breakpoints")
+            .and_then(Json::as_array)
+            .unwrap_or(&[]);
+        for breakpoint in requested {
+            let name = breakpoint.get("name").and_then(Json::as_str).unwrap_or("");
+            let ip = self
+                .machine
+                .as_ref()
+                .and_then(|machine| machine.code.get_label_ip(name));
+            debugger.break_at_label(name);
+            self.label_breakpoints.push(name.to_string());
+            let mut result = vec![("verified", Json::from(ip.is_some()))];
+            if let Some(ip) = ip {
+                result.push(("line", Json::from(self.line_for_ip(ip))));
+            }
+            breakpoints.push(Json::object(result));
+        }
+        Ok(Json::object(vec![("breakpoints", Json::from(breakpoints))]))
+    }
+
+    fn stack_trace(&self) -> Result<Json, String> {
+        let machine = self.machine()?;
+        let mut frames = vec![];
+        let mut ip = machine.ip;
+        for (index, frame) in machine.backtrace().iter().enumerate() {
+            let (source, line, column) = match frame.location.source {
+                Some(ref location) => (file_source(&location.file), location.line, location.column),
+                None => (source(), self.line_for_ip(ip), 1),
+            };
+            frames.push(Json::object(vec![
+                ("id", Json::from(index)),
+                (
+                    "name",
+                    Json::from(
+                        frame
+                            .location
+                            .label
+                            .clone()
+                            .unwrap_or_else(|| "?".to_string()),
+                    ),
+                ),
+                ("source", source),
+                ("line", Json::from(line)),
+                ("column", Json::from(column)),
+            ]));
+            ip = frame.return_address.saturating_sub(1);
+        }
+        Ok(Json::object(vec![
+            ("totalFrames", Json::from(frames.len())),
+            ("stackFrames", Json::from(frames)),
+        ]))
+    }
+
+    fn scopes(&self, arguments: &Json) -> Result<Json, String> {
+        let frame = arguments
+            .get("frameId")
+            .and_then(Json::as_usize)
+            .unwrap_or(0);
+        Ok(Json::object(vec![(
+            "scopes",
+            Json::from(vec![
+                Json::object(vec![
+                    ("name", Json::from("Locals")),
+                    (
+                        "variablesReference",
+                        Json::from(OPERAND_STACK_REFERENCE + 1 + frame),
+                    ),
+                    ("expensive", Json::from(false)),
+                ]),
+                Json::object(vec![
+                    ("name", Json::from("Operand Stack")),
+                    ("variablesReference", Json::from(OPERAND_STACK_REFERENCE)),
+                    ("expensive", Json::from(false)),
+                ]),
+            ]),
+        )]))
+    }
+
+    fn variables(&self, arguments: &Json) -> Result<Json, String> {
+        let machine = self.machine()?;
+        let reference = arguments
+            .get("variablesReference")
+            .and_then(Json::as_usize)
+            .unwrap_or(0);
+
+        let variables: Vec<(String, String)> = if reference == OPERAND_STACK_REFERENCE {
+            // Show the top of the stack first.
+            machine
+                .operand_stack
+                .as_slice()
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(index, value)| (format!("{}", index), format!("{:?}", value)))
+                .collect()
+        } else {
+            let backtrace = machine.backtrace();
+            let frame = reference
+                .checked_sub(OPERAND_STACK_REFERENCE + 1)
+                .and_then(|frame| backtrace.get(frame))
+                .ok_or_else(|| format!("Unknown variables reference {}", reference))?;
+            frame
+                .locals
+                .iter()
+                .map(|(name, value)| (name.clone(), format!("{:?}", value)))
+                .collect()
+        };
+
+        let variables = variables
+            .into_iter()
+            .map(|(name, value)| {
+                Json::object(vec![
+                    ("name", Json::from(name)),
+                    ("value", Json::from(value)),
+                    ("variablesReference", Json::from(0)),
+                ])
+            })
+            .collect();
+        Ok(Json::object(vec![("variables", Json::Array(variables))]))
+    }
+
+    fn source(&self) -> Result<Json, String> {
+        let machine = self.machine()?;
+        Ok(Json::object(vec![(
+            "content",
+            Json::from(format!("{:?}", machine.code)),
+        )]))
+    }
+
+    fn machine(&self) -> Result<&Machine<'a, T>, String> {
+        self.machine
+            .as_ref()
+            .ok_or_else(|| "No program has been launched".to_string())
+    }
+
+    fn has_source_map(&self) -> bool {
+        self.machine
+            .as_ref()
+            .map(|machine| !machine.code.source_map().is_empty())
+            .unwrap_or(false)
+    }
+
+    /// The first line at or after `line` in the source file at `path` which
+    /// produced an instruction, and the address of that instruction.
+    fn source_line(&self, path: &str, line: usize) -> Option<(usize, usize)> {
+        let machine = self.machine.as_ref()?;
+        machine
+            .code
+            .source_map()
+            .ranges()
+            .iter()
+            .filter(|(_, _, location)| {
+                location.line >= line && Path::new(path).ends_with(&location.file)
+            })
+            .map(|(start, _, location)| (location.line, *start))
+            .min()
+    }
+
+    /// The first line at or after `line` in the disassembly which holds an
+    /// instruction, and the address of that instruction.
+    fn disassembly_line(&self, line: usize) -> Option<(usize, usize)> {
+        self.lines
+            .iter()
+            .enumerate()
+            .skip(line.saturating_sub(1))
+            .find_map(|(index, ip)| ip.map(|ip| (index + 1, ip)))
+    }
+
+    /// The line of the disassembly holding the instruction which contains
+    /// `ip`.  Addresses past the end of the code map to the last line.
+    fn line_for_ip(&self, ip: usize) -> usize {
+        self.lines
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, line_ip)| line_ip.map(|line_ip| line_ip <= ip).unwrap_or(false))
+            .map(|(index, _)| index + 1)
+            .unwrap_or(1)
+    }
+
+    /// Run one of the debugger's execution commands, then tell the client
+    /// where the machine stopped.
+    fn execute<F>(&mut self, command: F, output: &mut dyn Write) -> io::Result<()>
+    where
+        F: FnOnce(&mut Machine<'a, T>, &Mutex<Debugger<T>>),
+    {
+        let finished = {
+            let machine = self.machine.as_mut().unwrap();
+            command(machine, &self.debugger);
+            machine.is_finished()
+        };
+        if finished {
+            self.event(
+                "exited",
+                Some(Json::object(vec![("exitCode", Json::from(0))])),
+                output,
+            )?;
+            return self.event("terminated", None, output);
+        }
+        let reason = match self.debugger.lock().unwrap().stop() {
+            Some(Stop::Breakpoint(_)) => "breakpoint",
+            Some(_) => "data breakpoint",
+            None => "step",
+        };
+        self.stopped(reason, output)
+    }
+
+    fn stopped(&mut self, reason: &str, output: &mut dyn Write) -> io::Result<()> {
+        let body = Json::object(vec![
+            ("reason", Json::from(reason)),
+            ("threadId", Json::from(THREAD_ID)),
+            ("allThreadsStopped", Json::from(true)),
+        ]);
+        self.event("stopped", Some(body), output)
+    }
+
+    fn respond(
+        &mut self,
+        request: &Json,
+        command: &str,
+        result: Result<Json, String>,
+        output: &mut dyn Write,
+    ) -> io::Result<()> {
+        let request_seq = request.get("seq").and_then(Json::as_usize).unwrap_or(0);
+        let mut members = vec![
+            ("seq", Json::from(self.next_seq())),
+            ("type", Json::from("response")),
+            ("request_seq", Json::from(request_seq)),
+            ("command", Json::from(command)),
+        ];
+        match result {
+            Ok(body) => {
+                members.push(("success", Json::from(true)));
+                members.push(("body", body));
+            }
+            Err(message) => {
+                members.push(("success", Json::from(false)));
+                members.push(("message", Json::from(message)));
+            }
+        }
+        write_message(output, &Json::object(members))
+    }
+
+    fn event(&mut self, event: &str, body: Option<Json>, output: &mut dyn Write) -> io::Result<()> {
+        let mut members = vec![
+            ("seq", Json::from(self.next_seq())),
+            ("type", Json::from("event")),
+            ("event", Json::from(event)),
+        ];
+        if let Some(body) = body {
+            members.push(("body", body));
+        }
+        write_message(output, &Json::object(members))
+    }
+
+    fn next_seq(&mut self) -> usize {
+        self.seq += 1;
+        self.seq
+    }
+}
+
+/// The address of the instruction on each line of `code`'s disassembly.
+fn disassembly_lines<T: fmt::Debug>(code: &Code<T>) -> Vec<Option<usize>> {
+    let mut addresses = code.instruction_addresses().into_iter();
+    format!("{:?}", code)
+        .lines()
+        .map(|line| {
+            if line.starts_with('\t') {
+                addresses.next()
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+fn source() -> Json {
+    Json::object(vec![
+        ("name", Json::from("disassembly")),
+        ("sourceReference", Json::from(SOURCE_REFERENCE)),
+    ])
+}
+
+/// A source file which the client can open itself.
+fn file_source(path: &str) -> Json {
+    let name = Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path);
+    Json::object(vec![("name", Json::from(name)), ("path", Json::from(path))])
+}
+
+/// Read one message, returning `None` at the end of the input.
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "Missing Content-Length header")
+    })?;
+    let mut body = vec![0u8; length];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+fn write_message(output: &mut dyn Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builder::Builder;
+    use crate::fixtures::instruction_table;
+    use crate::to_byte_code::ToByteCode;
+    use crate::write_many_table::WriteManyTable;
+    use std::env;
+    use std::fs;
+
+    /// Write a program to a temporary file and return its path.
+    ///
+    /// With a source file, the program's instructions come from lines 1 to
+    /// 3 and 5 to 7 of it.  The disassembly is:
+    ///
+    /// ```text
+    /// 1  @0 = 1
+    /// 2  @1 = 2
+    /// 3
+    /// 4  .main:
+    /// 5      push @0
+    /// 6      call
+    /// 7      ret
+    /// 8
+    /// 9  .function:
+    /// 10     push @1
+    /// 11     store
+    /// 12     ret
+    /// ```
+    fn program(it: &InstructionTable<usize>, name: &str, file: Option<&str>) -> String {
+        let mut builder: Builder<usize> = Builder::new(it);
+        let locate = |builder: &mut Builder<usize>, line: usize| {
+            if let Some(file) = file {
+                builder.set_location(file, line, 1);
+            }
+        };
+        locate(&mut builder, 1);
+        builder.push("push", vec![1]);
+        locate(&mut builder, 2);
+        builder.push("call", vec![]);
+        locate(&mut builder, 3);
+        builder.push("ret", vec![]);
+        builder.label("function");
+        locate(&mut builder, 5);
+        builder.push("push", vec![2]);
+        locate(&mut builder, 6);
+        builder.push("store", vec![]);
+        locate(&mut builder, 7);
+        builder.push("ret", vec![]);
+        let mut bytecode: Vec<u8> = vec![];
+        Code::from(builder).to_byte_code(&mut bytecode);
+
+        let path =
+            env::temp_dir().join(format!("stack-vm-dap-{}-{}.svm", name, std::process::id()));
+        fs::write(&path, bytecode).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn session(name: &str, requests: Vec<Json>) -> Vec<Json> {
+        source_session(name, None, requests)
+    }
+
+    fn source_session(name: &str, file: Option<&str>, requests: Vec<Json>) -> Vec<Json> {
+        let it = instruction_table();
+        let path = program(&it, name, file);
+        let mut input: Vec<u8> = vec![];
+        for (seq, request) in requests.into_iter().enumerate() {
+            let mut members = vec![
+                ("seq".to_string(), Json::from(seq + 1)),
+                ("type".to_string(), Json::from("request")),
+            ];
+            if let Json::Object(rest) = request {
+                members.extend(rest);
+            }
+            let message = Json::Object(members).to_string().replace("$PROGRAM", &path);
+            write!(
+                input,
+                "Content-Length: {}\r\n\r\n{}",
+                message.len(),
+                message
+            )
+            .unwrap();
+        }
+
+        let constants: WriteManyTable<usize> = WriteManyTable::new();
+        let mut output: Vec<u8> = vec![];
+        DapServer::new(&it, &constants)
+            .run(&input[..], &mut output)
+            .unwrap();
+        fs::remove_file(path).unwrap();
+
+        let mut output = &output[..];
+        let mut messages = vec![];
+        while let Some(body) = read_message(&mut output).unwrap() {
+            messages.push(Json::parse(&body).unwrap());
+        }
+        messages
+    }
+
+    fn request(command: &str, arguments: Json) -> Json {
+        Json::object(vec![
+            ("command", Json::from(command)),
+            ("arguments", arguments),
+        ])
+    }
+
+    fn launch(stop_on_entry: bool) -> Json {
+        request(
+            "launch",
+            Json::object(vec![
+                ("program", Json::from("$PROGRAM")),
+                ("stopOnEntry", Json::from(stop_on_entry)),
+            ]),
+        )
+    }
+
+    /// Summarise each message as its type and command or event.
+    fn summary(messages: &[Json]) -> Vec<String> {
+        messages
+            .iter()
+            .map(|message| {
+                let kind = message.get("type").and_then(Json::as_str).unwrap();
+                let name = message
+                    .get("command")
+                    .or_else(|| message.get("event"))
+                    .and_then(Json::as_str)
+                    .unwrap();
+                format!("{} {}", kind, name)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn run_to_completion() {
+        let messages = session(
+            "run",
+            vec![
+                request("initialize", Json::object(vec![])),
+                launch(false),
+                request("configurationDone", Json::object(vec![])),
+                request("disconnect", Json::object(vec![])),
+            ],
+        );
+        assert_eq!(
+            summary(&messages),
+            [
+                "response initialize",
+                "response launch",
+                "event initialized",
+                "response configurationDone",
+                "event exited",
+                "event terminated",
+                "response disconnect",
+            ]
+        );
+    }
+
+    #[test]
+    fn breakpoints_and_inspection() {
+        let messages = session(
+            "breakpoints",
+            vec![
+                launch(false),
+                request(
+                    "setBreakpoints",
+                    Json::object(vec![(
+                        "breakpoints",
+                        Json::from(vec![Json::object(vec![("line", Json::from(8))])]),
+                    )]),
+                ),
+                request("configurationDone", Json::object(vec![])),
+                request("stepIn", Json::object(vec![])),
+                request("stackTrace", Json::object(vec![])),
+                request(
+                    "variables",
+                    Json::object(vec![("variablesReference", Json::from(1))]),
+                ),
+                request(
+                    "variables",
+                    Json::object(vec![("variablesReference", Json::from(2))]),
+                ),
+                request("continue", Json::object(vec![])),
+            ],
+        );
+
+        // The breakpoint on the blank line moves to the first instruction of
+        // the function.
+        let breakpoints = messages[2].get("body").unwrap().get("breakpoints").unwrap();
+        assert_eq!(breakpoints.to_string(), r#"[{"verified":true,"line":10}]"#);
+        assert_eq!(
+            messages[4].to_string(),
+            r#"{"seq":5,"type":"event","event":"stopped","body":{"reason":"breakpoint","threadId":1,"allThreadsStopped":true}}"#
+        );
+        assert_eq!(
+            summary(&messages[5..7]),
+            ["response stepIn", "event stopped"]
+        );
+
+        let frames = messages[7].get("body").unwrap().get("stackFrames").unwrap();
+        let frames: Vec<(String, usize)> = frames
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|frame| {
+                (
+                    frame
+                        .get("name")
+                        .and_then(Json::as_str)
+                        .unwrap()
+                        .to_string(),
+                    frame.get("line").and_then(Json::as_usize).unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            frames,
+            [("function".to_string(), 11), ("main".to_string(), 6)]
+        );
+
+        let operands = messages[8].get("body").unwrap().get("variables").unwrap();
+        assert_eq!(
+            operands.to_string(),
+            r#"[{"name":"1","value":"2","variablesReference":0},{"name":"0","value":"1","variablesReference":0}]"#
+        );
+        let locals = messages[9].get("body").unwrap().get("variables").unwrap();
+        assert_eq!(locals.to_string(), "[]");
+
+        assert_eq!(
+            summary(&messages[10..]),
+            ["response continue", "event exited", "event terminated"]
+        );
+    }
+
+    #[test]
+    fn function_breakpoints_and_stop_on_entry() {
+        let messages = session(
+            "functions",
+            vec![
+                launch(true),
+                request(
+                    "setFunctionBreakpoints",
+                    Json::object(vec![(
+                        "breakpoints",
+                        Json::from(vec![
+                            Json::object(vec![("name", Json::from("function"))]),
+                            Json::object(vec![("name", Json::from("missing"))]),
+                        ]),
+                    )]),
+                ),
+                request("configurationDone", Json::object(vec![])),
+                request("next", Json::object(vec![])),
+                request("next", Json::object(vec![])),
+                request("source", Json::object(vec![])),
+                request("bogus", Json::object(vec![])),
+            ],
+        );
+
+        let breakpoints = messages[2].get("body").unwrap().get("breakpoints").unwrap();
+        assert_eq!(
+            breakpoints.to_string(),
+            r#"[{"verified":true,"line":10},{"verified":false}]"#
+        );
+        assert_eq!(
+            summary(&messages[3..9]),
+            [
+                "response configurationDone",
+                "event stopped",
+                "response next",
+                "event stopped",
+                "response next",
+                "event stopped",
+            ]
+        );
+        let reason = |message: &Json| {
+            message
+                .get("body")
+                .and_then(|body| body.get("reason"))
+                .and_then(Json::as_str)
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(reason(&messages[4]), "entry");
+        assert_eq!(reason(&messages[6]), "step");
+        // Stepping over the call stops at the breakpoint inside it.
+        assert_eq!(reason(&messages[8]), "breakpoint");
+
+        let content = messages[9].get("body").unwrap().get("content").unwrap();
+        assert_eq!(content.as_str().unwrap().lines().nth(8), Some(".function:"));
+        assert_eq!(messages[10].get("success"), Some(&Json::Bool(false)));
+    }
+
+    #[test]
+    fn invalid_requests() {
+        let messages = session(
+            "invalid",
+            vec![
+                launch(true),
+                request("configurationDone", Json::object(vec![])),
+                request("variables", Json::object(vec![])),
+                request(
+                    "variables",
+                    Json::object(vec![("variablesReference", Json::from(99))]),
+                ),
+                request("pause", Json::object(vec![])),
+            ],
+        );
+
+        assert_eq!(
+            summary(&messages[4..]),
+            ["response variables", "response variables", "response pause"]
+        );
+        let message = |message: &Json| {
+            assert_eq!(message.get("success"), Some(&Json::Bool(false)));
+            message
+                .get("message")
+                .and_then(Json::as_str)
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(message(&messages[4]), "Unknown variables reference 0");
+        assert_eq!(message(&messages[5]), "Unknown variables reference 99");
+        assert_eq!(message(&messages[6]), "not supported");
+    }
+
+    #[test]
+    fn launch_errors() {
+        let mut it = instruction_table();
+        let valid = program(&it, "valid", None);
+        let bytecode = fs::read(&valid).unwrap();
+        fs::remove_file(&valid).unwrap();
+
+        fn noop(_machine: &mut Machine<usize>, _args: &[usize]) {}
+        it.insert(crate::instruction::Instruction::new(9, "unknown", 0, noop));
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("unknown", vec![]);
+        let mut unknown: Vec<u8> = vec![];
+        Code::from(builder).to_byte_code(&mut unknown);
+
+        let files = vec![
+            ("garbage", b"not bytecode".to_vec()),
+            ("truncated", bytecode[..bytecode.len() / 2].to_vec()),
+            ("unknown", unknown),
+        ];
+        let mut requests = vec![];
+        for (name, contents) in &files {
+            let path =
+                env::temp_dir().join(format!("stack-vm-dap-{}-{}.svm", name, std::process::id()));
+            fs::write(&path, contents).unwrap();
+            requests.push(request(
+                "launch",
+                Json::object(vec![("program", Json::from(path.to_str().unwrap()))]),
+            ));
+        }
+        let messages = session("launch-errors", requests);
+        for (name, _) in &files {
+            let path =
+                env::temp_dir().join(format!("stack-vm-dap-{}-{}.svm", name, std::process::id()));
+            fs::remove_file(path).unwrap();
+        }
+
+        assert_eq!(
+            summary(&messages),
+            ["response launch", "response launch", "response launch"]
+        );
+        for message in &messages {
+            assert_eq!(message.get("success"), Some(&Json::Bool(false)));
+        }
+        let message = |index: usize| {
+            messages[index]
+                .get("message")
+                .and_then(Json::as_str)
+                .unwrap()
+        };
+        assert!(message(0).ends_with("it isn't valid bytecode"));
+        assert!(message(1).ends_with("it isn't valid bytecode"));
+        assert!(message(2)
+            .ends_with("Code uses instruction unknown (9) which isn't in the instruction table"));
+    }
+
+    #[test]
+    fn source_breakpoints() {
+        let messages = source_session(
+            "source",
+            Some("example.src"),
+            vec![
+                launch(false),
+                request(
+                    "setBreakpoints",
+                    Json::object(vec![
+                        (
+                            "source",
+                            Json::object(vec![("path", Json::from("/home/user/example.src"))]),
+                        ),
+                        (
+                            "breakpoints",
+                            Json::from(vec![
+                                Json::object(vec![("line", Json::from(4))]),
+                                Json::object(vec![("line", Json::from(8))]),
+                            ]),
+                        ),
+                    ]),
+                ),
+                request("configurationDone", Json::object(vec![])),
+                request("stackTrace", Json::object(vec![])),
+            ],
+        );
+
+        // The breakpoint on the line between the two halves of the program
+        // moves to the first line of the function.
+        let breakpoints = messages[2].get("body").unwrap().get("breakpoints").unwrap();
+        assert_eq!(
+            breakpoints.to_string(),
+            r#"[{"verified":true,"line":5},{"verified":false}]"#
+        );
+        assert_eq!(
+            summary(&messages[3..5]),
+            ["response configurationDone", "event stopped"]
+        );
+
+        let frames = messages[5].get("body").unwrap().get("stackFrames").unwrap();
+        assert_eq!(
+            frames.to_string(),
+            concat!(
+                r#"[{"id":0,"name":"function","source":{"name":"example.src","path":"example.src"},"line":5,"column":1},"#,
+                r#"{"id":1,"name":"main","source":{"name":"example.src","path":"example.src"},"line":2,"column":1}]"#
+            )
+        );
+    }
+}
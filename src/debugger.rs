@@ -0,0 +1,423 @@
+//! Breakpoints and watchpoints.
+//!
+//! A `Debugger` is an `Observer` which pauses the machine when something
+//! interesting happens:
+//!
+//! * a breakpoint stops the machine *before* the instruction at a given
+//!   address, or at the start of a given label, is executed,
+//! * a watchpoint on a local stops the machine *after* an instruction
+//!   changes the value of that local in the current frame, and
+//! * a watchpoint on the operand stack stops the machine *after* an
+//!   instruction grows the operand stack to a given depth.
+//!
+//! Pausing suspends the machine, so `Machine::run` returns.  While it's
+//! paused you can find out why from `Debugger::stop` and examine the
+//! machine with `Machine::backtrace`.  Calling `run` again carries on from
+//! where it stopped; the breakpoint it's paused on doesn't trigger again
+//! straight away.
+//!
+//! ## Examples
+//!
+//! ```
+//! use stack_vm::{Instruction, InstructionTable, Builder, Code, Machine, Stop, WriteManyTable};
+//!
+//! fn push(machine: &mut Machine<i64>, args: &[usize]) {
+//!     let arg = *machine.get_data(args[0]);
+//!     machine.operand_push(arg);
+//! }
+//!
+//! let mut instruction_table = InstructionTable::new();
+//! instruction_table.insert(Instruction::new(0, "push", 1, push));
+//! let mut builder: Builder<i64> = Builder::new(&instruction_table);
+//! builder.push("push", vec![1]);
+//! builder.label("second");
+//! builder.push("push", vec![2]);
+//!
+//! let constants: WriteManyTable<i64> = WriteManyTable::new();
+//! let mut machine = Machine::new(Code::from(builder), &constants, &instruction_table);
+//! let debugger = machine.debug();
+//! debugger.lock().unwrap().break_at_label("second");
+//!
+//! machine.run();
+//! assert_eq!(debugger.lock().unwrap().stop(), Some(&Stop::Breakpoint(3)));
+//! assert_eq!(machine.operand_stack.len(), 1);
+//!
+//! machine.run();
+//! assert!(machine.is_finished());
+//! assert_eq!(machine.operand_stack.len(), 2);
+//! ```
+
+use crate::backtrace::Location;
+use crate::machine::Machine;
+use crate::observer::Observer;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::Mutex;
+
+/// The reason a `Debugger` paused the machine.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop<T> {
+    /// The machine reached a breakpoint at the given address.
+    Breakpoint(usize),
+    /// A watched local changed value.  `None` means the local wasn't set.
+    Local {
+        name: String,
+        old: Option<T>,
+        new: Option<T>,
+    },
+    /// The operand stack grew to the given depth.
+    OperandDepth(usize),
+}
+
+/// One frame of the call stack, as returned by `Machine::backtrace`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackFrame<T> {
+    /// Where this frame is executing: the current instruction for the
+    /// innermost frame, or the return address of the call it's waiting on
+    /// for the others.
+    pub location: Location,
+    /// The address this frame will return to.
+    pub return_address: usize,
+    /// The frame's local variables, sorted by name.
+    pub locals: Vec<(String, T)>,
+}
+
+/// An observer which pauses the machine at breakpoints and watchpoints.
+///
+/// Register one with `Machine::debug`.
+#[derive(Debug)]
+pub struct Debugger<T> {
+    breakpoints: BTreeSet<usize>,
+    label_breakpoints: BTreeSet<String>,
+    watches: Vec<String>,
+    operand_depth: Option<usize>,
+    before: Vec<Option<T>>,
+    call_depth: usize,
+    operand_depth_before: usize,
+    paused_at: Option<usize>,
+    stop: Option<Stop<T>>,
+}
+
+impl<T: fmt::Debug + Clone + PartialEq> Debugger<T> {
+    /// Create a new debugger with no breakpoints or watchpoints.
+    pub fn new() -> Debugger<T> {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            label_breakpoints: BTreeSet::new(),
+            watches: vec![],
+            operand_depth: None,
+            before: vec![],
+            call_depth: 0,
+            operand_depth_before: 0,
+            paused_at: None,
+            stop: None,
+        }
+    }
+
+    /// Pause before executing the instruction at `ip`.
+    pub fn break_at(&mut self, ip: usize) {
+        self.breakpoints.insert(ip);
+    }
+
+    /// Pause before executing the first instruction after a label.
+    pub fn break_at_label(&mut self, name: &str) {
+        self.label_breakpoints.insert(name.to_string());
+    }
+
+    /// Remove the breakpoint at `ip`.
+    pub fn remove_breakpoint(&mut self, ip: usize) {
+        self.breakpoints.remove(&ip);
+    }
+
+    /// Remove the breakpoint on a label.
+    pub fn remove_label_breakpoint(&mut self, name: &str) {
+        self.label_breakpoints.remove(name);
+    }
+
+    /// Pause whenever the value of a local in the current frame changes.
+    pub fn watch_local(&mut self, name: &str) {
+        if !self.watches.iter().any(|watch| watch == name) {
+            self.watches.push(name.to_string());
+        }
+    }
+
+    /// Stop watching a local.
+    pub fn unwatch_local(&mut self, name: &str) {
+        self.watches.retain(|watch| watch != name);
+    }
+
+    /// Pause when the operand stack grows to `depth` elements or more.
+    pub fn watch_operand_depth(&mut self, depth: usize) {
+        self.operand_depth = Some(depth);
+    }
+
+    /// Stop watching the depth of the operand stack.
+    pub fn unwatch_operand_depth(&mut self) {
+        self.operand_depth = None;
+    }
+
+    /// Why the machine is paused, or `None` if it isn't.
+    pub fn stop(&self) -> Option<&Stop<T>> {
+        self.stop.as_ref()
+    }
+
+    /// Returns `true` if the debugger has paused the machine.
+    pub fn is_paused(&self) -> bool {
+        self.stop.is_some()
+    }
+
+    fn is_breakpoint(&self, machine: &Machine<T>, ip: usize) -> bool {
+        self.breakpoints.contains(&ip)
+            || self
+                .label_breakpoints
+                .iter()
+                .any(|name| machine.code.get_label_ip(name) == Some(ip))
+    }
+
+    fn watched_values(&self, machine: &Machine<T>) -> Vec<Option<T>> {
+        self.watches
+            .iter()
+            .map(|name| machine.call_stack.peek().get_local(name).cloned())
+            .collect()
+    }
+}
+
+impl<T: fmt::Debug + Clone + PartialEq> Default for Debugger<T> {
+    fn default() -> Debugger<T> {
+        Debugger::new()
+    }
+}
+
+impl<T: fmt::Debug + Clone + PartialEq + Send> Observer<T> for Debugger<T> {
+    fn before_instruction(
+        &mut self,
+        machine: &mut Machine<T>,
+        ip: usize,
+        _op_code: usize,
+        _args: &[usize],
+    ) {
+        self.stop = None;
+        let resuming = self.paused_at.take() == Some(ip);
+        if !resuming && self.is_breakpoint(machine, ip) {
+            self.paused_at = Some(ip);
+            self.stop = Some(Stop::Breakpoint(ip));
+            machine.suspend();
+            return;
+        }
+
+        self.before = self.watched_values(machine);
+        self.call_depth = machine.call_stack.len();
+        self.operand_depth_before = machine.operand_stack.len();
+    }
+
+    fn after_instruction(
+        &mut self,
+        machine: &mut Machine<T>,
+        _ip: usize,
+        _op_code: usize,
+        _args: &[usize],
+    ) {
+        if machine.call_stack.len() == self.call_depth {
+            let after = self.watched_values(machine);
+            let changed = self
+                .before
+                .iter()
+                .zip(after)
+                .zip(&self.watches)
+                .find(|((old, new), _)| *old != new);
+            if let Some(((old, new), name)) = changed {
+                self.stop = Some(Stop::Local {
+                    name: name.clone(),
+                    old: old.clone(),
+                    new,
+                });
+                machine.suspend();
+                return;
+            }
+        }
+
+        if let Some(depth) = self.operand_depth {
+            let current = machine.operand_stack.len();
+            if self.operand_depth_before < depth && current >= depth {
+                self.stop = Some(Stop::OperandDepth(current));
+                machine.suspend();
+            }
+        }
+    }
+}
+
+/// Execute one instruction, stepping into calls.
+pub(crate) fn step_into<T>(machine: &mut Machine<T>, debugger: &Mutex<Debugger<T>>)
+where
+    T: fmt::Debug + Clone + PartialEq,
+{
+    step(machine, debugger);
+}
+
+/// Execute one instruction, running any call it makes to completion.
+pub(crate) fn step_over<T>(machine: &mut Machine<T>, debugger: &Mutex<Debugger<T>>)
+where
+    T: fmt::Debug + Clone + PartialEq,
+{
+    let depth = machine.call_stack.len();
+    while step(machine, debugger) && machine.call_stack.len() > depth {}
+}
+
+/// Run until the current frame returns.
+pub(crate) fn step_out<T>(machine: &mut Machine<T>, debugger: &Mutex<Debugger<T>>)
+where
+    T: fmt::Debug + Clone + PartialEq,
+{
+    let depth = machine.call_stack.len();
+    while step(machine, debugger) && machine.call_stack.len() >= depth {}
+}
+
+/// Run until the debugger pauses the machine or it finishes.
+pub(crate) fn resume<T>(machine: &mut Machine<T>, debugger: &Mutex<Debugger<T>>)
+where
+    T: fmt::Debug + Clone + PartialEq,
+{
+    if step(machine, debugger) {
+        machine.run();
+    }
+}
+
+/// Execute one instruction, returning `false` if the machine has finished
+/// or the debugger paused it.
+///
+/// Breakpoints pause *before* an instruction.  Stepping on from a
+/// breakpoint runs the instruction it paused before, but any other
+/// breakpoint on the current instruction, such as one set on the first
+/// instruction before the program starts, is a real stop.
+fn step<T>(machine: &mut Machine<T>, debugger: &Mutex<Debugger<T>>) -> bool
+where
+    T: fmt::Debug + Clone + PartialEq,
+{
+    if machine.is_finished() {
+        return false;
+    }
+    machine.step();
+    !machine.is_finished() && !debugger.lock().unwrap().is_paused()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builder::Builder;
+    use crate::code::Code;
+    use crate::fixtures::instruction_table;
+    use crate::instruction_table::InstructionTable;
+    use crate::write_many_table::WriteManyTable;
+
+    fn code(it: &InstructionTable<usize>) -> Code<usize> {
+        let mut builder: Builder<usize> = Builder::new(it);
+        builder.push("push", vec![1]);
+        builder.push("store", vec![]);
+        builder.push("call", vec![]);
+        builder.push("ret", vec![]);
+        builder.label("function");
+        builder.push("push", vec![2]);
+        builder.push("store", vec![]);
+        builder.push("push", vec![3]);
+        builder.push("push", vec![4]);
+        builder.push("ret", vec![]);
+        Code::from(builder)
+    }
+
+    #[test]
+    fn breakpoints() {
+        let it = instruction_table();
+        let constants: WriteManyTable<usize> = WriteManyTable::new();
+        let mut machine = Machine::new(code(&it), &constants, &it);
+        let debugger = machine.debug();
+        debugger.lock().unwrap().break_at(3);
+        debugger.lock().unwrap().break_at_label("function");
+
+        machine.run();
+        assert_eq!(debugger.lock().unwrap().stop(), Some(&Stop::Breakpoint(3)));
+        assert_eq!(machine.ip, 3);
+
+        machine.run();
+        assert_eq!(debugger.lock().unwrap().stop(), Some(&Stop::Breakpoint(9)));
+        assert_eq!(machine.ip, 9);
+
+        debugger.lock().unwrap().remove_label_breakpoint("function");
+        machine.run();
+        assert!(!debugger.lock().unwrap().is_paused());
+        assert!(machine.is_finished());
+    }
+
+    #[test]
+    fn watch_local() {
+        let it = instruction_table();
+        let constants: WriteManyTable<usize> = WriteManyTable::new();
+        let mut machine = Machine::new(code(&it), &constants, &it);
+        let debugger = machine.debug();
+        debugger.lock().unwrap().watch_local("x");
+
+        machine.run();
+        assert_eq!(
+            debugger.lock().unwrap().stop(),
+            Some(&Stop::Local {
+                name: "x".to_string(),
+                old: None,
+                new: Some(1),
+            })
+        );
+        assert_eq!(machine.ip, 5);
+
+        // The call gives us a new frame, which isn't a change.
+        machine.run();
+        assert_eq!(
+            debugger.lock().unwrap().stop(),
+            Some(&Stop::Local {
+                name: "x".to_string(),
+                old: None,
+                new: Some(2),
+            })
+        );
+        assert_eq!(machine.ip, 14);
+    }
+
+    #[test]
+    fn watch_operand_depth() {
+        let it = instruction_table();
+        let constants: WriteManyTable<usize> = WriteManyTable::new();
+        let mut machine = Machine::new(code(&it), &constants, &it);
+        let debugger = machine.debug();
+        debugger.lock().unwrap().watch_operand_depth(2);
+
+        machine.run();
+        assert_eq!(
+            debugger.lock().unwrap().stop(),
+            Some(&Stop::OperandDepth(2))
+        );
+        assert_eq!(machine.ip, 20);
+    }
+
+    #[test]
+    fn backtrace() {
+        let it = instruction_table();
+        let constants: WriteManyTable<usize> = WriteManyTable::new();
+        let mut machine = Machine::new(code(&it), &constants, &it);
+        let debugger = machine.debug();
+        debugger.lock().unwrap().break_at(14);
+
+        machine.run();
+        assert_eq!(
+            machine.backtrace(),
+            [
+                StackFrame {
+                    location: Location::new(&machine.code, 14),
+                    return_address: 7,
+                    locals: vec![("x".to_string(), 2)],
+                },
+                StackFrame {
+                    location: Location::of_return(&machine.code, 7),
+                    return_address: 22,
+                    locals: vec![("x".to_string(), 1)],
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,36 @@
+//! Instructions shared by the debugger, REPL and DAP tests.
+//!
+//! Each test module builds its own program from these instructions.  Calls
+//! always go to a label named `function`, and `store` pops into a local
+//! named `x`.
+
+use crate::instruction::Instruction;
+use crate::instruction_table::InstructionTable;
+use crate::machine::Machine;
+
+fn push(machine: &mut Machine<usize>, args: &[usize]) {
+    let arg = *machine.get_data(args[0]);
+    machine.operand_push(arg);
+}
+
+fn store(machine: &mut Machine<usize>, _args: &[usize]) {
+    let value = machine.operand_pop();
+    machine.set_local("x", value);
+}
+
+fn call(machine: &mut Machine<usize>, _args: &[usize]) {
+    machine.call("function");
+}
+
+fn ret(machine: &mut Machine<usize>, _args: &[usize]) {
+    machine.ret();
+}
+
+pub fn instruction_table() -> InstructionTable<usize> {
+    let mut it = InstructionTable::new();
+    it.insert(Instruction::new(0, "push", 1, push).with_doc("Push an operand."));
+    it.insert(Instruction::new(1, "store", 0, store));
+    it.insert(Instruction::new(2, "call", 0, call));
+    it.insert(Instruction::new(3, "ret", 0, ret));
+    it
+}
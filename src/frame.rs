@@ -11,7 +11,7 @@ use crate::table::Table;
 /// * A `WriteManyTable` for storage of local variables.
 /// * A return address - the instruction pointer for the machine to return to
 ///   when returning from this call.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Frame<T> {
     locals: WriteManyTable<T>,
     pub return_address: usize
@@ -35,6 +35,11 @@ impl<T> Frame<T> {
     pub fn set_local(&mut self, name: &str, value: T) {
         self.locals.insert(name, value);
     }
+
+    /// Return the table of local variables.
+    pub fn locals(&self) -> &WriteManyTable<T> {
+        &self.locals
+    }
 }
 
 #[cfg(test)]
@@ -50,6 +50,120 @@
 //!
 //! Instruction::new(1, "jump", 1, jump);
 //! ```
+//!
+//! ## Argument kinds
+//!
+//! By default every argument is an index into the data section.  An
+//! instruction can instead declare that an argument is an immediate
+//! `usize`, stored directly in the code, or the address of a label, which
+//! the builder fills in from the label's name.  Either way the instruction
+//! receives the value itself in `args` rather than an index.
+//!
+//! ```
+//! use stack_vm::{ArgKind, Instruction, Machine};
+//!
+//! fn jump_n(machine: &mut Machine<u64>, args: &[usize]) {
+//!     if machine.operand_stack.len() > args[0] {
+//!         machine.jump_ip(args[1]);
+//!     }
+//! }
+//!
+//! Instruction::new(2, "jump_n", 2, jump_n)
+//!     .with_arg_kinds(vec![ArgKind::Immediate, ArgKind::Label]);
+//! ```
+//!
+//! Data arguments which hold the name of a label or a local can say so with
+//! `ArgKind::LabelName` and `ArgKind::LocalName`.  They're still indexes
+//! into the data section, but the builder checks that label names refer to
+//! a label, the disassembler shows the name rather than the index, and
+//! `Code::verify` checks them too.  The builder reads names from operands
+//! with the function given to `Builder::set_operand_name`, and passes it on
+//! to the `Code`.  An instruction can also carry a short description of what
+//! it does, which the debugger's `help` command shows.
+//!
+//! ```
+//! use stack_vm::{ArgKind, Instruction, Machine};
+//!
+//! fn jump(machine: &mut Machine<String>, args: &[usize]) {
+//!     let label = machine.get_data(args[0]).clone();
+//!     machine.jump(&label);
+//! }
+//!
+//! let instruction = Instruction::new(1, "jump", 1, jump)
+//!     .with_arg_kinds(vec![ArgKind::LabelName])
+//!     .with_doc("Jump to a label.");
+//! assert_eq!(instruction.signature(), "jump <label name>");
+//! ```
+//!
+//! ## Variadic instructions
+//!
+//! An instruction's arity is the number of arguments it needs, but it can
+//! accept more, up to a maximum or without limit.  Each use of the
+//! instruction stores how many arguments it was given, and the extra
+//! arguments are always indexes into the data section.
+//!
+//! ```
+//! use stack_vm::{Instruction, Machine};
+//!
+//! fn sum(machine: &mut Machine<i64>, args: &[usize]) {
+//!     let total = args.iter().map(|arg| *machine.get_data(*arg)).sum();
+//!     machine.operand_push(total);
+//! }
+//!
+//! let instruction = Instruction::new(3, "sum", 1, sum).with_variadic_arity(None);
+//! assert!(instruction.accepts_arity(1));
+//! assert!(instruction.accepts_arity(10));
+//! assert!(!instruction.accepts_arity(0));
+//! ```
+//!
+//! ## Stack effects
+//!
+//! An instruction can declare how many operands it pops and pushes, and
+//! where it sends control.  Once every instruction does, `Code::stack_depths`
+//! can check that the operand stack never underflows before the program is
+//! run.
+//!
+//! ```
+//! use stack_vm::{ArgKind, Flow, Instruction, Machine};
+//!
+//! fn add(machine: &mut Machine<i64>, _args: &[usize]) {
+//!     let rhs = machine.operand_pop();
+//!     let lhs = machine.operand_pop();
+//!     machine.operand_push(lhs + rhs);
+//! }
+//!
+//! fn jump_if(machine: &mut Machine<i64>, args: &[usize]) {
+//!     if machine.operand_pop() != 0 {
+//!         machine.jump_ip(args[0]);
+//!     }
+//! }
+//!
+//! Instruction::new(0, "add", 0, add).with_stack_effect(2, 1);
+//! Instruction::new(1, "jump_if", 1, jump_if)
+//!     .with_arg_kinds(vec![ArgKind::Label])
+//!     .with_stack_effect(1, 0)
+//!     .with_flow(Flow::Branch);
+//! ```
+//!
+//! A variadic instruction whose pops depend on how many arguments it was
+//! given declares how many it pops for each argument past its arity.
+//!
+//! ```
+//! use stack_vm::{Instruction, Machine};
+//!
+//! fn make_list(machine: &mut Machine<Vec<i64>>, args: &[usize]) {
+//!     let mut list = vec![];
+//!     for _ in args {
+//!         list.insert(0, machine.operand_pop()[0]);
+//!     }
+//!     machine.operand_push(list);
+//! }
+//!
+//! let instruction = Instruction::new(2, "make_list", 0, make_list)
+//!     .with_variadic_arity(None)
+//!     .with_variadic_stack_effect(0, 1, 1);
+//! assert_eq!(instruction.stack_effect.unwrap().pops(3), 3);
+//! ```
 
 use std::fmt;
 use crate::machine::Machine;
@@ -60,12 +174,124 @@ use crate::machine::Machine;
 /// * An op code - a unique integer to identify this instruction.
 /// * A name for serialisation and debugging reasons.
 /// * An arity - the number of arguments this instruction expects to receive.
+/// * A maximum arity, which is the same as the arity unless the instruction
+///   is variadic, and `None` if it takes any number of extra arguments.
 /// * A function which is used to execute the instruction.
+/// * The kind of each argument.
+/// * An optional description of what it does.
+/// * An optional stack effect, and where it sends control.
 pub struct Instruction<T: fmt::Debug> {
-    pub op_code: usize,
-    pub name:    String,
-    pub arity:   usize,
-    pub fun:     InstructionFn<T>
+    pub op_code:      usize,
+    pub name:         String,
+    pub arity:        usize,
+    pub max_arity:    Option<usize>,
+    pub fun:          InstructionFn<T>,
+    pub arg_kinds:    Vec<ArgKind>,
+    pub doc:          Option<String>,
+    pub stack_effect: Option<StackEffect>,
+    pub flow:         Flow
+}
+
+/// How an instruction argument is stored in the code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgKind {
+    /// An index into the data section.
+    Data,
+    /// A `usize` stored directly in the code.
+    Immediate,
+    /// The address of a label, stored directly in the code.
+    Label,
+    /// An index into the data section, whose operand is the name of a
+    /// label.
+    LabelName,
+    /// An index into the data section, whose operand is the name of a
+    /// local.
+    LocalName,
+}
+
+impl ArgKind {
+    /// Returns `true` if arguments of this kind are indexes into the data
+    /// section.
+    pub fn is_data(self) -> bool {
+        match self {
+            ArgKind::Data | ArgKind::LabelName | ArgKind::LocalName => true,
+            ArgKind::Immediate | ArgKind::Label => false,
+        }
+    }
+
+    /// The number which represents this kind in bytecode.
+    pub(crate) fn to_byte(self) -> u8 {
+        match self {
+            ArgKind::Data => 0,
+            ArgKind::Immediate => 1,
+            ArgKind::Label => 2,
+            ArgKind::LabelName => 3,
+            ArgKind::LocalName => 4,
+        }
+    }
+
+    /// The kind represented by a number in bytecode.
+    pub(crate) fn from_byte(byte: u8) -> Option<ArgKind> {
+        match byte {
+            0 => Some(ArgKind::Data),
+            1 => Some(ArgKind::Immediate),
+            2 => Some(ArgKind::Label),
+            3 => Some(ArgKind::LabelName),
+            4 => Some(ArgKind::LocalName),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ArgKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ArgKind::Data => "data",
+            ArgKind::Immediate => "immediate",
+            ArgKind::Label => "label",
+            ArgKind::LabelName => "label name",
+            ArgKind::LocalName => "local name",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The number of operands an instruction pops from and then pushes onto the
+/// operand stack.
+///
+/// A variadic instruction pops `pops_per_extra_arg` more operands for each
+/// argument it's given past its arity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackEffect {
+    pub pops: usize,
+    pub pops_per_extra_arg: usize,
+    pub pushes: usize,
+}
+
+impl StackEffect {
+    /// The number of operands popped by an instruction given `extra_args`
+    /// arguments past its arity.
+    pub fn pops(&self, extra_args: usize) -> usize {
+        self.pops + self.pops_per_extra_arg * extra_args
+    }
+}
+
+/// Where an instruction sends control once it's finished.
+///
+/// Only arguments declared as `ArgKind::Label` or `ArgKind::LabelName` are
+/// known jump targets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    /// Continues with the next instruction.  Calls are `Next` too, with a
+    /// stack effect covering the whole call.
+    Next,
+    /// Either jumps to one of its label arguments or continues with the
+    /// next instruction.
+    Branch,
+    /// Always jumps to one of its label arguments.
+    Jump,
+    /// Never continues, like a return or a halt.
+    Stop,
 }
 
 /// The instruction function signature.
@@ -77,7 +303,8 @@ pub struct Instruction<T: fmt::Debug> {
 /// required (by pushing operands to the stack, for example).
 ///
 /// The `args` array contains indexes into the `Builder`'s data section. It's
-/// up to your instruction to retrieve said data.
+/// up to your instruction to retrieve said data.  Arguments declared as
+/// immediates or labels contain their value or address instead.
 pub type InstructionFn<T> = fn(machine: &mut Machine<T>, args: &[usize]);
 
 impl<T: fmt::Debug> fmt::Debug for Instruction<T> {
@@ -88,14 +315,130 @@ impl<T: fmt::Debug> fmt::Debug for Instruction<T> {
 
 impl<T: fmt::Debug> Instruction<T> {
     /// Create a new instruction.
+    ///
+    /// All of its arguments are indexes into the data section.
     pub fn new(op_code: usize, name: &str, arity: usize, fun: InstructionFn<T>) -> Instruction<T> {
         Instruction {
             op_code,
             name: String::from(name),
             arity,
-            fun
+            max_arity: Some(arity),
+            fun,
+            arg_kinds: vec![ArgKind::Data; arity],
+            doc: None,
+            stack_effect: None,
+            flow: Flow::Next
+        }
+
+    }
+
+    /// Declare the kind of each of the instruction's arguments.
+    ///
+    /// Panics if there isn't exactly one kind per argument.
+    pub fn with_arg_kinds(mut self, arg_kinds: Vec<ArgKind>) -> Instruction<T> {
+        if arg_kinds.len() != self.arity {
+            panic!(
+                "Instruction {} has arity of {}, but {} argument kinds were given.",
+                self.name,
+                self.arity,
+                arg_kinds.len()
+            );
+        }
+        self.arg_kinds = arg_kinds;
+        self
+    }
+
+    /// Allow the instruction to take more arguments than its arity, up to
+    /// `max_arity` or without limit if it's `None`.
+    ///
+    /// Panics if `max_arity` is less than the arity.
+    pub fn with_variadic_arity(mut self, max_arity: Option<usize>) -> Instruction<T> {
+        if let Some(max_arity) = max_arity {
+            if max_arity < self.arity {
+                panic!(
+                    "Instruction {} has arity of {}, but a maximum arity of {} was given.",
+                    self.name, self.arity, max_arity
+                );
+            }
+        }
+        self.max_arity = max_arity;
+        self
+    }
+
+    /// Returns `true` if the instruction can take more arguments than its
+    /// arity.
+    pub fn is_variadic(&self) -> bool {
+        self.max_arity != Some(self.arity)
+    }
+
+    /// Returns `true` if the instruction can take `arity` arguments.
+    pub fn accepts_arity(&self, arity: usize) -> bool {
+        match self.max_arity {
+            Some(max_arity) => self.arity <= arity && arity <= max_arity,
+            None => self.arity <= arity,
+        }
+    }
+
+    /// Describe what the instruction does.
+    pub fn with_doc(mut self, doc: &str) -> Instruction<T> {
+        self.doc = Some(doc.to_string());
+        self
+    }
+
+    /// Declare how many operands the instruction pops and then pushes.
+    ///
+    /// For a variadic instruction the pops don't depend on how many
+    /// arguments it's given.  Use `with_variadic_stack_effect` when they do.
+    pub fn with_stack_effect(self, pops: usize, pushes: usize) -> Instruction<T> {
+        self.with_variadic_stack_effect(pops, 0, pushes)
+    }
+
+    /// Declare how many operands the instruction pops, how many more it pops
+    /// for each argument past its arity, and how many it then pushes.
+    pub fn with_variadic_stack_effect(
+        mut self,
+        pops: usize,
+        pops_per_extra_arg: usize,
+        pushes: usize,
+    ) -> Instruction<T> {
+        self.stack_effect = Some(StackEffect {
+            pops,
+            pops_per_extra_arg,
+            pushes,
+        });
+        self
+    }
+
+    /// Declare where the instruction sends control.
+    pub fn with_flow(mut self, flow: Flow) -> Instruction<T> {
+        self.flow = flow;
+        self
+    }
+
+    /// Returns the instruction's name followed by the kind of each of its
+    /// arguments, such as `jump_n <immediate> <label>`.  Variadic
+    /// instructions end with `<data>...`.
+    pub fn signature(&self) -> String {
+        let mut result = self.name.clone();
+        for kind in &self.arg_kinds {
+            result.push_str(&format!(" <{}>", kind));
         }
+        if self.is_variadic() {
+            result.push_str(" <data>...");
+        }
+        result
+    }
+
+    /// Returns the kind of argument at `position`.
+    ///
+    /// Arguments past the arity of a variadic instruction are data.
+    pub fn arg_kind(&self, position: usize) -> ArgKind {
+        self.arg_kinds.get(position).cloned().unwrap_or(ArgKind::Data)
+    }
 
+    /// Returns `true` if every argument is an index into the data section.
+    pub fn has_data_args_only(&self) -> bool {
+        self.arg_kinds.iter().all(|kind| *kind == ArgKind::Data)
     }
 }
 
@@ -114,5 +457,100 @@ mod test {
         assert_eq!(operand.op_code, 13);
         assert_eq!(operand.name, "noop".to_string());
         assert_eq!(operand.arity, 7);
+        assert_eq!(operand.arg_kinds, vec![ArgKind::Data; 7]);
+        assert_eq!(operand.doc, None);
+        assert_eq!(operand.stack_effect, None);
+        assert_eq!(operand.flow, Flow::Next);
+        assert_eq!(operand.max_arity, Some(7));
+        assert!(!operand.is_variadic());
+    }
+
+    #[test]
+    fn with_arg_kinds() {
+        let instruction = Instruction::new(0, "jump_n", 2, noop)
+            .with_arg_kinds(vec![ArgKind::Immediate, ArgKind::Label]);
+        assert_eq!(instruction.arg_kind(0), ArgKind::Immediate);
+        assert_eq!(instruction.arg_kind(1), ArgKind::Label);
+        assert!(!instruction.has_data_args_only());
+        assert!(Instruction::new(1, "push", 1, noop).has_data_args_only());
+    }
+
+    #[test]
+    fn with_doc() {
+        let instruction = Instruction::new(0, "store", 1, noop)
+            .with_arg_kinds(vec![ArgKind::LocalName])
+            .with_doc("Pop a value into a local.");
+        assert_eq!(instruction.doc.as_deref(), Some("Pop a value into a local."));
+        assert_eq!(instruction.signature(), "store <local name>");
+        assert!(instruction.arg_kind(0).is_data());
+        assert!(!instruction.has_data_args_only());
+    }
+
+    #[test]
+    fn with_variadic_arity() {
+        let instruction = Instruction::new(0, "call_native", 1, noop).with_variadic_arity(Some(3));
+        assert!(instruction.is_variadic());
+        assert!(!instruction.accepts_arity(0));
+        assert!(instruction.accepts_arity(1));
+        assert!(instruction.accepts_arity(3));
+        assert!(!instruction.accepts_arity(4));
+        assert_eq!(instruction.signature(), "call_native <data> <data>...");
+
+        let instruction = Instruction::new(1, "make_list", 0, noop).with_variadic_arity(None);
+        assert!(instruction.accepts_arity(0));
+        assert!(instruction.accepts_arity(100));
+    }
+
+    #[test]
+    #[should_panic(expected = "but a maximum arity of 1 was given")]
+    fn with_small_max_arity() {
+        Instruction::new(0, "call_native", 2, noop).with_variadic_arity(Some(1));
+    }
+
+    #[test]
+    fn with_stack_effect() {
+        let instruction = Instruction::new(0, "ret", 0, noop)
+            .with_stack_effect(1, 0)
+            .with_flow(Flow::Stop);
+        assert_eq!(
+            instruction.stack_effect,
+            Some(StackEffect {
+                pops: 1,
+                pops_per_extra_arg: 0,
+                pushes: 0
+            })
+        );
+        assert_eq!(instruction.flow, Flow::Stop);
+    }
+
+    #[test]
+    fn with_variadic_stack_effect() {
+        let instruction = Instruction::new(0, "make_list", 0, noop)
+            .with_variadic_arity(None)
+            .with_variadic_stack_effect(0, 1, 1);
+        let effect = instruction.stack_effect.unwrap();
+        assert_eq!(effect.pops(0), 0);
+        assert_eq!(effect.pops(3), 3);
+        assert_eq!(effect.pushes, 1);
+    }
+
+    #[test]
+    fn arg_kind_bytes() {
+        for kind in &[
+            ArgKind::Data,
+            ArgKind::Immediate,
+            ArgKind::Label,
+            ArgKind::LabelName,
+            ArgKind::LocalName,
+        ] {
+            assert_eq!(ArgKind::from_byte(kind.to_byte()), Some(*kind));
+        }
+        assert_eq!(ArgKind::from_byte(5), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "argument kinds were given")]
+    fn with_wrong_number_of_arg_kinds() {
+        Instruction::new(0, "jump", 1, noop).with_arg_kinds(vec![]);
     }
 }
@@ -3,7 +3,7 @@
 //! Stores the instructions of your machine and allows them to be retrieved
 //! by name or op code.
 
-use crate::instruction::Instruction;
+use crate::instruction::{ArgKind, Instruction};
 use std::collections::HashMap;
 use std::fmt;
 
@@ -19,12 +19,12 @@ impl<T: fmt::Debug> InstructionTable<T> {
         InstructionTable(HashMap::new())
     }
 
-    /// Retrieve an instruction by looking up it's op code.
+    /// Retrieve an instruction by looking up its op code.
     pub fn by_op_code(&self, op_code: usize) -> Option<&Instruction<T>> {
         self.0.get(&op_code)
     }
 
-    /// Retrieve an instruction by looking up it's name.
+    /// Retrieve an instruction by looking up its name.
     pub fn by_name(&self, name: &str) -> Option<&Instruction<T>> {
         self.0.values().find(|instr| instr.name == name)
     }
@@ -52,6 +52,22 @@ impl<T: fmt::Debug> InstructionTable<T> {
         result.sort_by(|lhs, rhs| lhs.0.cmp(&rhs.0));
         result
     }
+
+    /// Returns the argument kinds for use in the `Code` struct.
+    ///
+    /// Generates a vector of tuples containing the op code and argument
+    /// kinds of each instruction which has arguments other than data
+    /// indexes.
+    pub fn arg_kinds(&self) -> Vec<(usize, Vec<ArgKind>)> {
+        let mut result: Vec<(usize, Vec<ArgKind>)> = self
+            .0
+            .values()
+            .filter(|instr| !instr.has_data_args_only())
+            .map(|instr| (instr.op_code, instr.arg_kinds.clone()))
+            .collect();
+        result.sort_by(|lhs, rhs| lhs.0.cmp(&rhs.0));
+        result
+    }
 }
 
 #[cfg(test)]
@@ -90,4 +106,12 @@ mod test {
         let instr = table.by_name("NOOP").unwrap();
         assert_eq!(instr.op_code, 0);
     }
+    #[test]
+    fn arg_kinds() {
+        let mut table: InstructionTable<usize> = InstructionTable::new();
+        table.insert(Instruction::new(0, "NOOP", 0, noop));
+        table.insert(Instruction::new(1, "PUSH", 1, noop));
+        table.insert(Instruction::new(2, "JUMP", 1, noop).with_arg_kinds(vec![ArgKind::Label]));
+        assert_eq!(table.arg_kinds(), vec![(2, vec![ArgKind::Label])]);
+    }
 }
@@ -8,7 +8,7 @@
 //! your program.
 //!
 //! Stack machines are computers which use an operand stack to perform the
-//! evaluation of postfix expressions.  Every computer architecture has it's
+//! evaluation of postfix expressions.  Every computer architecture has its
 //! own instruction set which is the basic set of operations that the computer
 //! can perform.
 //!
@@ -66,7 +66,7 @@
 //!
 //! Once you have finished defining your instructions you can use them to build
 //! a `stack_vm::InstructionTable`, where every instruction is identified by
-//! it's `op_code`, `name` and `arity`.
+//! its `op_code`, `name` and `arity`.
 //!
 //! * `op_code` a positive integer which uniquely identifies this instruction.
 //!   This is manually entered rather than auto-generated from insert order
@@ -78,7 +78,8 @@
 //! * `arity` the number of arguments your instruction expects *from program
 //!   data*.  This is not the number of operands your function needs off the
 //!   operand stack.  This is used so that you can place constant data into
-//!   the program at compile time.
+//!   the program at compile time.  Variadic instructions declared with
+//!   `Instruction::with_variadic_arity` take at least this many.
 //!
 //! ```
 //! use stack_vm::{Instruction, InstructionTable, Machine};
@@ -196,31 +197,61 @@
 
 extern crate rmp;
 
+mod backtrace;
 mod builder;
+mod channel;
 mod code;
+mod coroutine;
+mod coverage;
+mod dap;
+mod debugger;
 mod frame;
 mod from_byte_code;
 mod instruction;
 mod instruction_table;
 mod machine;
+mod observer;
+mod profiler;
+mod repl;
+mod scheduler;
+mod shared;
+mod snapshot;
+mod source_map;
 mod stack;
 mod table;
 mod to_byte_code;
+mod trace;
 mod write_many_table;
 mod write_once_table;
 
-pub use crate::builder::Builder;
-pub use crate::code::Code;
+pub use crate::backtrace::{Backtrace, Location, RuntimeError};
+pub use crate::builder::{Argument, BuildError, Builder, FunctionHook, Label};
+pub use crate::channel::{Mailbox, Router};
+pub use crate::code::{Code, Function, OperandName, VerifyError};
+pub use crate::coroutine::{Coroutine, CoroutineId, Generator};
+pub use crate::coverage::Coverage;
+pub use crate::dap::DapServer;
+pub use crate::debugger::{Debugger, StackFrame, Stop};
 pub use crate::frame::Frame;
 pub use crate::from_byte_code::FromByteCode;
-pub use crate::instruction::{Instruction, InstructionFn};
+pub use crate::instruction::{ArgKind, Flow, Instruction, InstructionFn, StackEffect};
 pub use crate::instruction_table::InstructionTable;
 pub use crate::machine::Machine;
+pub use crate::observer::{Observer, SharedObserver};
+pub use crate::profiler::{Profiler, Stats};
+pub use crate::repl::Repl;
+pub use crate::scheduler::{Scheduler, ThreadId, ThreadState};
+pub use crate::shared::{Constants, Shared};
+pub use crate::snapshot::{RestoreError, Snapshot};
+pub use crate::source_map::{SourceLocation, SourceMap};
 pub use crate::stack::Stack;
 pub use crate::table::Table;
 pub use crate::to_byte_code::ToByteCode;
+pub use crate::trace::{TraceEntry, TraceReader, Tracer};
 pub use crate::write_many_table::WriteManyTable;
 pub use crate::write_once_table::WriteOnceTable;
 
 #[cfg(test)]
 mod acceptance;
+#[cfg(test)]
+mod fixtures;
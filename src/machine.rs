@@ -2,12 +2,27 @@
 //!
 //! Pour all your ingredients into `Machine` and make it dance.
 
-use crate::code::Code;
+use crate::backtrace::{self, Backtrace, Location, RuntimeError};
+use crate::channel::Router;
+use crate::code::{Code, Function};
+use crate::coroutine::{Coroutine, CoroutineId, Generator};
+use crate::coverage::Coverage;
+use crate::debugger::{Debugger, StackFrame};
 use crate::frame::Frame;
 use crate::instruction_table::InstructionTable;
+use crate::observer::{Observer, SharedObserver};
+use crate::profiler::Profiler;
+use crate::scheduler::{Scheduler, ThreadId, ThreadState};
+use crate::shared::{Constants, Shared};
+use crate::snapshot::{RestoreError, Snapshot};
 use crate::stack::Stack;
 use crate::table::Table;
+use crate::trace::Tracer;
 use std::fmt;
+use std::io::Write;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
 
 // TODO why is this needed to use a dep?
 extern crate shipyard;
@@ -16,19 +31,40 @@ use machine::shipyard::World;
 /// `Machine` contains all the information needed to run your program.
 ///
 /// * A `Code`, used describe the source instructions and data to execute.
+///   The code is reference counted so that many machines (including forks
+///   and machines on other threads) can share a single copy of it.
 /// * An instruction pointer, which points to the currently-executing
 ///   instruciton.
 /// * A `Table` of constants, which you can use in your instructions if needed.
 /// * A `Stack` of `Frame` used to keep track of calls being executed.
 /// * A `Stack` of `T` which is used as the main operand stack.
+/// * Any coroutines which have been started by the program or the host.
+/// * A `Scheduler` containing any green threads which have been spawned.
+/// * Optionally, a `Router` used to send messages to other machines.
+/// * Any `Observer`s which are notified as the machine executes.
+///
+/// The instruction table and constants are either borrowed (see `new`) or
+/// shared through an `Arc` (see `new_shared`).  Either way the constants
+/// are thread safe, so the machine is `Send` and `Sync` whenever `T` is.
+/// Use `new_shared` to move a machine onto a worker thread which outlives
+/// the tables.
 pub struct Machine<'a, T: 'a + fmt::Debug> {
-    pub code: Code<T>,
-    pub instruction_table: &'a InstructionTable<T>,
+    pub code: Arc<Code<T>>,
+    pub instruction_table: Shared<'a, InstructionTable<T>>,
     pub ip: usize,
-    pub constants: &'a dyn Table<Item = T>,
+    pub constants: Constants<'a, T>,
     pub call_stack: Stack<Frame<T>>,
     pub operand_stack: Stack<T>,
     pub world: World,
+    coroutines: Vec<Option<Coroutine<T>>>,
+    yielded: Option<T>,
+    scheduler: Scheduler<T>,
+    suspended: bool,
+    router: Option<Router<T>>,
+    instruction_ip: usize,
+    observers: Vec<SharedObserver<T>>,
+    coroutine_depth: usize,
+    executed: usize,
 }
 
 impl<'a, T: 'a + fmt::Debug> Machine<'a, T> {
@@ -36,10 +72,46 @@ impl<'a, T: 'a + fmt::Debug> Machine<'a, T> {
     ///
     /// The machine is initialised by passing in your `Code` which contains
     /// all the code and data of your program, and a `Table` of constants`.
-    pub fn new(
-        code: Code<T>,
-        constants: &'a dyn Table<Item = T>,
+    /// The constants must be `Sync`, so the machine can be shared between
+    /// threads.
+    ///
+    /// The code can be passed either by value or as an `Arc<Code<T>>`.  The
+    /// latter allows a single loaded program to back any number of machines
+    /// without being copied.
+    pub fn new<C: Into<Arc<Code<T>>>>(
+        code: C,
+        constants: &'a (dyn Table<Item = T> + Sync),
         instruction_table: &'a InstructionTable<T>,
+    ) -> Machine<'a, T> {
+        Machine::with_shared(
+            code.into(),
+            Constants::Borrowed(constants),
+            Shared::Borrowed(instruction_table),
+        )
+    }
+
+    /// Returns a new `Machine` which owns handles to everything it needs.
+    ///
+    /// This is the same as `new` except that the constants and instruction
+    /// table are passed as `Arc`s, so the machine doesn't borrow anything.
+    /// The constants must be thread safe, so the machine can be moved into a
+    /// thread pool.
+    pub fn new_shared(
+        code: Arc<Code<T>>,
+        constants: Arc<dyn Table<Item = T> + Send + Sync>,
+        instruction_table: Arc<InstructionTable<T>>,
+    ) -> Machine<'a, T> {
+        Machine::with_shared(
+            code,
+            Constants::Owned(constants),
+            Shared::Owned(instruction_table),
+        )
+    }
+
+    fn with_shared(
+        code: Arc<Code<T>>,
+        constants: Constants<'a, T>,
+        instruction_table: Shared<'a, InstructionTable<T>>,
     ) -> Machine<'a, T> {
         let frame: Frame<T> = Frame::new(code.code.len());
         let mut call_stack = Stack::new();
@@ -53,6 +125,15 @@ impl<'a, T: 'a + fmt::Debug> Machine<'a, T> {
             call_stack,
             operand_stack: Stack::new(),
             world: World::new(),
+            coroutines: vec![],
+            yielded: None,
+            scheduler: Scheduler::new(),
+            suspended: false,
+            router: None,
+            instruction_ip: 0,
+            observers: vec![],
+            coroutine_depth: 0,
+            executed: 0,
         }
     }
 
@@ -64,40 +145,136 @@ impl<'a, T: 'a + fmt::Debug> Machine<'a, T> {
     /// one-by-one.  Each instruction function is executed, much like a
     /// callback.
     ///
-    /// Stops when either the last instruction is executed or when the
-    /// last frame is removed from the call stack.
+    /// Stops when either the last instruction is executed, when the
+    /// last frame is removed from the call stack or when the machine is
+    /// suspended (for example by a coroutine yielding).
     pub fn run(&mut self) {
-        loop {
-            if self.ip == self.code.code.len() {
-                break;
-            }
+        self.suspended = false;
+        while !self.is_finished() && !self.suspended {
+            self.step();
+        }
+        if self.is_finished() && self.coroutine_depth == 0 {
+            self.halt();
+        }
+    }
 
-            let op_code = self.next_code();
-            let arity = self.next_code();
+    /// Execute a single instruction.
+    ///
+    /// Fetches the instruction at the current instruction pointer along with
+    /// its arguments and calls its function.
+    ///
+    /// Observers see an instruction which blocks on `receive` only once:
+    /// `before_instruction` is called for the first attempt and
+    /// `after_instruction` once it gets a message, but neither is called
+    /// for the attempts in between.
+    pub fn step(&mut self) {
+        self.suspended = false;
+        let retry = self.is_waiting();
+        self.stop_receiving();
+        let ip = self.ip;
+        self.instruction_ip = ip;
+        let op_code = self.code.code[ip];
+        let arity = self.code.code[ip + 1];
 
-            let instr = self
-                .instruction_table
-                .by_op_code(op_code)
-                .unwrap_or_else(|| panic!("Unable to find instruction with op code {}", op_code));
+        let fun = self
+            .instruction_table
+            .by_op_code(op_code)
+            .unwrap_or_else(|| {
+                self.fail(format!("Unable to find instruction with op code {}", op_code))
+            })
+            .fun;
 
-            let mut args: Vec<usize> = vec![];
+        let args: Vec<usize> = self.code.code[ip + 2..ip + 2 + arity].to_vec();
 
-            for _i in 0..arity {
-                args.push(self.next_code());
+        if !self.observers.is_empty() && !retry {
+            self.notify(|observer, machine| {
+                observer.before_instruction(machine, ip, op_code, &args)
+            });
+            if self.suspended {
+                return;
             }
+        }
+
+        self.ip = ip + 2 + arity;
+        fun(self, args.as_slice());
+        if !self.is_waiting() {
+            self.executed += 1;
+        }
 
-            let fun = instr.fun;
-            fun(self, args.as_slice());
+        if !self.observers.is_empty() && !self.is_waiting() {
+            self.notify(|observer, machine| {
+                observer.after_instruction(machine, ip, op_code, &args)
+            });
         }
     }
 
-    /// Retrieve the next instruction of the program and increment
-    /// the instruction pointer.
-    #[inline]
-    fn next_code(&mut self) -> usize {
-        let code = self.code.code[self.ip];
-        self.ip += 1;
-        code
+    /// Run the machine like `run`, but turn a panic into a `RuntimeError`
+    /// which says where the machine was when it happened.
+    ///
+    /// This catches panics raised by your instructions as well as by the
+    /// machine itself.  The machine may be left part way through an
+    /// instruction, so you should only use it to investigate the error
+    /// afterwards.
+    pub fn try_run(&mut self) -> Result<(), RuntimeError> {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.run()));
+        result.map_err(|payload| {
+            let message = payload
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_else(|| "Unknown error".to_string());
+            // Our own panics already carry a backtrace.
+            let heading = format!("\n{}", backtrace::HEADING);
+            let message = match message.find(&heading) {
+                Some(index) => message[..index].to_string(),
+                None => message,
+            };
+            RuntimeError {
+                message,
+                backtrace: self.backtrace_at(self.instruction_ip),
+            }
+        })
+    }
+
+    /// Returns the function containing the current instruction, if it was
+    /// defined with `Builder::function`.
+    pub fn current_function(&self) -> Option<&Function> {
+        self.code.function_for_ip(self.ip)
+    }
+
+    fn backtrace_at(&self, ip: usize) -> Backtrace {
+        let return_addresses: Vec<usize> = self
+            .call_stack
+            .as_slice()
+            .iter()
+            .map(|frame| frame.return_address)
+            .collect();
+        Backtrace::new(&self.code, ip, &return_addresses)
+    }
+
+    /// Panic with a backtrace of the instruction being executed.
+    fn fail(&self, message: String) -> ! {
+        panic!("{}\n{}", message, self.backtrace_at(self.instruction_ip))
+    }
+
+    /// Returns `true` when the instruction pointer has reached the end of the
+    /// code, i.e. there is nothing left to execute.
+    pub fn is_finished(&self) -> bool {
+        self.ip == self.code.code.len()
+    }
+
+    /// The address of the instruction which is currently executing (or was
+    /// most recently executed).
+    pub fn instruction_ip(&self) -> usize {
+        self.instruction_ip
+    }
+
+    /// Suspend the machine.
+    ///
+    /// The machine stops executing after the current instruction.  Calling
+    /// `run` again carries on from the next instruction.
+    pub fn suspend(&mut self) {
+        self.suspended = true;
     }
 
     /// Look up a local variable in the current call frame.
@@ -145,7 +322,7 @@ impl<'a, T: 'a + fmt::Debug> Machine<'a, T> {
         self.code
             .data
             .get(idx)
-            .unwrap_or_else(|| panic!("Constant data is not present at index {}.", idx))
+            .unwrap_or_else(|| self.fail(format!("Constant data is not present at index {}.", idx)))
     }
 
     /// Perform a jump to a named label.
@@ -159,25 +336,70 @@ impl<'a, T: 'a + fmt::Debug> Machine<'a, T> {
         self.ip = self
             .code
             .get_label_ip(label)
-            .unwrap_or_else(|| panic!("Attempted to jump to unknown label {}", label));
+            .unwrap_or_else(|| self.fail(format!("Attempted to jump to unknown label {}", label)));
+        if !self.observers.is_empty() {
+            let ip = self.ip;
+            self.notify(|observer, machine| observer.on_jump(machine, label, ip));
+        }
     }
 
     /// Performs a call to a named label.
     ///
-    /// This method is very similar to `jump` except that it records it's
+    /// This method is very similar to `jump` except that it records its
     /// current instruction pointer and saves it in the call stack.
     ///
     /// This method performs the following actions:
-    /// * Create a new frame with it's return address set to the current
+    /// * Create a new frame with its return address set to the current
     ///   instruction pointer.
     /// * Jump to the named label using `jump`.
     ///
     /// This method specifically does not transfer operands to call arguments.
     pub fn call(&mut self, label: &str) {
-        self.call_stack.push(Frame::new(self.ip));
+        let return_address = self.ip;
+        self.call_stack.push(Frame::new(return_address));
+        if !self.observers.is_empty() {
+            self.notify(|observer, machine| observer.on_call(machine, label, return_address));
+        }
         self.jump(label);
     }
 
+    /// Perform a jump to an address.
+    ///
+    /// Use this with arguments declared as `ArgKind::Label`, which hold the
+    /// address of their label.  Observers are told the name of the label at
+    /// the address, or the address itself if there isn't one.
+    pub fn jump_ip(&mut self, ip: usize) {
+        if ip > self.code.code.len() {
+            self.fail(format!("Attempted to jump to address {} outside the code", ip));
+        }
+        self.ip = ip;
+        if !self.observers.is_empty() {
+            let label = self.label_at(ip);
+            self.notify(|observer, machine| observer.on_jump(machine, &label, ip));
+        }
+    }
+
+    /// Performs a call to an address.
+    ///
+    /// This is `call` for arguments declared as `ArgKind::Label`.
+    pub fn call_ip(&mut self, ip: usize) {
+        let return_address = self.ip;
+        self.call_stack.push(Frame::new(return_address));
+        if !self.observers.is_empty() {
+            let label = self.label_at(ip);
+            self.notify(|observer, machine| observer.on_call(machine, &label, return_address));
+        }
+        self.jump_ip(ip);
+    }
+
+    /// The name of the label at exactly `ip`, or `ip` itself.
+    fn label_at(&self, ip: usize) -> String {
+        match self.code.labels.iter().find(|label| label.0 == ip) {
+            Some((_ip, name)) => name.clone(),
+            None => ip.to_string(),
+        }
+    }
+
     /// Performs a return.
     ///
     /// This method pops the top frame off the call stack and moves the
@@ -194,6 +416,503 @@ impl<'a, T: 'a + fmt::Debug> Machine<'a, T> {
     pub fn ret(&mut self) {
         let frame = self.call_stack.pop();
         self.ip = frame.return_address;
+        if !self.observers.is_empty() {
+            let return_address = self.ip;
+            self.notify(|observer, machine| observer.on_ret(machine, return_address));
+        }
+    }
+
+    /// Register an observer to be notified as the machine executes.
+    ///
+    /// Keep a clone of the `Arc` to read any results the observer collects.
+    pub fn observe(&mut self, observer: SharedObserver<T>) {
+        self.observers.push(observer);
+    }
+
+    /// Start tracing execution to a writer.
+    ///
+    /// Registers a `Tracer` as an observer and returns it so that you can
+    /// retrieve the writer afterwards.  See the `trace` module for details.
+    pub fn trace<W: Write + Send + 'static>(&mut self, writer: W) -> Arc<Mutex<Tracer<W>>> {
+        let tracer = Arc::new(Mutex::new(Tracer::new(writer)));
+        self.observe(tracer.clone());
+        tracer
+    }
+
+    /// Start profiling execution.
+    ///
+    /// Registers a `Profiler` as an observer and returns it so that you can
+    /// read the statistics afterwards.  See the `profiler` module for
+    /// details.
+    pub fn profile(&mut self) -> Arc<Mutex<Profiler>> {
+        let profiler = Arc::new(Mutex::new(Profiler::new()));
+        self.observe(profiler.clone());
+        profiler
+    }
+
+    /// Start collecting code coverage.
+    ///
+    /// Registers a `Coverage` as an observer and returns it so that you can
+    /// read the coverage afterwards.  See the `coverage` module for details.
+    pub fn cover(&mut self) -> Arc<Mutex<Coverage>> {
+        let coverage = Arc::new(Mutex::new(Coverage::new()));
+        self.observe(coverage.clone());
+        coverage
+    }
+
+    /// Remove all registered observers.
+    pub fn clear_observers(&mut self) {
+        self.observers.clear();
+    }
+
+    fn notify<F>(&mut self, mut callback: F)
+    where
+        F: FnMut(&mut dyn Observer<T>, &mut Machine<'a, T>),
+    {
+        let observers = mem::take(&mut self.observers);
+        for observer in &observers {
+            let mut observer = observer.lock().unwrap();
+            callback(&mut *observer, self);
+        }
+        let added = mem::replace(&mut self.observers, observers);
+        self.observers.extend(added);
+    }
+
+    fn halt(&mut self) {
+        if !self.observers.is_empty() {
+            self.notify(|observer, machine| observer.on_halt(machine));
+        }
+    }
+
+    /// Create a new coroutine starting at a named label.
+    ///
+    /// The coroutine does not start running until it is first resumed.  When
+    /// it returns from its outermost frame it is finished.
+    ///
+    /// This method will panic the thread if the label does not exist.
+    pub fn coroutine(&mut self, label: &str) -> CoroutineId {
+        let ip = self
+            .code
+            .get_label_ip(label)
+            .unwrap_or_else(|| {
+                self.fail(format!("Attempted to start coroutine at unknown label {}", label))
+            });
+        self.coroutines
+            .push(Some(Coroutine::new(ip, self.code.code.len())));
+        self.coroutines.len() - 1
+    }
+
+    /// Resume a coroutine.
+    ///
+    /// Swaps the coroutine's instruction pointer, call stack and operand
+    /// stack into the machine and runs it until it either yields or finishes.
+    /// Returns the yielded value, or `None` if the coroutine finished (or was
+    /// suspended without yielding anything).
+    ///
+    /// This may be called by the host, or from within an instruction in which
+    /// case the yielded value is returned to the calling script.
+    ///
+    /// This method will panic the thread if the coroutine does not exist or
+    /// if it is already running (i.e. a coroutine attempts to resume itself).
+    pub fn resume(&mut self, id: CoroutineId) -> Option<T> {
+        let mut coroutine = self
+            .coroutines
+            .get_mut(id)
+            .unwrap_or_else(|| panic!("Attempted to resume unknown coroutine {}", id))
+            .take()
+            .unwrap_or_else(|| panic!("Coroutine {} is already running", id));
+
+        if coroutine.is_finished() {
+            self.coroutines[id] = Some(coroutine);
+            return None;
+        }
+
+        let parent_yield = self.yielded.take();
+        let parent_suspended = self.suspended;
+        self.swap_state(&mut coroutine);
+        self.coroutine_depth += 1;
+        self.run();
+        self.coroutine_depth -= 1;
+        if self.is_finished() && !self.suspended {
+            coroutine.finish();
+        }
+        self.swap_state(&mut coroutine);
+        let result = mem::replace(&mut self.yielded, parent_yield);
+        self.suspended = parent_suspended;
+
+        self.coroutines[id] = Some(coroutine);
+        result
+    }
+
+    /// Yield a value from the currently running coroutine.
+    ///
+    /// The machine stops executing after the current instruction and the
+    /// value is returned from `resume`.  Yielding from the main program
+    /// stops `run`; the value can be retrieved with `take_yielded` and
+    /// calling `run` again carries on from the next instruction.
+    pub fn yield_value(&mut self, value: T) {
+        self.yielded = Some(value);
+        self.suspend();
+    }
+
+    /// Take the value yielded by the main program, if any.
+    pub fn take_yielded(&mut self) -> Option<T> {
+        self.yielded.take()
+    }
+
+    /// Returns `true` if the coroutine has run to completion.
+    pub fn is_coroutine_finished(&self, id: CoroutineId) -> bool {
+        match self.coroutines.get(id) {
+            Some(Some(coroutine)) => coroutine.is_finished(),
+            Some(None) => false,
+            None => panic!("Unknown coroutine {}", id),
+        }
+    }
+
+    /// Start a coroutine at a named label and iterate over the values it
+    /// yields.
+    pub fn generator<'m>(&'m mut self, label: &str) -> Generator<'m, 'a, T> {
+        let id = self.coroutine(label);
+        Generator::new(self, id)
+    }
+
+    /// Spawn a new green thread starting at a named label.
+    ///
+    /// The thread does not run until the machine is run with `run_threads`.
+    ///
+    /// This method will panic the thread if the label does not exist.
+    pub fn spawn(&mut self, label: &str) -> ThreadId {
+        let ip = self
+            .code
+            .get_label_ip(label)
+            .unwrap_or_else(|| {
+                self.fail(format!("Attempted to spawn thread at unknown label {}", label))
+            });
+        let context = Coroutine::new(ip, self.code.code.len());
+        self.scheduler.spawn(context)
+    }
+
+    /// Wait for another thread to finish.
+    ///
+    /// Returns `true` if the thread has already finished.  Otherwise the
+    /// current thread is suspended until it has, and execution carries on
+    /// from the next instruction once it is rescheduled.
+    pub fn join(&mut self, id: ThreadId) -> bool {
+        let current = self.scheduler.current();
+        if id == current {
+            panic!("Thread {} attempted to join itself", id);
+        }
+        if self.scheduler.state(id) == ThreadState::Finished {
+            return true;
+        }
+        self.scheduler.set_state(current, ThreadState::Joining(id));
+        self.suspend();
+        false
+    }
+
+    /// Put the current thread to sleep for a number of scheduling rounds.
+    ///
+    /// Sleeping for zero rounds simply gives up the rest of the current time
+    /// slice.
+    pub fn sleep(&mut self, rounds: usize) {
+        let current = self.scheduler.current();
+        let until = self.scheduler.round() + rounds;
+        self.scheduler
+            .set_state(current, ThreadState::Sleeping(until));
+        self.suspend();
+    }
+
+    /// The handle of the currently running thread.
+    pub fn current_thread(&self) -> ThreadId {
+        self.scheduler.current()
+    }
+
+    /// Returns `true` if the thread has run to completion.
+    pub fn is_thread_finished(&self, id: ThreadId) -> bool {
+        self.scheduler.state(id) == ThreadState::Finished
+    }
+
+    /// Retrieve the machine's thread scheduler.
+    pub fn scheduler(&self) -> &Scheduler<T> {
+        &self.scheduler
+    }
+
+    /// Run all threads until they have finished.
+    ///
+    /// Threads are given time slices in round-robin order, with each slice
+    /// executing at most `budget` instructions.  Returns `true` once every
+    /// thread has finished, in which case the main thread's state is swapped
+    /// back into the machine.
+    ///
+    /// Returns `false` if every unfinished thread is waiting, and at least
+    /// one of them is waiting for a message.  Another machine on the same
+    /// `Router` may still send it, so call `run_threads` again once it has.
+    ///
+    /// This method will panic the thread if every unfinished thread is
+    /// waiting to join another, as none of them can ever make progress.
+    pub fn run_threads(&mut self, budget: usize) -> bool {
+        let mut idle = 0;
+        loop {
+            let current = self.scheduler.current();
+            if self.scheduler.is_runnable(current) || self.has_message(current) {
+                idle = 0;
+                self.suspended = false;
+                for _i in 0..budget {
+                    if self.is_finished() || self.suspended {
+                        break;
+                    }
+                    self.step();
+                }
+                if self.is_finished() && !self.suspended {
+                    self.scheduler.set_state(current, ThreadState::Finished);
+                }
+            } else {
+                idle += 1;
+            }
+
+            if self.scheduler.all_finished() {
+                break;
+            }
+            if idle > self.scheduler.len() && !self.scheduler.any_sleeping() {
+                if self.scheduler.any_receiving() {
+                    return false;
+                }
+                panic!("Deadlock: every thread is waiting to join another");
+            }
+
+            let (previous, next) = self.scheduler.advance();
+            self.switch_thread(previous, next);
+        }
+
+        let previous = self.scheduler.set_current(0);
+        self.switch_thread(previous, 0);
+        self.halt();
+        true
+    }
+
+    /// Connect this machine to a `Router` so that it can send and receive
+    /// messages.
+    pub fn connect(&mut self, router: &Router<T>) {
+        self.router = Some(router.clone());
+    }
+
+    /// Send an operand to a named mailbox.
+    ///
+    /// This method will panic the thread if the machine is not connected to
+    /// a router.
+    pub fn send(&mut self, mailbox: &str, value: T) {
+        self.router().send(mailbox, value);
+    }
+
+    /// Receive an operand from a named mailbox.
+    ///
+    /// If a message is waiting it is returned straight away.  Otherwise the
+    /// machine rewinds to the start of the current instruction, suspends and
+    /// returns `None`; the instruction will be executed again the next time
+    /// the machine runs.  Because of this your instruction should call
+    /// `receive` before making any other changes to the machine and return
+    /// immediately when it gets `None`.
+    ///
+    /// This method will panic the thread if the machine is not connected to
+    /// a router.
+    pub fn receive(&mut self, mailbox: &str) -> Option<T> {
+        let message = self.router().receive(mailbox);
+        if message.is_none() {
+            self.ip = self.instruction_ip;
+            let current = self.scheduler.current();
+            self.scheduler
+                .set_state(current, ThreadState::Receiving(mailbox.to_string()));
+            self.suspend();
+        }
+        message
+    }
+
+    /// The number of instructions the machine has executed, not counting
+    /// receives which found their mailbox empty.
+    pub fn executed(&self) -> usize {
+        self.executed
+    }
+
+    /// Returns `true` if the current thread is suspended waiting for a
+    /// message.
+    pub fn is_waiting(&self) -> bool {
+        let current = self.scheduler.current();
+        matches!(self.scheduler.state(current), ThreadState::Receiving(_))
+    }
+
+    /// Returns `true` if a thread is waiting for a message which has
+    /// arrived.  The thread stays `Receiving` until it retries, so that the
+    /// retry isn't reported to observers as a new instruction.
+    fn has_message(&self, id: ThreadId) -> bool {
+        match self.scheduler.state(id) {
+            ThreadState::Receiving(mailbox) => !self.router().mailbox(&mailbox).is_empty(),
+            _ => false,
+        }
+    }
+
+    /// Forget that the current thread was waiting for a message.
+    fn stop_receiving(&mut self) {
+        if self.is_waiting() {
+            let current = self.scheduler.current();
+            self.scheduler.set_state(current, ThreadState::Runnable);
+        }
+    }
+
+    fn router(&self) -> &Router<T> {
+        self.router
+            .as_ref()
+            .expect("Machine is not connected to a router")
+    }
+
+    fn switch_thread(&mut self, from: ThreadId, to: ThreadId) {
+        if from == to {
+            return;
+        }
+        let mut context = self.scheduler.take_context(to);
+        self.swap_state(&mut context);
+        self.scheduler.put_context(from, context);
+    }
+
+    fn swap_state(&mut self, coroutine: &mut Coroutine<T>) {
+        mem::swap(&mut self.ip, &mut coroutine.ip);
+        mem::swap(&mut self.call_stack, &mut coroutine.call_stack);
+        mem::swap(&mut self.operand_stack, &mut coroutine.operand_stack);
+    }
+}
+
+impl<'a, T: 'a + fmt::Debug + Clone> Machine<'a, T> {
+    /// Start debugging.
+    ///
+    /// Registers a `Debugger` as an observer and returns it so that you can
+    /// set breakpoints and watchpoints.  See the `debugger` module for
+    /// details.
+    pub fn debug(&mut self) -> Arc<Mutex<Debugger<T>>>
+    where
+        T: PartialEq + Send + 'static,
+    {
+        let debugger = Arc::new(Mutex::new(Debugger::new()));
+        self.observe(debugger.clone());
+        debugger
+    }
+
+    /// Describe the call stack, innermost frame first.
+    ///
+    /// Each frame has the `Location` of the code it's executing: the
+    /// current instruction for the innermost frame, or the return address
+    /// of the call it's waiting on for the others.  See the `backtrace`
+    /// module for details.
+    pub fn backtrace(&self) -> Vec<StackFrame<T>> {
+        let frames = self.call_stack.as_slice();
+        let mut location = Location::new(&self.code, self.ip);
+        let mut backtrace = vec![];
+        for frame in frames.iter().rev() {
+            let locals = frame.locals();
+            backtrace.push(StackFrame {
+                location,
+                return_address: frame.return_address,
+                locals: locals
+                    .keys()
+                    .into_iter()
+                    .map(|name| {
+                        let value = locals.get(&name).unwrap().clone();
+                        (name, value)
+                    })
+                    .collect(),
+            });
+            location = Location::of_return(&self.code, frame.return_address);
+        }
+        backtrace
+    }
+
+    /// Take a snapshot of the machine's execution state.
+    ///
+    /// The snapshot contains copies of the instruction pointer, the call
+    /// stack and the operand stack, along with the fingerprint of the code
+    /// being executed.  See the `snapshot` module for more details.
+    pub fn snapshot(&self) -> Snapshot<T> {
+        Snapshot {
+            fingerprint: self.code.fingerprint(),
+            ip: self.ip,
+            call_stack: self.call_stack.as_slice().to_vec(),
+            operand_stack: self.operand_stack.as_slice().to_vec(),
+        }
+    }
+
+    /// Fork the machine.
+    ///
+    /// Returns an independent copy of the machine's instruction pointer,
+    /// call stack, operand stack, coroutines and threads.  The code,
+    /// constants and instruction table are shared with the original rather
+    /// than copied, which makes forking cheap enough to use for speculative
+    /// execution.
+    ///
+    /// A fork stays connected to the same `Router` as the original, but gets
+    /// a new, empty `World` and has no observers.
+    pub fn fork(&self) -> Machine<'a, T> {
+        Machine {
+            code: self.code.clone(),
+            instruction_table: self.instruction_table.clone(),
+            ip: self.ip,
+            constants: self.constants.clone(),
+            call_stack: self.call_stack.clone(),
+            operand_stack: self.operand_stack.clone(),
+            world: World::new(),
+            coroutines: self.coroutines.clone(),
+            yielded: self.yielded.clone(),
+            scheduler: self.scheduler.clone(),
+            suspended: self.suspended,
+            router: self.router.clone(),
+            instruction_ip: self.instruction_ip,
+            observers: vec![],
+            coroutine_depth: self.coroutine_depth,
+            executed: self.executed,
+        }
+    }
+}
+
+impl<'a, T: 'a + fmt::Debug> Machine<'a, T> {
+    /// Restore the machine's execution state from a snapshot.
+    ///
+    /// The snapshot is validated against the machine's code first: it must
+    /// have been taken from code with the same fingerprint, and the
+    /// instruction pointer and every return address must be the address of
+    /// an instruction (or the end of the code).  On success the machine's
+    /// instruction pointer, call stack and operand stack are replaced and
+    /// `run` will carry on from where the snapshot was taken.
+    pub fn restore(&mut self, snapshot: Snapshot<T>) -> Result<(), RestoreError> {
+        let fingerprint = self.code.fingerprint();
+        if snapshot.fingerprint != fingerprint {
+            return Err(RestoreError::CodeMismatch {
+                expected: fingerprint,
+                actual: snapshot.fingerprint,
+            });
+        }
+        if snapshot.call_stack.is_empty() {
+            return Err(RestoreError::EmptyCallStack);
+        }
+        if !self.code.is_instruction_boundary(snapshot.ip) {
+            return Err(RestoreError::InvalidAddress(snapshot.ip));
+        }
+        if let Some(frame) = snapshot
+            .call_stack
+            .iter()
+            .find(|frame| !self.code.is_instruction_boundary(frame.return_address))
+        {
+            return Err(RestoreError::InvalidAddress(frame.return_address));
+        }
+
+        self.ip = snapshot.ip;
+        self.call_stack = Stack::new();
+        for frame in snapshot.call_stack {
+            self.call_stack.push(frame);
+        }
+        self.operand_stack = Stack::new();
+        for operand in snapshot.operand_stack {
+            self.operand_stack.push(operand);
+        }
+        self.suspended = false;
+        self.stop_receiving();
+        Ok(())
     }
 }
 
@@ -234,6 +953,121 @@ mod test {
         assert!(machine.operand_stack.is_empty());
     }
 
+    #[test]
+    fn try_run() {
+        let it = instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("push", vec![2]);
+        builder.label("sum");
+        builder.push("add", vec![]);
+        let constants: WriteManyTable<usize> = WriteManyTable::new();
+        let mut machine = Machine::new(Code::from(builder), &constants, &it);
+        let error = machine.try_run().unwrap_err();
+        assert_eq!(error.message, "Unable to pop from empty stack!");
+        assert_eq!(error.backtrace.to_string(), "stack-vm backtrace:\n  0: sum+0\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "Attempted to jump to unknown label nowhere\nstack-vm backtrace:\n  0: main+0")]
+    fn jump_panics_with_backtrace() {
+        let it = instruction_table();
+        let builder: Builder<usize> = Builder::new(&it);
+        let constants: WriteManyTable<usize> = WriteManyTable::new();
+        let mut machine = Machine::new(Code::from(builder), &constants, &it);
+        machine.jump("nowhere");
+    }
+
+    #[test]
+    fn backtrace() {
+        let it = instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("push", vec![2]);
+        builder.label("function");
+        builder.push("push", vec![3]);
+        let constants: WriteManyTable<usize> = WriteManyTable::new();
+        let mut machine = Machine::new(Code::from(builder), &constants, &it);
+        machine.call("function");
+        let backtrace = machine.backtrace();
+        assert_eq!(backtrace.len(), 2);
+        assert_eq!(backtrace[0].location.to_string(), "function+0");
+        assert_eq!(backtrace[1].location.to_string(), "main+0");
+    }
+
+    #[test]
+    fn jump_ip_and_call_ip() {
+        let it = instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("push", vec![2]);
+        builder.label("function");
+        builder.push("push", vec![3]);
+        let constants: WriteManyTable<usize> = WriteManyTable::new();
+        let mut machine = Machine::new(Code::from(builder), &constants, &it);
+        machine.jump_ip(3);
+        assert_eq!(machine.ip, 3);
+        machine.call_ip(0);
+        assert_eq!(machine.ip, 0);
+        assert_eq!(machine.call_stack.len(), 2);
+        assert_eq!(machine.backtrace()[1].location.to_string(), "main+3");
+    }
+
+    #[test]
+    #[should_panic(expected = "Attempted to jump to address 7 outside the code")]
+    fn jump_ip_outside_code() {
+        let it = instruction_table();
+        let builder: Builder<usize> = Builder::new(&it);
+        let constants: WriteManyTable<usize> = WriteManyTable::new();
+        let mut machine = Machine::new(Code::from(builder), &constants, &it);
+        machine.jump_ip(7);
+    }
+
+    #[test]
+    fn new_with_shared_code() {
+        let it = instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("push", vec![2]);
+        builder.push("push", vec![3]);
+        builder.push("add", vec![]);
+        let code = Arc::new(Code::from(builder));
+        let constants: WriteManyTable<usize> = WriteManyTable::new();
+
+        let mut machines: Vec<Machine<usize>> = (0..10)
+            .map(|_| Machine::new(code.clone(), &constants, &it))
+            .collect();
+        assert_eq!(Arc::strong_count(&code), 11);
+        for machine in machines.iter_mut() {
+            machine.run();
+            assert_eq!(machine.operand_pop(), 5);
+        }
+    }
+
+    #[test]
+    fn shared_code_across_threads() {
+        let it = instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("push", vec![2]);
+        builder.push("push", vec![3]);
+        builder.push("add", vec![]);
+        let code = Arc::new(Code::from(builder));
+        let constants: WriteManyTable<usize> = WriteManyTable::new();
+
+        let results: Vec<usize> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    let code = code.clone();
+                    let constants = &constants;
+                    let it = &it;
+                    scope.spawn(move || {
+                        let mut machine = Machine::new(code, constants, it);
+                        machine.run();
+                        machine.operand_pop()
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert_eq!(results, [5, 5, 5, 5]);
+    }
+
     #[test]
     fn run() {
         let it = instruction_table();
@@ -279,6 +1113,110 @@ mod test {
         assert!(machine.get_local_deep("inner").is_none());
     }
 
+    #[test]
+    fn snapshot_and_restore() {
+        let it = instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("push", vec![2]);
+        builder.push("push", vec![3]);
+        builder.push("add", vec![]);
+        let constants: WriteManyTable<usize> = WriteManyTable::new();
+        let mut machine = Machine::new(Code::from(builder), &constants, &it);
+        machine.step();
+        machine.set_local("example", 13);
+        let snapshot = machine.snapshot();
+        machine.run();
+        assert_eq!(machine.operand_pop(), 5);
+
+        machine.restore(snapshot).unwrap();
+        assert_eq!(machine.ip, 3);
+        assert_eq!(*machine.get_local("example").unwrap(), 13);
+        assert_eq!(machine.operand_stack.as_slice(), [2]);
+        machine.run();
+        assert_eq!(machine.operand_pop(), 5);
+    }
+
+    #[test]
+    fn restore_rejects_other_code() {
+        let it = instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("push", vec![2]);
+        let constants: WriteManyTable<usize> = WriteManyTable::new();
+        let machine = Machine::new(Code::from(builder), &constants, &it);
+        let snapshot = machine.snapshot();
+
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("add", vec![]);
+        let mut machine = Machine::new(Code::from(builder), &constants, &it);
+        match machine.restore(snapshot) {
+            Err(RestoreError::CodeMismatch { .. }) => (),
+            other => panic!("Expected a code mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn restore_rejects_invalid_address() {
+        let it = instruction_table();
+        let builder: Builder<usize> = Builder::new(&it);
+        let constants: WriteManyTable<usize> = WriteManyTable::new();
+        let mut machine = Machine::new(Code::from(builder), &constants, &it);
+        let mut snapshot = machine.snapshot();
+        snapshot.ip = 7;
+        assert_eq!(
+            machine.restore(snapshot),
+            Err(RestoreError::InvalidAddress(7))
+        );
+    }
+
+    #[test]
+    fn restore_rejects_address_inside_instruction() {
+        let it = instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("push", vec![2]);
+        builder.push("add", vec![]);
+        let constants: WriteManyTable<usize> = WriteManyTable::new();
+        let mut machine = Machine::new(Code::from(builder), &constants, &it);
+
+        let mut snapshot = machine.snapshot();
+        snapshot.ip = 2;
+        assert_eq!(
+            machine.restore(snapshot),
+            Err(RestoreError::InvalidAddress(2))
+        );
+
+        let mut snapshot = machine.snapshot();
+        snapshot.call_stack[0].return_address = 1;
+        assert_eq!(
+            machine.restore(snapshot),
+            Err(RestoreError::InvalidAddress(1))
+        );
+    }
+
+    #[test]
+    fn fork() {
+        let it = instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("push", vec![2]);
+        builder.push("push", vec![3]);
+        builder.push("add", vec![]);
+        let constants: WriteManyTable<usize> = WriteManyTable::new();
+        let mut machine = Machine::new(Code::from(builder), &constants, &it);
+        machine.step();
+        machine.set_local("example", 13);
+
+        let mut fork = machine.fork();
+        assert!(Arc::ptr_eq(&machine.code, &fork.code));
+        fork.set_local("example", 14);
+        fork.operand_push(10);
+        fork.run();
+        assert_eq!(fork.operand_pop(), 13);
+
+        assert_eq!(machine.ip, 3);
+        assert_eq!(*machine.get_local("example").unwrap(), 13);
+        machine.run();
+        assert_eq!(machine.operand_pop(), 5);
+    }
+
     #[test]
     fn set_local() {
         let it = instruction_table();
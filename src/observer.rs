@@ -0,0 +1,293 @@
+//! Execution observers.
+//!
+//! An observer is notified as the machine executes, which makes it possible
+//! to collect metrics or enforce policies without changing any of your
+//! instructions.  Observers are registered with `Machine::observe` and are
+//! called:
+//!
+//! * before and after every instruction,
+//! * whenever the machine calls, returns or jumps, and
+//! * when the machine halts because it has run out of code.
+//!
+//! Every callback receives the machine, so an observer can inspect its
+//! state or suspend it.  Suspending the machine from `before_instruction`
+//! stops the instruction from being executed.
+//!
+//! Observers are shared with the machine through an `Arc<Mutex<_>>`, so the
+//! host can keep hold of its own handle to read any results afterwards.
+//! When no observers are registered the machine skips all of this.
+//!
+//! ## Examples
+//!
+//! ```
+//! use stack_vm::{Instruction, InstructionTable, Builder, Code, Machine, Observer, WriteManyTable};
+//! use std::sync::{Arc, Mutex};
+//!
+//! #[derive(Default)]
+//! struct Counter(usize);
+//!
+//! impl Observer<i64> for Counter {
+//!     fn after_instruction(&mut self, _machine: &mut Machine<i64>, _ip: usize, _op_code: usize, _args: &[usize]) {
+//!         self.0 += 1;
+//!     }
+//! }
+//!
+//! fn push(machine: &mut Machine<i64>, args: &[usize]) {
+//!     let arg = *machine.get_data(args[0]);
+//!     machine.operand_push(arg);
+//! }
+//!
+//! let mut instruction_table = InstructionTable::new();
+//! instruction_table.insert(Instruction::new(0, "push", 1, push));
+//! let mut builder: Builder<i64> = Builder::new(&instruction_table);
+//! builder.push("push", vec![1]);
+//! builder.push("push", vec![2]);
+//!
+//! let constants: WriteManyTable<i64> = WriteManyTable::new();
+//! let mut machine = Machine::new(Code::from(builder), &constants, &instruction_table);
+//! let counter = Arc::new(Mutex::new(Counter::default()));
+//! machine.observe(counter.clone());
+//! machine.run();
+//! assert_eq!(counter.lock().unwrap().0, 2);
+//! ```
+
+use crate::machine::Machine;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Callbacks made by the `Machine` as it executes.
+///
+/// Every method has an empty default implementation, so you only need to
+/// implement the ones you're interested in.
+pub trait Observer<T: fmt::Debug>: Send {
+    /// Called before the instruction at `ip` is executed.
+    ///
+    /// If the observer suspends the machine then the instruction is not
+    /// executed and the instruction pointer is left at `ip`.
+    fn before_instruction(
+        &mut self,
+        _machine: &mut Machine<T>,
+        _ip: usize,
+        _op_code: usize,
+        _args: &[usize],
+    ) {
+    }
+
+    /// Called after the instruction at `ip` has been executed.
+    ///
+    /// An instruction which blocks on `Machine::receive` has only been
+    /// executed once it gets a message, so the attempts which block are
+    /// not reported.
+    fn after_instruction(
+        &mut self,
+        _machine: &mut Machine<T>,
+        _ip: usize,
+        _op_code: usize,
+        _args: &[usize],
+    ) {
+    }
+
+    /// Called when the machine calls a label, after the new frame has been
+    /// pushed and before jumping to the label.
+    fn on_call(&mut self, _machine: &mut Machine<T>, _label: &str, _return_address: usize) {}
+
+    /// Called when the machine returns, after the frame has been popped.
+    fn on_ret(&mut self, _machine: &mut Machine<T>, _return_address: usize) {}
+
+    /// Called when the machine jumps to a label, after the instruction
+    /// pointer has been moved.  Calls also trigger this callback.
+    fn on_jump(&mut self, _machine: &mut Machine<T>, _label: &str, _ip: usize) {}
+
+    /// Called when the machine stops because there's no code left to
+    /// execute.
+    fn on_halt(&mut self, _machine: &mut Machine<T>) {}
+}
+
+/// An observer which has been registered with a `Machine`.
+pub type SharedObserver<T> = Arc<Mutex<dyn Observer<T>>>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builder::Builder;
+    use crate::channel::Router;
+    use crate::code::Code;
+    use crate::instruction::Instruction;
+    use crate::instruction_table::InstructionTable;
+    use crate::write_many_table::WriteManyTable;
+
+    #[derive(Default)]
+    struct Recorder(Vec<String>);
+
+    impl Observer<usize> for Recorder {
+        fn before_instruction(
+            &mut self,
+            _machine: &mut Machine<usize>,
+            ip: usize,
+            op_code: usize,
+            args: &[usize],
+        ) {
+            self.0.push(format!("before {} {} {:?}", ip, op_code, args));
+        }
+
+        fn after_instruction(
+            &mut self,
+            machine: &mut Machine<usize>,
+            ip: usize,
+            _op_code: usize,
+            _args: &[usize],
+        ) {
+            self.0.push(format!("after {} -> {}", ip, machine.ip));
+        }
+
+        fn on_call(&mut self, _machine: &mut Machine<usize>, label: &str, return_address: usize) {
+            self.0.push(format!("call {} {}", label, return_address));
+        }
+
+        fn on_ret(&mut self, _machine: &mut Machine<usize>, return_address: usize) {
+            self.0.push(format!("ret {}", return_address));
+        }
+
+        fn on_jump(&mut self, _machine: &mut Machine<usize>, label: &str, ip: usize) {
+            self.0.push(format!("jump {} {}", label, ip));
+        }
+
+        fn on_halt(&mut self, _machine: &mut Machine<usize>) {
+            self.0.push("halt".to_string());
+        }
+    }
+
+    /// Refuses to let the machine execute op code 2.
+    struct Policy;
+
+    impl Observer<usize> for Policy {
+        fn before_instruction(
+            &mut self,
+            machine: &mut Machine<usize>,
+            _ip: usize,
+            op_code: usize,
+            _args: &[usize],
+        ) {
+            if op_code == 2 {
+                machine.suspend();
+            }
+        }
+    }
+
+    fn push(machine: &mut Machine<usize>, args: &[usize]) {
+        let arg = *machine.get_data(args[0]);
+        machine.operand_push(arg);
+    }
+
+    fn call(machine: &mut Machine<usize>, _args: &[usize]) {
+        machine.call("function");
+    }
+
+    fn ret(machine: &mut Machine<usize>, _args: &[usize]) {
+        machine.ret();
+    }
+
+    fn receive(machine: &mut Machine<usize>, _args: &[usize]) {
+        if let Some(value) = machine.receive("inbox") {
+            machine.operand_push(value);
+        }
+    }
+
+    fn instruction_table() -> InstructionTable<usize> {
+        let mut it = InstructionTable::new();
+        it.insert(Instruction::new(0, "push", 1, push));
+        it.insert(Instruction::new(1, "call", 0, call));
+        it.insert(Instruction::new(2, "ret", 0, ret));
+        it.insert(Instruction::new(3, "receive", 0, receive));
+        it
+    }
+
+    fn code(it: &InstructionTable<usize>) -> Code<usize> {
+        let mut builder: Builder<usize> = Builder::new(it);
+        builder.push("call", vec![]);
+        builder.push("ret", vec![]);
+        builder.label("function");
+        builder.push("push", vec![7]);
+        builder.push("ret", vec![]);
+        Code::from(builder)
+    }
+
+    #[test]
+    fn callbacks() {
+        let it = instruction_table();
+        let constants: WriteManyTable<usize> = WriteManyTable::new();
+        let mut machine = Machine::new(code(&it), &constants, &it);
+        let recorder = Arc::new(Mutex::new(Recorder::default()));
+        machine.observe(recorder.clone());
+        machine.run();
+
+        assert_eq!(
+            recorder.lock().unwrap().0,
+            [
+                "before 0 1 []",
+                "call function 2",
+                "jump function 4",
+                "after 0 -> 4",
+                "before 4 0 [0]",
+                "after 4 -> 7",
+                "before 7 2 []",
+                "ret 2",
+                "after 7 -> 2",
+                "before 2 2 []",
+                "ret 9",
+                "after 2 -> 9",
+                "halt",
+            ]
+        );
+    }
+
+    #[test]
+    fn blocked_receive_is_reported_once() {
+        let it = instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("receive", vec![]);
+        let constants: WriteManyTable<usize> = WriteManyTable::new();
+        let mut machine = Machine::new(Code::from(builder), &constants, &it);
+        let router = Router::new();
+        machine.connect(&router);
+        let recorder = Arc::new(Mutex::new(Recorder::default()));
+        machine.observe(recorder.clone());
+
+        machine.run();
+        machine.run();
+        assert!(machine.is_waiting());
+        router.send("inbox", 13);
+        machine.run();
+
+        assert_eq!(machine.operand_pop(), 13);
+        assert_eq!(
+            recorder.lock().unwrap().0,
+            ["before 0 3 []", "after 0 -> 2", "halt"]
+        );
+    }
+
+    #[test]
+    fn suspending_skips_instruction() {
+        let it = instruction_table();
+        let constants: WriteManyTable<usize> = WriteManyTable::new();
+        let mut machine = Machine::new(code(&it), &constants, &it);
+        machine.observe(Arc::new(Mutex::new(Policy)));
+        machine.run();
+
+        assert_eq!(machine.ip, 7);
+        assert_eq!(machine.operand_pop(), 7);
+        assert!(!machine.is_finished());
+    }
+
+    #[test]
+    fn clear_observers() {
+        let it = instruction_table();
+        let constants: WriteManyTable<usize> = WriteManyTable::new();
+        let mut machine = Machine::new(code(&it), &constants, &it);
+        let recorder = Arc::new(Mutex::new(Recorder::default()));
+        machine.observe(recorder.clone());
+        machine.clear_observers();
+        machine.run();
+        assert!(recorder.lock().unwrap().0.is_empty());
+    }
+}
@@ -0,0 +1,286 @@
+//! Instruction-level profiling.
+//!
+//! A `Profiler` is an `Observer` which works out where a program spends its
+//! time.  It records:
+//!
+//! * how many times each instruction was executed, and for how long,
+//! * how many instructions were executed inside each label, and for how
+//!   long, where an instruction belongs to the closest label before it,
+//! * how many times each label was called, and
+//! * how many instructions were executed under each distinct call stack.
+//!
+//! `Profiler::report` summarises the first three as a table, and
+//! `Profiler::write_folded` writes the call stacks in the "folded" format
+//! understood by flamegraph tools such as `inferno` and `flamegraph.pl`.
+//! Each line of the folded output is a stack of labels separated by
+//! semicolons followed by the number of instructions executed there:
+//!
+//! ```text
+//! main 3
+//! main;double 2
+//! ```
+//!
+//! The call stack is tracked from `Machine::call` and `Machine::ret`, so it
+//! doesn't follow switches between coroutines or green threads.
+//!
+//! ## Examples
+//!
+//! ```
+//! use stack_vm::{Instruction, InstructionTable, Builder, Code, Machine, WriteManyTable};
+//!
+//! fn push(machine: &mut Machine<i64>, args: &[usize]) {
+//!     let arg = *machine.get_data(args[0]);
+//!     machine.operand_push(arg);
+//! }
+//!
+//! let mut instruction_table = InstructionTable::new();
+//! instruction_table.insert(Instruction::new(0, "push", 1, push));
+//! let mut builder: Builder<i64> = Builder::new(&instruction_table);
+//! builder.push("push", vec![1]);
+//! builder.push("push", vec![2]);
+//!
+//! let constants: WriteManyTable<i64> = WriteManyTable::new();
+//! let mut machine = Machine::new(Code::from(builder), &constants, &instruction_table);
+//! let profiler = machine.profile();
+//! machine.run();
+//!
+//! let profiler = profiler.lock().unwrap();
+//! assert_eq!(profiler.op_code("push").unwrap().count, 2);
+//! assert_eq!(profiler.label("main").unwrap().count, 2);
+//!
+//! let mut folded: Vec<u8> = vec![];
+//! profiler.write_folded(&mut folded).unwrap();
+//! assert_eq!(String::from_utf8(folded).unwrap(), "main 2\n");
+//! ```
+
+use crate::machine::Machine;
+use crate::observer::Observer;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/// The number of executions and total time spent in one part of a program.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    pub count: usize,
+    pub time: Duration,
+}
+
+impl Stats {
+    fn record(&mut self, time: Duration) {
+        self.count += 1;
+        self.time += time;
+    }
+}
+
+/// An observer which collects execution statistics.
+///
+/// Register one with `Machine::profile`.
+#[derive(Debug, Default)]
+pub struct Profiler {
+    op_codes: HashMap<String, Stats>,
+    labels: HashMap<String, Stats>,
+    calls: HashMap<String, usize>,
+    stacks: HashMap<String, usize>,
+    stack: Vec<String>,
+    current: Option<(String, String, Instant)>,
+}
+
+impl Profiler {
+    /// Create a new profiler with no statistics.
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    /// Retrieve the statistics for an instruction by name.
+    pub fn op_code(&self, name: &str) -> Option<&Stats> {
+        self.op_codes.get(name)
+    }
+
+    /// Retrieve the statistics for the instructions inside a label.
+    pub fn label(&self, name: &str) -> Option<&Stats> {
+        self.labels.get(name)
+    }
+
+    /// The number of times a label was called.
+    pub fn calls(&self, name: &str) -> usize {
+        self.calls.get(name).cloned().unwrap_or(0)
+    }
+
+    /// Forget all statistics collected so far.
+    pub fn reset(&mut self) {
+        *self = Profiler::new();
+    }
+
+    /// Summarise the statistics as a human readable table.
+    ///
+    /// Instructions and labels are listed from most to least time spent.
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        report.push_str("op code\tcount\ttime\n");
+        for (name, stats) in sorted_by_time(&self.op_codes) {
+            report.push_str(&format!("{}\t{}\t{:?}\n", name, stats.count, stats.time));
+        }
+        report.push_str("\nlabel\tcount\ttime\tcalls\n");
+        for (name, stats) in sorted_by_time(&self.labels) {
+            report.push_str(&format!(
+                "{}\t{}\t{:?}\t{}\n",
+                name,
+                stats.count,
+                stats.time,
+                self.calls(name)
+            ));
+        }
+        report
+    }
+
+    /// Write the call stacks in the folded format used by flamegraph tools.
+    pub fn write_folded(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut stacks: Vec<(&String, &usize)> = self.stacks.iter().collect();
+        stacks.sort();
+        for (stack, count) in stacks {
+            writeln!(out, "{} {}", stack, count)?;
+        }
+        Ok(())
+    }
+}
+
+fn sorted_by_time(stats: &HashMap<String, Stats>) -> Vec<(&String, &Stats)> {
+    let mut stats: Vec<(&String, &Stats)> = stats.iter().collect();
+    stats.sort_by(|lhs, rhs| rhs.1.time.cmp(&lhs.1.time).then_with(|| lhs.0.cmp(rhs.0)));
+    stats
+}
+
+impl<T: fmt::Debug> Observer<T> for Profiler {
+    fn before_instruction(
+        &mut self,
+        machine: &mut Machine<T>,
+        ip: usize,
+        op_code: usize,
+        _args: &[usize],
+    ) {
+        let name = machine.code.symbol(op_code).unwrap_or("?").to_string();
+        let label = match machine.code.label_for_ip(ip) {
+            Some((_, label)) => label.clone(),
+            None => "?".to_string(),
+        };
+        if self.stack.is_empty() {
+            self.stack.push(label.clone());
+        }
+        *self.stacks.entry(self.stack.join(";")).or_insert(0) += 1;
+        self.current = Some((name, label, Instant::now()));
+    }
+
+    fn after_instruction(
+        &mut self,
+        _machine: &mut Machine<T>,
+        _ip: usize,
+        _op_code: usize,
+        _args: &[usize],
+    ) {
+        if let Some((name, label, started)) = self.current.take() {
+            let elapsed = started.elapsed();
+            self.op_codes.entry(name).or_default().record(elapsed);
+            self.labels.entry(label).or_default().record(elapsed);
+        }
+    }
+
+    fn on_call(&mut self, _machine: &mut Machine<T>, label: &str, _return_address: usize) {
+        *self.calls.entry(label.to_string()).or_insert(0) += 1;
+        self.stack.push(label.to_string());
+    }
+
+    fn on_ret(&mut self, _machine: &mut Machine<T>, _return_address: usize) {
+        if self.stack.len() > 1 {
+            self.stack.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builder::Builder;
+    use crate::code::Code;
+    use crate::instruction::Instruction;
+    use crate::instruction_table::InstructionTable;
+    use crate::write_many_table::WriteManyTable;
+
+    fn push(machine: &mut Machine<usize>, args: &[usize]) {
+        let arg = *machine.get_data(args[0]);
+        machine.operand_push(arg);
+    }
+
+    fn call(machine: &mut Machine<usize>, args: &[usize]) {
+        let label = machine.get_data(args[0]).to_string();
+        machine.call(&format!("f{}", label));
+    }
+
+    fn ret(machine: &mut Machine<usize>, _args: &[usize]) {
+        machine.ret();
+    }
+
+    fn instruction_table() -> InstructionTable<usize> {
+        let mut it = InstructionTable::new();
+        it.insert(Instruction::new(0, "push", 1, push));
+        it.insert(Instruction::new(1, "call", 1, call));
+        it.insert(Instruction::new(2, "ret", 0, ret));
+        it
+    }
+
+    fn profile() -> Profiler {
+        let it = instruction_table();
+        let mut builder: Builder<usize> = Builder::new(&it);
+        builder.push("call", vec![1]);
+        builder.push("call", vec![1]);
+        builder.push("ret", vec![]);
+        builder.label("f1");
+        builder.push("push", vec![1]);
+        builder.push("call", vec![2]);
+        builder.push("ret", vec![]);
+        builder.label("f2");
+        builder.push("ret", vec![]);
+
+        let constants: WriteManyTable<usize> = WriteManyTable::new();
+        let mut machine = Machine::new(Code::from(builder), &constants, &it);
+        let profiler = machine.profile();
+        machine.run();
+        let mut profiler = profiler.lock().unwrap();
+        std::mem::take(&mut *profiler)
+    }
+
+    #[test]
+    fn counts() {
+        let profiler = profile();
+        assert_eq!(profiler.op_code("call").unwrap().count, 4);
+        assert_eq!(profiler.op_code("push").unwrap().count, 2);
+        assert_eq!(profiler.op_code("ret").unwrap().count, 5);
+        assert_eq!(profiler.label("main").unwrap().count, 3);
+        assert_eq!(profiler.label("f1").unwrap().count, 6);
+        assert_eq!(profiler.label("f2").unwrap().count, 2);
+        assert_eq!(profiler.calls("f1"), 2);
+        assert_eq!(profiler.calls("f2"), 2);
+        assert_eq!(profiler.calls("main"), 0);
+    }
+
+    #[test]
+    fn folded() {
+        let profiler = profile();
+        let mut folded: Vec<u8> = vec![];
+        profiler.write_folded(&mut folded).unwrap();
+        assert_eq!(
+            String::from_utf8(folded).unwrap(),
+            "main 3\nmain;f1 6\nmain;f1;f2 2\n"
+        );
+    }
+
+    #[test]
+    fn report() {
+        let report = profile().report();
+        assert!(report.starts_with("op code\tcount\ttime\n"));
+        assert!(report.contains("\nlabel\tcount\ttime\tcalls\n"));
+        assert!(report.contains("\nf1\t6\t"));
+        assert!(report.contains("\npush\t2\t"));
+    }
+}
@@ -0,0 +1,372 @@
+//! An interactive debugger.
+//!
+//! `Repl` reads debugger commands one line at a time and drives a `Machine`
+//! with them.  It's what the `stack-vm-debug` binary runs, but because this
+//! crate doesn't know about your operands or instructions you'll want to
+//! embed it in a binary of your own which loads your instruction table.
+//!
+//! The following commands are understood:
+//!
+//! | Command            | Description                                          |
+//! |--------------------|------------------------------------------------------|
+//! | `break <label>`    | Pause at a label, or at an address if it's a number. |
+//! | `delete <label>`   | Remove a breakpoint.                                 |
+//! | `watch <local>`    | Pause when a local in the current frame changes.     |
+//! | `step`, `s`        | Execute one instruction.                             |
+//! | `next`, `n`        | Execute one instruction, stepping over calls.        |
+//! | `finish`, `f`      | Run until the current function returns.              |
+//! | `continue`, `c`    | Run until a breakpoint or the end of the code.       |
+//! | `stack`            | Print the operand stack, bottom first.               |
+//! | `backtrace`, `bt`  | Print the call stack, innermost frame first.         |
+//! | `locals`           | Print the locals of the current frame.               |
+//! | `list`, `l`        | Disassemble the code around the current instruction. |
+//! | `help`             | Print a list of commands.                            |
+//! | `help <name>`      | Describe an instruction and its arguments.           |
+//! | `quit`, `q`        | Leave the debugger.                                  |
+//!
+//! ## Examples
+//!
+//! ```
+//! use stack_vm::{Instruction, InstructionTable, Builder, Code, Machine, Repl, WriteManyTable};
+//!
+//! fn push(machine: &mut Machine<i64>, args: &[usize]) {
+//!     let arg = *machine.get_data(args[0]);
+//!     machine.operand_push(arg);
+//! }
+//!
+//! let mut instruction_table = InstructionTable::new();
+//! instruction_table.insert(Instruction::new(0, "push", 1, push));
+//! let mut builder: Builder<i64> = Builder::new(&instruction_table);
+//! builder.push("push", vec![1]);
+//! builder.push("push", vec![2]);
+//!
+//! let constants: WriteManyTable<i64> = WriteManyTable::new();
+//! let mut machine = Machine::new(Code::from(builder), &constants, &instruction_table);
+//! let mut output: Vec<u8> = vec![];
+//! Repl::new(&mut machine).run(&b"step\nstack\n"[..], &mut output).unwrap();
+//! assert!(String::from_utf8(output).unwrap().contains("[1]"));
+//! ```
+
+use crate::debugger::{self, Debugger, Stop};
+use crate::machine::Machine;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::sync::{Arc, Mutex};
+
+/// The number of instructions `list` shows either side of the current one.
+const LIST_CONTEXT: usize = 5;
+
+const HELP: &str = "\
+break <label>    pause at a label, or at an address if it's a number
+delete <label>   remove a breakpoint
+watch <local>    pause when a local in the current frame changes
+step, s          execute one instruction
+next, n          execute one instruction, stepping over calls
+finish, f        run until the current function returns
+continue, c      run until a breakpoint or the end of the code
+stack            print the operand stack, bottom first
+backtrace, bt    print the call stack, innermost frame first
+locals           print the locals of the current frame
+list, l          disassemble the code around the current instruction
+help             print this list
+help <name>      describe an instruction and its arguments
+quit, q          leave the debugger";
+
+/// A line-oriented debugger for a `Machine`.
+pub struct Repl<'m, 'a: 'm, T: 'a + fmt::Debug> {
+    machine: &'m mut Machine<'a, T>,
+    debugger: Arc<Mutex<Debugger<T>>>,
+}
+
+impl<'m, 'a: 'm, T> Repl<'m, 'a, T>
+where
+    T: 'a + fmt::Debug + Clone + PartialEq + Send + 'static,
+{
+    /// Attach a new debugger to `machine`.
+    pub fn new(machine: &'m mut Machine<'a, T>) -> Repl<'m, 'a, T> {
+        let debugger = machine.debug();
+        Repl { machine, debugger }
+    }
+
+    /// Read and execute commands until `quit` or the end of the input.
+    ///
+    /// A prompt is written to `output` before each command is read.
+    pub fn run<R: BufRead>(&mut self, input: R, output: &mut dyn Write) -> io::Result<()> {
+        let mut lines = input.lines();
+        loop {
+            write!(output, "(stack-vm) ")?;
+            output.flush()?;
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => return Ok(()),
+            };
+            if !self.execute(&line, output)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Execute a single command.
+    ///
+    /// Returns `false` if the command asks to leave the debugger.
+    pub fn execute(&mut self, line: &str, output: &mut dyn Write) -> io::Result<bool> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(true),
+        };
+        let argument = words.next();
+
+        match (command, argument) {
+            ("break", Some(target)) | ("b", Some(target)) => {
+                let mut debugger = self.debugger.lock().unwrap();
+                match target.parse::<usize>() {
+                    Ok(ip) => debugger.break_at(ip),
+                    Err(_) => debugger.break_at_label(target),
+                }
+                writeln!(output, "breakpoint at {}", target)?;
+            }
+            ("delete", Some(target)) | ("d", Some(target)) => {
+                let mut debugger = self.debugger.lock().unwrap();
+                match target.parse::<usize>() {
+                    Ok(ip) => debugger.remove_breakpoint(ip),
+                    Err(_) => debugger.remove_label_breakpoint(target),
+                }
+                writeln!(output, "deleted breakpoint at {}", target)?;
+            }
+            ("watch", Some(name)) => {
+                self.debugger.lock().unwrap().watch_local(name);
+                writeln!(output, "watching {}", name)?;
+            }
+            ("step", None) | ("s", None) => {
+                debugger::step_into(self.machine, &self.debugger);
+                self.print_location(output)?;
+            }
+            ("next", None) | ("n", None) => {
+                debugger::step_over(self.machine, &self.debugger);
+                self.print_location(output)?;
+            }
+            ("finish", None) | ("f", None) => {
+                debugger::step_out(self.machine, &self.debugger);
+                self.print_location(output)?;
+            }
+            ("continue", None) | ("c", None) => {
+                debugger::resume(self.machine, &self.debugger);
+                self.print_location(output)?;
+            }
+            ("stack", None) => {
+                writeln!(output, "{:?}", self.machine.operand_stack.as_slice())?;
+            }
+            ("backtrace", None) | ("bt", None) => {
+                for (depth, frame) in self.machine.backtrace().iter().enumerate() {
+                    writeln!(
+                        output,
+                        "#{} {} (returns to {})",
+                        depth, frame.location, frame.return_address
+                    )?;
+                }
+            }
+            ("locals", None) => {
+                let frame = self.machine.call_stack.peek();
+                let names = frame.locals().keys();
+                if names.is_empty() {
+                    writeln!(output, "no locals")?;
+                }
+                for name in names {
+                    writeln!(output, "{} = {:?}", name, frame.get_local(&name).unwrap())?;
+                }
+            }
+            ("list", None) | ("l", None) => {
+                for line in self.listing() {
+                    writeln!(output, "{}", line)?;
+                }
+            }
+            ("help", None) | ("h", None) => {
+                writeln!(output, "{}", HELP)?;
+            }
+            ("help", Some(name)) | ("h", Some(name)) => {
+                match self.machine.instruction_table.by_name(name) {
+                    Some(instruction) => {
+                        writeln!(output, "{}", instruction.signature())?;
+                        if let Some(ref doc) = instruction.doc {
+                            writeln!(output, "{}", doc)?;
+                        }
+                    }
+                    None => writeln!(output, "unknown instruction: {}", name)?,
+                }
+            }
+            ("quit", None) | ("q", None) => return Ok(false),
+            _ => {
+                writeln!(output, "unknown command: {} (try help)", line.trim())?;
+            }
+        }
+        Ok(true)
+    }
+
+    fn print_location(&self, output: &mut dyn Write) -> io::Result<()> {
+        if let Some(stop) = self.debugger.lock().unwrap().stop() {
+            match stop {
+                Stop::Breakpoint(_) => writeln!(output, "breakpoint")?,
+                Stop::Local { name, old, new } => {
+                    writeln!(output, "{} changed from {:?} to {:?}", name, old, new)?
+                }
+                Stop::OperandDepth(depth) => {
+                    writeln!(output, "operand stack reached depth {}", depth)?
+                }
+            }
+        }
+        if self.machine.is_finished() {
+            return writeln!(output, "finished");
+        }
+        let ip = self.machine.ip;
+        match self.machine.code.label_for_ip(ip) {
+            Some((label_ip, name)) => write!(output, "at {}+{} ({})", name, ip - label_ip, ip)?,
+            None => write!(output, "at {}", ip)?,
+        }
+        match self.machine.code.source_location(ip) {
+            Some(source) => writeln!(output, " {}", source),
+            None => writeln!(output),
+        }
+    }
+
+    /// The lines of the code's disassembly which surround the current
+    /// instruction, with addresses added and the current one marked.
+    fn listing(&self) -> Vec<String> {
+        let code = &self.machine.code;
+        let end = code.code.len();
+        let mut lines = vec![];
+        let mut current = 0;
+        for ip in code.instruction_addresses().into_iter().chain(Some(end)) {
+            if let Some(label) = code.label_at(ip) {
+                if ip == self.machine.ip {
+                    current = lines.len();
+                }
+                lines.push(format!(".{}:", label));
+            }
+            if ip < end {
+                let marker = if ip == self.machine.ip { "=>" } else { "  " };
+                if ip == self.machine.ip {
+                    current = lines.len();
+                }
+                lines.push(format!(
+                    "{} {:>4}\t{}",
+                    marker,
+                    ip,
+                    code.describe_instruction(ip)
+                ));
+            }
+        }
+        if self.machine.is_finished() {
+            current = lines.len();
+        }
+
+        let start = current.saturating_sub(LIST_CONTEXT);
+        let end = (current + LIST_CONTEXT + 1).min(lines.len());
+        lines[start..end].to_vec()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builder::Builder;
+    use crate::code::Code;
+    use crate::fixtures::instruction_table;
+    use crate::instruction_table::InstructionTable;
+    use crate::write_many_table::WriteManyTable;
+
+    fn code(it: &InstructionTable<usize>) -> Code<usize> {
+        let mut builder: Builder<usize> = Builder::new(it);
+        builder.push("call", vec![]);
+        builder.push("push", vec![1]);
+        builder.push("ret", vec![]);
+        builder.label("function");
+        builder.push("push", vec![2]);
+        builder.push("store", vec![]);
+        builder.push("ret", vec![]);
+        Code::from(builder)
+    }
+
+    fn session(commands: &str) -> String {
+        let it = instruction_table();
+        let constants: WriteManyTable<usize> = WriteManyTable::new();
+        let mut machine = Machine::new(code(&it), &constants, &it);
+        let mut output: Vec<u8> = vec![];
+        Repl::new(&mut machine)
+            .run(commands.as_bytes(), &mut output)
+            .unwrap();
+        String::from_utf8(output)
+            .unwrap()
+            .replace("(stack-vm) ", "")
+    }
+
+    #[test]
+    fn step_and_next() {
+        assert_eq!(
+            session("step\nstep\n"),
+            "at function+0 (7)\nat function+3 (10)\n"
+        );
+        assert_eq!(session("next\nnext\n"), "at main+2 (2)\nat main+5 (5)\n");
+    }
+
+    #[test]
+    fn help() {
+        assert_eq!(session("help push\n"), "push <data>\nPush an operand.\n");
+        assert_eq!(session("help ret\n"), "ret\n");
+        assert_eq!(session("help pop\n"), "unknown instruction: pop\n");
+    }
+
+    #[test]
+    fn finish() {
+        assert_eq!(
+            session("step\nfinish\nfinish\n"),
+            "at function+0 (7)\nat main+2 (2)\nfinished\n"
+        );
+    }
+
+    #[test]
+    fn breakpoints() {
+        assert_eq!(
+            session("break function\ncontinue\ncontinue\n"),
+            "breakpoint at function\nbreakpoint\nat function+0 (7)\nfinished\n"
+        );
+        assert_eq!(
+            session("break 0\ncontinue\ncontinue\n"),
+            "breakpoint at 0\nbreakpoint\nat main+0 (0)\nfinished\n"
+        );
+    }
+
+    #[test]
+    fn watch() {
+        assert_eq!(
+            session("watch x\ncontinue\nlocals\n"),
+            "watching x\nx changed from None to Some(2)\nat function+5 (12)\nx = 2\n"
+        );
+    }
+
+    #[test]
+    fn inspect() {
+        assert_eq!(
+            session("step\nstep\nstack\nbacktrace\nlocals\n"),
+            "at function+0 (7)\nat function+3 (10)\n[2]\n\
+             #0 function+3 (returns to 2)\n#1 main+2 (returns to 14)\nno locals\n"
+        );
+    }
+
+    #[test]
+    fn list() {
+        assert_eq!(
+            session("step\nlist\n"),
+            "at function+0 (7)\n.main:\n      0\tcall\n      2\tpush @0\n      5\tret\n\
+             .function:\n=>    7\tpush @1\n     10\tstore\n     12\tret\n"
+        );
+    }
+
+    #[test]
+    fn quit_and_unknown() {
+        assert_eq!(
+            session("frobnicate\nquit\nstep\n"),
+            "unknown command: frobnicate (try help)\n"
+        );
+    }
+}
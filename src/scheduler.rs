@@ -0,0 +1,293 @@
+//! A green-thread scheduler.
+//!
+//! The scheduler allows a single `Machine` to run many lightweight threads
+//! which share the machine's code, constants and instruction table.  Each
+//! thread has its own instruction pointer, call stack and operand stack.
+//!
+//! Threads are time-sliced round-robin: each runnable thread gets to execute
+//! up to a fixed budget of instructions before the next thread is switched
+//! in.  A thread gives up the rest of its slice early when it sleeps,
+//! joins another thread which is still running or receives from an empty
+//! mailbox.
+//!
+//! The program started by `Machine::new` is always thread `0`.
+//!
+//! ## Examples
+//!
+//! ```
+//! use stack_vm::{Instruction, InstructionTable, Builder, Code, Machine, WriteManyTable};
+//!
+//! fn push(machine: &mut Machine<i64>, args: &[usize]) {
+//!     let arg = *machine.get_data(args[0]);
+//!     machine.operand_push(arg);
+//! }
+//!
+//! fn spawn(machine: &mut Machine<i64>, _args: &[usize]) {
+//!     let id = machine.spawn("worker");
+//!     machine.operand_push(id as i64);
+//! }
+//!
+//! fn join(machine: &mut Machine<i64>, _args: &[usize]) {
+//!     let id = machine.operand_pop() as usize;
+//!     machine.join(id);
+//! }
+//!
+//! let mut instruction_table = InstructionTable::new();
+//! instruction_table.insert(Instruction::new(0, "push", 1, push));
+//! instruction_table.insert(Instruction::new(1, "spawn", 0, spawn));
+//! instruction_table.insert(Instruction::new(2, "join", 0, join));
+//!
+//! let mut builder: Builder<i64> = Builder::new(&instruction_table);
+//! builder.push("spawn", vec![]);
+//! builder.push("join", vec![]);
+//! builder.push("push", vec![1]);
+//! builder.label("worker");
+//!
+//! let constants: WriteManyTable<i64> = WriteManyTable::new();
+//! let mut machine = Machine::new(Code::from(builder), &constants, &instruction_table);
+//! machine.run_threads(10);
+//! assert_eq!(machine.operand_pop(), 1);
+//! ```
+
+use crate::coroutine::Coroutine;
+use std::fmt;
+
+/// A handle used to refer to a thread owned by a `Machine`.
+pub type ThreadId = usize;
+
+/// The scheduling state of a thread.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ThreadState {
+    /// The thread is ready to be given a time slice.
+    Runnable,
+    /// The thread is waiting until the scheduler reaches the given round.
+    Sleeping(usize),
+    /// The thread is waiting for another thread to finish.
+    Joining(ThreadId),
+    /// The thread is waiting for a message to arrive in the named mailbox.
+    Receiving(String),
+    /// The thread has run to completion.
+    Finished,
+}
+
+/// A thread known to the scheduler.
+///
+/// The execution state of a suspended thread is stored as a `Coroutine`.
+/// While a thread is running its state lives in the `Machine` instead and
+/// `context` is empty.
+#[derive(Debug, Clone)]
+struct Thread<T> {
+    context: Option<Coroutine<T>>,
+    state: ThreadState,
+}
+
+/// The scheduler.
+///
+/// Keeps track of every thread in a `Machine`, which one is currently
+/// running and how many rounds of scheduling have taken place.  Sleeping
+/// threads are measured in rounds, where a round is one pass over all
+/// threads.
+#[derive(Debug, Clone)]
+pub struct Scheduler<T> {
+    threads: Vec<Thread<T>>,
+    current: ThreadId,
+    round: usize,
+}
+
+impl<T: fmt::Debug> Scheduler<T> {
+    /// Create a new scheduler containing only the main thread.
+    pub fn new() -> Scheduler<T> {
+        Scheduler {
+            threads: vec![Thread {
+                context: None,
+                state: ThreadState::Runnable,
+            }],
+            current: 0,
+            round: 0,
+        }
+    }
+
+    /// Add a suspended thread and return its handle.
+    pub(crate) fn spawn(&mut self, context: Coroutine<T>) -> ThreadId {
+        self.threads.push(Thread {
+            context: Some(context),
+            state: ThreadState::Runnable,
+        });
+        self.threads.len() - 1
+    }
+
+    /// The handle of the currently running thread.
+    pub fn current(&self) -> ThreadId {
+        self.current
+    }
+
+    /// The number of completed scheduling rounds.
+    pub fn round(&self) -> usize {
+        self.round
+    }
+
+    /// The number of threads, including finished ones.
+    pub fn len(&self) -> usize {
+        self.threads.len()
+    }
+
+    /// Returns `true` if there are no threads.
+    ///
+    /// This is never the case, as the main thread always exists.
+    pub fn is_empty(&self) -> bool {
+        self.threads.is_empty()
+    }
+
+    /// Retrieve the scheduling state of a thread.
+    pub fn state(&self, id: ThreadId) -> ThreadState {
+        self.thread(id).state.clone()
+    }
+
+    /// Change the scheduling state of a thread.
+    pub(crate) fn set_state(&mut self, id: ThreadId, state: ThreadState) {
+        self.thread_mut(id).state = state;
+    }
+
+    /// Returns `true` if the thread can be given a time slice right now.
+    ///
+    /// Threads whose reason for waiting has passed are made runnable again.
+    /// The scheduler can't see mailboxes, so receiving threads stay blocked
+    /// until the machine wakes them with `set_state`.
+    pub(crate) fn is_runnable(&mut self, id: ThreadId) -> bool {
+        let runnable = match self.state(id) {
+            ThreadState::Runnable => true,
+            ThreadState::Sleeping(until) => until <= self.round,
+            ThreadState::Joining(other) => self.state(other) == ThreadState::Finished,
+            ThreadState::Receiving(_) | ThreadState::Finished => false,
+        };
+        if runnable {
+            self.set_state(id, ThreadState::Runnable);
+        }
+        runnable
+    }
+
+    /// Returns `true` once every thread has finished.
+    pub fn all_finished(&self) -> bool {
+        self.threads
+            .iter()
+            .all(|thread| thread.state == ThreadState::Finished)
+    }
+
+    /// Returns `true` if any thread is waiting for a message.
+    pub fn any_receiving(&self) -> bool {
+        self.threads
+            .iter()
+            .any(|thread| matches!(thread.state, ThreadState::Receiving(_)))
+    }
+
+    /// Returns `true` if any thread is waiting only for time to pass.
+    pub fn any_sleeping(&self) -> bool {
+        self.threads
+            .iter()
+            .any(|thread| matches!(thread.state, ThreadState::Sleeping(_)))
+    }
+
+    /// Move on to the next thread in round-robin order, starting a new
+    /// round whenever we wrap around.
+    ///
+    /// Returns the previous and new current thread handles.
+    pub(crate) fn advance(&mut self) -> (ThreadId, ThreadId) {
+        let previous = self.current;
+        self.current += 1;
+        if self.current == self.threads.len() {
+            self.current = 0;
+            self.round += 1;
+        }
+        (previous, self.current)
+    }
+
+    /// Make `id` the current thread without advancing the round.
+    pub(crate) fn set_current(&mut self, id: ThreadId) -> ThreadId {
+        let previous = self.current;
+        self.current = id;
+        previous
+    }
+
+    /// Take the saved execution state of a suspended thread.
+    pub(crate) fn take_context(&mut self, id: ThreadId) -> Coroutine<T> {
+        self.thread_mut(id)
+            .context
+            .take()
+            .unwrap_or_else(|| panic!("Thread {} is already running", id))
+    }
+
+    /// Store the execution state of a thread which is being suspended.
+    pub(crate) fn put_context(&mut self, id: ThreadId, context: Coroutine<T>) {
+        self.thread_mut(id).context = Some(context);
+    }
+
+    fn thread(&self, id: ThreadId) -> &Thread<T> {
+        self.threads
+            .get(id)
+            .unwrap_or_else(|| panic!("Unknown thread {}", id))
+    }
+
+    fn thread_mut(&mut self, id: ThreadId) -> &mut Thread<T> {
+        self.threads
+            .get_mut(id)
+            .unwrap_or_else(|| panic!("Unknown thread {}", id))
+    }
+}
+
+impl<T: fmt::Debug> Default for Scheduler<T> {
+    fn default() -> Scheduler<T> {
+        Scheduler::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn new() {
+        let scheduler: Scheduler<usize> = Scheduler::new();
+        assert_eq!(scheduler.len(), 1);
+        assert_eq!(scheduler.current(), 0);
+        assert_eq!(scheduler.state(0), ThreadState::Runnable);
+    }
+
+    #[test]
+    fn advance() {
+        let mut scheduler: Scheduler<usize> = Scheduler::new();
+        scheduler.spawn(Coroutine::new(0, 0));
+        assert_eq!(scheduler.advance(), (0, 1));
+        assert_eq!(scheduler.round(), 0);
+        assert_eq!(scheduler.advance(), (1, 0));
+        assert_eq!(scheduler.round(), 1);
+    }
+
+    #[test]
+    fn sleeping_is_runnable_after_round() {
+        let mut scheduler: Scheduler<usize> = Scheduler::new();
+        scheduler.set_state(0, ThreadState::Sleeping(1));
+        assert!(!scheduler.is_runnable(0));
+        scheduler.advance();
+        assert!(scheduler.is_runnable(0));
+        assert_eq!(scheduler.state(0), ThreadState::Runnable);
+    }
+
+    #[test]
+    fn joining_is_runnable_after_finish() {
+        let mut scheduler: Scheduler<usize> = Scheduler::new();
+        let other = scheduler.spawn(Coroutine::new(0, 0));
+        scheduler.set_state(0, ThreadState::Joining(other));
+        assert!(!scheduler.is_runnable(0));
+        scheduler.set_state(other, ThreadState::Finished);
+        assert!(scheduler.is_runnable(0));
+    }
+
+    #[test]
+    fn receiving_is_not_runnable() {
+        let mut scheduler: Scheduler<usize> = Scheduler::new();
+        scheduler.set_state(0, ThreadState::Receiving("inbox".to_string()));
+        assert!(!scheduler.is_runnable(0));
+        scheduler.advance();
+        assert!(!scheduler.is_runnable(0));
+    }
+}
@@ -0,0 +1,139 @@
+//! Borrowed or shared references.
+//!
+//! `Machine` needs access to an instruction table and a table of constants
+//! which it doesn't own.  These can either be borrowed for the lifetime of
+//! the machine, or shared using an `Arc` so that the machine owns a handle to
+//! them and can be moved freely between threads.
+//!
+//! Tables of constants must be `Sync` to be borrowed, and `Send + Sync` to
+//! be shared, so a machine is `Send` and `Sync` whichever way it holds
+//! them.
+
+use crate::table::Table;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// A reference which is either borrowed or reference counted.
+///
+/// Dereferences to the underlying value either way.
+pub enum Shared<'a, X: ?Sized + 'a> {
+    Borrowed(&'a X),
+    Owned(Arc<X>),
+}
+
+impl<'a, X: ?Sized + 'a> Deref for Shared<'a, X> {
+    type Target = X;
+
+    fn deref(&self) -> &X {
+        match self {
+            Shared::Borrowed(value) => value,
+            Shared::Owned(value) => value,
+        }
+    }
+}
+
+impl<'a, X: ?Sized + 'a> Clone for Shared<'a, X> {
+    fn clone(&self) -> Shared<'a, X> {
+        match self {
+            Shared::Borrowed(value) => Shared::Borrowed(*value),
+            Shared::Owned(value) => Shared::Owned(value.clone()),
+        }
+    }
+}
+
+impl<'a, X: ?Sized + 'a> From<&'a X> for Shared<'a, X> {
+    fn from(value: &'a X) -> Shared<'a, X> {
+        Shared::Borrowed(value)
+    }
+}
+
+impl<'a, X: ?Sized + 'a> From<Arc<X>> for Shared<'a, X> {
+    fn from(value: Arc<X>) -> Shared<'a, X> {
+        Shared::Owned(value)
+    }
+}
+
+impl<'a, X: ?Sized + fmt::Debug + 'a> fmt::Debug for Shared<'a, X> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// A machine's table of constants.
+///
+/// Either a `Sync` table borrowed for the lifetime of the machine, or a
+/// `Send + Sync` table shared through an `Arc`.  Either way it can be sent
+/// to and shared between threads, and dereferences to the table.
+pub enum Constants<'a, T: 'a> {
+    Borrowed(&'a (dyn Table<Item = T> + Sync + 'a)),
+    Owned(Arc<dyn Table<Item = T> + Send + Sync + 'a>),
+}
+
+impl<'a, T: 'a> Deref for Constants<'a, T> {
+    type Target = dyn Table<Item = T> + Sync + 'a;
+
+    fn deref(&self) -> &(dyn Table<Item = T> + Sync + 'a) {
+        match self {
+            Constants::Borrowed(table) => *table,
+            Constants::Owned(table) => &**table,
+        }
+    }
+}
+
+impl<'a, T: 'a> Clone for Constants<'a, T> {
+    fn clone(&self) -> Constants<'a, T> {
+        match self {
+            Constants::Borrowed(table) => Constants::Borrowed(*table),
+            Constants::Owned(table) => Constants::Owned(table.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn borrowed() {
+        let value = 13;
+        let shared: Shared<usize> = Shared::from(&value);
+        assert_eq!(*shared, 13);
+    }
+
+    #[test]
+    fn owned() {
+        let value = Arc::new(13);
+        let shared: Shared<usize> = Shared::from(value.clone());
+        assert_eq!(*shared, 13);
+        let other = shared.clone();
+        assert_eq!(Arc::strong_count(&value), 3);
+        assert_eq!(*other, 13);
+    }
+
+    #[test]
+    fn borrowed_constants() {
+        use crate::write_many_table::WriteManyTable;
+        use std::thread;
+
+        let mut table = WriteManyTable::new();
+        table.insert("answer", 42);
+        let constants: Constants<usize> = Constants::Borrowed(&table);
+        let result =
+            thread::scope(|scope| scope.spawn(|| *constants.get("answer").unwrap()).join());
+        assert_eq!(result.unwrap(), 42);
+    }
+
+    #[test]
+    fn owned_constants() {
+        use crate::write_many_table::WriteManyTable;
+        use std::thread;
+
+        let mut table = WriteManyTable::new();
+        table.insert("answer", 42);
+        let constants: Constants<usize> = Constants::Owned(Arc::new(table));
+        let result =
+            thread::scope(|scope| scope.spawn(|| *constants.get("answer").unwrap()).join());
+        assert_eq!(result.unwrap(), 42);
+    }
+}
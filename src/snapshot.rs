@@ -0,0 +1,245 @@
+//! Machine snapshots.
+//!
+//! A `Snapshot` captures the execution state of a `Machine` - its
+//! instruction pointer, call stack (including each frame's locals and return
+//! address) and operand stack - so that it can be restored later, possibly
+//! in another process.
+//!
+//! Snapshots implement `ToByteCode` and `FromByteCode` so they can be dumped
+//! and loaded in the same way as `Code`.  Each snapshot records the
+//! fingerprint of the code it was taken from, and `Machine::restore` refuses
+//! to restore it into a machine running different code.
+//!
+//! Snapshots only contain the state of the running program; coroutines,
+//! green threads and router connections are not included.
+//!
+//! ## Examples
+//!
+//! ```
+//! # extern crate rmp;
+//! # extern crate stack_vm;
+//! # use stack_vm::*;
+//! # use std::io::{Read, Write};
+//!
+//! #[derive(Clone, Debug, PartialEq)]
+//! struct Operand(i64);
+//!
+//! impl ToByteCode for Operand {
+//!     fn to_byte_code(&self, mut buf: &mut dyn Write) {
+//!         rmp::encode::write_sint(&mut buf, self.0).unwrap();
+//!     }
+//! }
+//!
+//! impl FromByteCode for Operand {
+//!     fn from_byte_code(mut buf: &mut dyn Read) -> Operand {
+//!         Operand(rmp::decode::read_int(&mut buf).unwrap())
+//!     }
+//! }
+//!
+//! fn push(machine: &mut Machine<Operand>, args: &[usize]) {
+//!     let arg = machine.get_data(args[0]).clone();
+//!     machine.operand_push(arg);
+//! }
+//!
+//! # fn main() {
+//! let mut instruction_table = InstructionTable::new();
+//! instruction_table.insert(Instruction::new(0, "push", 1, push));
+//! let mut builder: Builder<Operand> = Builder::new(&instruction_table);
+//! builder.push("push", vec![Operand(13)]);
+//! builder.push("push", vec![Operand(14)]);
+//! let code = Code::from(builder);
+//! let constants: WriteManyTable<Operand> = WriteManyTable::new();
+//!
+//! let mut machine = Machine::new(code, &constants, &instruction_table);
+//! machine.step();
+//! let mut bytecode: Vec<u8> = vec![];
+//! machine.snapshot().to_byte_code(&mut bytecode);
+//!
+//! let mut builder: Builder<Operand> = Builder::new(&instruction_table);
+//! builder.push("push", vec![Operand(13)]);
+//! builder.push("push", vec![Operand(14)]);
+//! let code = Code::from(builder);
+//! let mut machine = Machine::new(code, &constants, &instruction_table);
+//! machine.restore(Snapshot::from_byte_code(&mut &bytecode[..])).unwrap();
+//! machine.run();
+//! assert_eq!(machine.operand_pop(), Operand(14));
+//! assert_eq!(machine.operand_pop(), Operand(13));
+//! # }
+//! ```
+
+use crate::code::read_string;
+use crate::frame::Frame;
+use crate::from_byte_code::FromByteCode;
+use crate::table::Table;
+use crate::to_byte_code::ToByteCode;
+use rmp::{decode, encode};
+use std::error;
+use std::fmt;
+use std::io::{Read, Write};
+
+/// The saved execution state of a `Machine`.
+///
+/// Contains:
+/// * The fingerprint of the `Code` the snapshot was taken from.
+/// * The instruction pointer.
+/// * The call stack, bottom frame first.
+/// * The operand stack, bottom operand first.
+#[derive(Debug, Clone)]
+pub struct Snapshot<T> {
+    pub fingerprint: u64,
+    pub ip: usize,
+    pub call_stack: Vec<Frame<T>>,
+    pub operand_stack: Vec<T>,
+}
+
+/// The reasons a `Snapshot` can be rejected by `Machine::restore`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RestoreError {
+    /// The snapshot was taken from a different program.
+    CodeMismatch { expected: u64, actual: u64 },
+    /// The snapshot refers to an address which isn't the start of an
+    /// instruction.
+    InvalidAddress(usize),
+    /// The snapshot has no call frames.
+    EmptyCallStack,
+}
+
+impl fmt::Display for RestoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RestoreError::CodeMismatch { expected, actual } => write!(
+                f,
+                "Snapshot was taken from code with fingerprint {:x} but the machine is running {:x}",
+                actual, expected
+            ),
+            RestoreError::InvalidAddress(ip) => {
+                write!(f, "Snapshot refers to address {} which is not an instruction in the code", ip)
+            }
+            RestoreError::EmptyCallStack => write!(f, "Snapshot has an empty call stack"),
+        }
+    }
+}
+
+impl error::Error for RestoreError {}
+
+impl<T: ToByteCode> ToByteCode for Snapshot<T> {
+    /// Create bytecode for this `Snapshot`.
+    ///
+    /// Encodes into a Map of the following format:
+    /// ```json
+    /// {
+    ///     "fingerprint" => 1234567890,
+    ///     "ip" => 6,
+    ///     "call_stack" => [ [ 10, { "x" => 13 } ], [ 6, {} ] ],
+    ///     "operand_stack" => [ 123, 456 ]
+    /// }
+    /// ```
+    fn to_byte_code(&self, mut buf: &mut dyn Write) {
+        encode::write_map_len(&mut buf, 4).unwrap();
+
+        encode::write_str(&mut buf, "fingerprint").unwrap();
+        encode::write_uint(&mut buf, self.fingerprint).unwrap();
+
+        encode::write_str(&mut buf, "ip").unwrap();
+        encode::write_uint(&mut buf, self.ip as u64).unwrap();
+
+        encode::write_str(&mut buf, "call_stack").unwrap();
+        encode::write_array_len(&mut buf, self.call_stack.len() as u32).unwrap();
+        for frame in &self.call_stack {
+            encode::write_array_len(&mut buf, 2).unwrap();
+            encode::write_uint(&mut buf, frame.return_address as u64).unwrap();
+            let locals = frame.locals();
+            let names = locals.keys();
+            encode::write_map_len(&mut buf, names.len() as u32).unwrap();
+            for name in names {
+                encode::write_str(&mut buf, &name).unwrap();
+                locals.get(&name).unwrap().to_byte_code(&mut buf);
+            }
+        }
+
+        encode::write_str(&mut buf, "operand_stack").unwrap();
+        encode::write_array_len(&mut buf, self.operand_stack.len() as u32).unwrap();
+        for operand in &self.operand_stack {
+            operand.to_byte_code(&mut buf);
+        }
+    }
+}
+
+impl<T: FromByteCode> FromByteCode for Snapshot<T> {
+    fn from_byte_code(mut buf: &mut dyn Read) -> Snapshot<T> {
+        let map_len = decode::read_map_len(&mut buf).unwrap();
+        assert_eq!(map_len, 4);
+
+        let section = read_string(&mut buf);
+        assert_eq!(section, "fingerprint");
+        let fingerprint = decode::read_int(&mut buf).unwrap();
+
+        let section = read_string(&mut buf);
+        assert_eq!(section, "ip");
+        let ip = decode::read_int(&mut buf).unwrap();
+
+        let section = read_string(&mut buf);
+        assert_eq!(section, "call_stack");
+        let frame_len = decode::read_array_len(&mut buf).unwrap();
+        let mut call_stack = vec![];
+        for _i in 0..frame_len {
+            let len = decode::read_array_len(&mut buf).unwrap();
+            assert_eq!(len, 2);
+            let mut frame = Frame::new(decode::read_int(&mut buf).unwrap());
+            let locals_len = decode::read_map_len(&mut buf).unwrap();
+            for _j in 0..locals_len {
+                let name = read_string(&mut buf);
+                frame.set_local(&name, FromByteCode::from_byte_code(&mut buf));
+            }
+            call_stack.push(frame);
+        }
+
+        let section = read_string(&mut buf);
+        assert_eq!(section, "operand_stack");
+        let operand_len = decode::read_array_len(&mut buf).unwrap();
+        let mut operand_stack = vec![];
+        for _i in 0..operand_len {
+            operand_stack.push(FromByteCode::from_byte_code(&mut buf));
+        }
+
+        Snapshot {
+            fingerprint,
+            ip,
+            call_stack,
+            operand_stack,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut outer = Frame::new(10);
+        outer.set_local("x", 13);
+        outer.set_local("y", 14);
+        let inner = Frame::new(6);
+        let snapshot: Snapshot<usize> = Snapshot {
+            fingerprint: 0xdead_beef,
+            ip: 8,
+            call_stack: vec![outer, inner],
+            operand_stack: vec![123, 456],
+        };
+
+        let mut bytecode: Vec<u8> = vec![];
+        snapshot.to_byte_code(&mut bytecode);
+        let loaded: Snapshot<usize> = Snapshot::from_byte_code(&mut &bytecode[..]);
+
+        assert_eq!(loaded.fingerprint, 0xdead_beef);
+        assert_eq!(loaded.ip, 8);
+        assert_eq!(loaded.call_stack.len(), 2);
+        assert_eq!(loaded.call_stack[0].return_address, 10);
+        assert_eq!(*loaded.call_stack[0].get_local("x").unwrap(), 13);
+        assert_eq!(*loaded.call_stack[0].get_local("y").unwrap(), 14);
+        assert_eq!(loaded.call_stack[1].return_address, 6);
+        assert!(loaded.call_stack[1].locals().is_empty());
+        assert_eq!(loaded.operand_stack, [123, 456]);
+    }
+}
@@ -0,0 +1,175 @@
+//! Source maps.
+//!
+//! If you're compiling a language down to stack-vm code then you'll want to
+//! know which part of your source produced each instruction, so that errors
+//! can point at the line your users wrote rather than an address.
+//!
+//! Call `Builder::set_location` before pushing the instructions for a piece
+//! of source, and the builder records the range of addresses they occupy in
+//! a `SourceMap`.  The map is kept in the `Code`, is included in its
+//! bytecode, and is used to add source locations to backtraces.
+//!
+//! ## Examples
+//!
+//! ```
+//! use stack_vm::{Instruction, InstructionTable, Builder, Code, Machine, SourceLocation};
+//!
+//! fn push(machine: &mut Machine<i64>, args: &[usize]) {
+//!     let arg = *machine.get_data(args[0]);
+//!     machine.operand_push(arg);
+//! }
+//!
+//! let mut instruction_table = InstructionTable::new();
+//! instruction_table.insert(Instruction::new(0, "push", 1, push));
+//! let mut builder: Builder<i64> = Builder::new(&instruction_table);
+//! builder.set_location("example.src", 1, 1);
+//! builder.push("push", vec![1]);
+//! builder.set_location("example.src", 2, 5);
+//! builder.push("push", vec![2]);
+//!
+//! let code = Code::from(builder);
+//! assert_eq!(
+//!     code.source_location(4),
+//!     Some(&SourceLocation::new("example.src", 2, 5))
+//! );
+//! ```
+
+use std::fmt;
+
+/// A position in a source file.
+///
+/// Lines and columns are counted from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl SourceLocation {
+    /// Create a new source location.
+    pub fn new(file: &str, line: usize, column: usize) -> SourceLocation {
+        SourceLocation {
+            file: file.to_string(),
+            line,
+            column,
+        }
+    }
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// A mapping from ranges of code addresses to source locations.
+///
+/// Ranges are half-open (they include their start but not their end), don't
+/// overlap, and are kept in address order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceMap {
+    ranges: Vec<(usize, usize, SourceLocation)>,
+}
+
+impl SourceMap {
+    /// Create an empty source map.
+    pub fn new() -> SourceMap {
+        SourceMap::default()
+    }
+
+    /// Record that the addresses from `start` up to (but not including) `end`
+    /// came from `location`.
+    ///
+    /// Ranges must be inserted in address order.  A range which carries on
+    /// directly from the previous one at the same location is merged into
+    /// it.
+    pub fn insert(&mut self, start: usize, end: usize, location: SourceLocation) {
+        if let Some(last) = self.ranges.last_mut() {
+            assert!(
+                last.1 <= start,
+                "Source map range {}..{} overlaps {}..{}",
+                start,
+                end,
+                last.0,
+                last.1
+            );
+            if last.1 == start && last.2 == location {
+                last.1 = end;
+                return;
+            }
+        }
+        self.ranges.push((start, end, location));
+    }
+
+    /// Find the source location of the instruction at `ip`.
+    pub fn lookup(&self, ip: usize) -> Option<&SourceLocation> {
+        let index = match self.ranges.binary_search_by(|range| range.0.cmp(&ip)) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        let range = &self.ranges[index];
+        if ip < range.1 {
+            Some(&range.2)
+        } else {
+            None
+        }
+    }
+
+    /// Retrieve every range as a start address, end address and location.
+    pub fn ranges(&self) -> &[(usize, usize, SourceLocation)] {
+        self.ranges.as_slice()
+    }
+
+    /// The number of ranges in the map.
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    /// Returns `true` if the map has no ranges.
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        let mut map = SourceMap::new();
+        map.insert(2, 5, SourceLocation::new("a", 1, 1));
+        map.insert(7, 9, SourceLocation::new("a", 2, 1));
+        assert_eq!(map.lookup(0), None);
+        assert_eq!(map.lookup(2).unwrap().line, 1);
+        assert_eq!(map.lookup(4).unwrap().line, 1);
+        assert_eq!(map.lookup(5), None);
+        assert_eq!(map.lookup(8).unwrap().line, 2);
+        assert_eq!(map.lookup(9), None);
+    }
+
+    #[test]
+    fn insert_merges_adjacent_ranges() {
+        let mut map = SourceMap::new();
+        map.insert(0, 2, SourceLocation::new("a", 1, 1));
+        map.insert(2, 5, SourceLocation::new("a", 1, 1));
+        map.insert(5, 7, SourceLocation::new("a", 1, 2));
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.ranges()[0].1, 5);
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps")]
+    fn insert_out_of_order() {
+        let mut map = SourceMap::new();
+        map.insert(2, 5, SourceLocation::new("a", 1, 1));
+        map.insert(0, 2, SourceLocation::new("a", 1, 1));
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(SourceLocation::new("a.src", 3, 7).to_string(), "a.src:3:7");
+    }
+}
@@ -18,7 +18,7 @@ use std::fmt;
 /// let value = stack.pop();
 /// assert_eq!(value, 13);
 /// ```
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Stack<T>(Vec<T>);
 
 impl<T: fmt::Debug> Stack<T> {
@@ -27,6 +27,11 @@ impl<T: fmt::Debug> Stack<T> {
         Stack(vec![])
     }
 
+    /// Returns the number of elements in the stack.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
     /// Returns `true` if the stack contains no elements.
     pub fn is_empty(&self) -> bool {
         self.0.is_empty()
@@ -75,6 +80,14 @@ mod test {
         assert!(stack.is_empty());
     }
 
+    #[test]
+    fn len() {
+        let mut stack: Stack<usize> = Stack::new();
+        stack.push(13);
+        stack.push(14);
+        assert_eq!(stack.len(), 2);
+    }
+
     #[test]
     fn push() {
         let mut stack: Stack<usize> = Stack::new();
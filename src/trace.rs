@@ -0,0 +1,252 @@
+//! Execution tracing.
+//!
+//! A `Tracer` is an `Observer` which records every instruction the machine
+//! executes to a writer, so that a misbehaving program can be examined after
+//! the fact.  Each entry records:
+//!
+//! * the instruction pointer,
+//! * the name of the instruction (from `Code::symbols`),
+//! * the instruction's arguments,
+//! * the depth of the operand stack before the instruction executed, and
+//! * the depth of the call stack before the instruction executed.
+//!
+//! Entries are written as a stream of MsgPack arrays:
+//!
+//! ```json
+//! [ 4, "push", [ 0 ], 1, 2 ]
+//! ```
+//!
+//! A `TraceReader` reads the entries back, and `TraceReader::print` writes a
+//! human readable listing with each address shown relative to the label
+//! which contains it.
+//!
+//! ## Examples
+//!
+//! ```
+//! use stack_vm::{Instruction, InstructionTable, Builder, Code, Machine, TraceReader, WriteManyTable};
+//!
+//! fn push(machine: &mut Machine<i64>, args: &[usize]) {
+//!     let arg = *machine.get_data(args[0]);
+//!     machine.operand_push(arg);
+//! }
+//!
+//! let mut instruction_table = InstructionTable::new();
+//! instruction_table.insert(Instruction::new(0, "push", 1, push));
+//! let mut builder: Builder<i64> = Builder::new(&instruction_table);
+//! builder.push("push", vec![1]);
+//! builder.push("push", vec![2]);
+//!
+//! let constants: WriteManyTable<i64> = WriteManyTable::new();
+//! let mut machine = Machine::new(Code::from(builder), &constants, &instruction_table);
+//! let tracer = machine.trace(vec![]);
+//! machine.run();
+//!
+//! let trace = tracer.lock().unwrap().writer().clone();
+//! let mut listing: Vec<u8> = vec![];
+//! TraceReader::new(&trace[..]).print(&machine.code, &mut listing).unwrap();
+//! assert_eq!(
+//!     String::from_utf8(listing).unwrap(),
+//!     "main+0\tpush @0\toperands=0 calls=1\nmain+3\tpush @1\toperands=1 calls=1\n"
+//! );
+//! ```
+
+use crate::code::Code;
+use crate::machine::Machine;
+use crate::observer::Observer;
+use rmp::{decode, encode};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/// A single executed instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    pub ip: usize,
+    pub name: String,
+    pub args: Vec<usize>,
+    pub operand_depth: usize,
+    pub call_depth: usize,
+}
+
+impl TraceEntry {
+    /// Write this entry as a MsgPack array.
+    pub fn write(&self, mut buf: &mut dyn Write) -> io::Result<()> {
+        encode::write_array_len(&mut buf, 5).map_err(io::Error::from)?;
+        encode::write_uint(&mut buf, self.ip as u64).map_err(io::Error::from)?;
+        encode::write_str(&mut buf, &self.name).map_err(io::Error::from)?;
+        encode::write_array_len(&mut buf, self.args.len() as u32).map_err(io::Error::from)?;
+        for arg in &self.args {
+            encode::write_uint(&mut buf, *arg as u64).map_err(io::Error::from)?;
+        }
+        encode::write_uint(&mut buf, self.operand_depth as u64).map_err(io::Error::from)?;
+        encode::write_uint(&mut buf, self.call_depth as u64).map_err(io::Error::from)?;
+        Ok(())
+    }
+
+    /// Describe this entry, showing its address relative to the label which
+    /// contains it.
+    pub fn describe<T: fmt::Debug>(&self, code: &Code<T>) -> String {
+        let location = match code.label_for_ip(self.ip) {
+            Some((label_ip, name)) => format!("{}+{}", name, self.ip - label_ip),
+            None => format!("{}", self.ip),
+        };
+        let mut result = format!("{}\t{}", location, self.name);
+        let op_code = code.symbols().iter().find(|symbol| symbol.1 == self.name);
+        for (i, arg) in self.args.iter().enumerate() {
+            match op_code {
+                Some((op_code, _name)) => {
+                    result.push_str(&format!(" {}", code.describe_arg(*op_code, i, *arg)))
+                }
+                None => result.push_str(&format!(" @{}", arg)),
+            }
+        }
+        result.push_str(&format!(
+            "\toperands={} calls={}",
+            self.operand_depth, self.call_depth
+        ));
+        result
+    }
+}
+
+/// An observer which writes a `TraceEntry` for every executed instruction.
+///
+/// Register one with `Machine::trace`.  If the writer returns an error then
+/// tracing stops and the error is available from `error`.
+#[derive(Debug)]
+pub struct Tracer<W: Write> {
+    writer: W,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> Tracer<W> {
+    /// Create a new tracer which writes to `writer`.
+    pub fn new(writer: W) -> Tracer<W> {
+        Tracer {
+            writer,
+            error: None,
+        }
+    }
+
+    /// Retrieve the underlying writer.
+    pub fn writer(&self) -> &W {
+        &self.writer
+    }
+
+    /// Retrieve the error which stopped tracing, if any.
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+}
+
+impl<T: fmt::Debug, W: Write + Send> Observer<T> for Tracer<W> {
+    fn before_instruction(
+        &mut self,
+        machine: &mut Machine<T>,
+        ip: usize,
+        op_code: usize,
+        args: &[usize],
+    ) {
+        if self.error.is_some() {
+            return;
+        }
+        let entry = TraceEntry {
+            ip,
+            name: machine.code.symbol(op_code).unwrap_or("?").to_string(),
+            args: args.to_vec(),
+            operand_depth: machine.operand_stack.len(),
+            call_depth: machine.call_stack.len(),
+        };
+        if let Err(error) = entry.write(&mut self.writer) {
+            self.error = Some(error);
+        }
+    }
+}
+
+/// Reads `TraceEntry`s written by a `Tracer`.
+///
+/// The reader is an iterator which stops at the end of the input.
+pub struct TraceReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> TraceReader<R> {
+    /// Create a new reader.
+    pub fn new(reader: R) -> TraceReader<R> {
+        TraceReader { reader }
+    }
+
+    /// Print every remaining entry, one per line, with addresses shown
+    /// relative to the labels in `code`.
+    pub fn print<T: fmt::Debug>(self, code: &Code<T>, out: &mut dyn Write) -> io::Result<()> {
+        for entry in self {
+            writeln!(out, "{}", entry.describe(code))?;
+        }
+        Ok(())
+    }
+
+    fn read_entry(&mut self) -> Option<TraceEntry> {
+        let mut buf = &mut self.reader;
+        let len = decode::read_array_len(&mut buf).ok()?;
+        if len != 5 {
+            return None;
+        }
+        let ip = decode::read_int(&mut buf).ok()?;
+        let name_len = decode::read_str_len(&mut buf).ok()?;
+        let mut name = vec![0u8; name_len as usize];
+        buf.read_exact(&mut name).ok()?;
+        let args_len = decode::read_array_len(&mut buf).ok()?;
+        let mut args = vec![];
+        for _i in 0..args_len {
+            args.push(decode::read_int(&mut buf).ok()?);
+        }
+        let operand_depth = decode::read_int(&mut buf).ok()?;
+        let call_depth = decode::read_int(&mut buf).ok()?;
+        Some(TraceEntry {
+            ip,
+            name: String::from_utf8(name).ok()?,
+            args,
+            operand_depth,
+            call_depth,
+        })
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = TraceEntry;
+
+    fn next(&mut self) -> Option<TraceEntry> {
+        self.read_entry()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(ip: usize) -> TraceEntry {
+        TraceEntry {
+            ip,
+            name: "push".to_string(),
+            args: vec![0, 1],
+            operand_depth: 2,
+            call_depth: 1,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut buf: Vec<u8> = vec![];
+        entry(0).write(&mut buf).unwrap();
+        entry(4).write(&mut buf).unwrap();
+        let entries: Vec<TraceEntry> = TraceReader::new(&buf[..]).collect();
+        assert_eq!(entries, [entry(0), entry(4)]);
+    }
+
+    #[test]
+    fn describe_without_labels() {
+        let code: Code<usize> = Code::empty();
+        assert_eq!(
+            entry(4).describe(&code),
+            "4\tpush @0 @1\toperands=2 calls=1"
+        );
+    }
+}
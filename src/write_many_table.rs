@@ -25,7 +25,7 @@ use std::collections::HashMap;
 /// let value = *table.get("example").unwrap();
 /// assert_eq!(value, 14);
 /// ```
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct WriteManyTable<T>(HashMap<String, T>);
 
 impl<T> WriteManyTable<T> {
@@ -33,6 +33,13 @@ impl<T> WriteManyTable<T> {
     pub fn new() -> WriteManyTable<T> {
         WriteManyTable(HashMap::new())
     }
+
+    /// Return a sorted list of the keys in the table.
+    pub fn keys(&self) -> Vec<String> {
+        let mut result: Vec<String> = self.0.keys().cloned().collect();
+        result.sort();
+        result
+    }
 }
 
 impl<T> Table for WriteManyTable<T> {
@@ -82,4 +89,12 @@ mod test {
         write_many_table.insert("example", 14);
         assert_eq!(*write_many_table.get("example").unwrap(), 14);
     }
+
+    #[test]
+    fn keys_are_sorted() {
+        let mut write_many_table: WriteManyTable<usize> = WriteManyTable::new();
+        write_many_table.insert("b", 2);
+        write_many_table.insert("a", 1);
+        assert_eq!(write_many_table.keys(), ["a", "b"]);
+    }
 }